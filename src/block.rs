@@ -0,0 +1,174 @@
+use std::collections::BTreeMap;
+use std::rc::Rc;
+
+use crate::disassembler::{decode, Decoded};
+use crate::instructions::OPCodes;
+use crate::memory::{Mem, DEVICE_START};
+
+/* longest run of instructions translated in one go */
+const MAX_BLOCK: usize = 64;
+
+/*
+straight line code starting at `start`, at most one control transfer and
+only as the last op; TRAP, RTI and the reserved opcode are left out so they
+always go through CPU::step
+*/
+pub struct Block {
+    pub start: u16,
+    pub ops: Vec<Decoded>,
+}
+
+pub struct BlockEngine {
+    blocks: BTreeMap<u16, Rc<Block>>,
+    /* address -> start of every block that contains it */
+    owners: BTreeMap<u16, Vec<u16>>,
+}
+
+impl BlockEngine {
+    pub const fn new() -> BlockEngine {
+        BlockEngine {
+            blocks: BTreeMap::new(),
+            owners: BTreeMap::new(),
+        }
+    }
+
+    /* the block at `pc`, translating it first if needed */
+    pub fn block(&mut self, pc: u16, memory: &mut Mem) -> Rc<Block> {
+        if let Some(block) = self.blocks.get(&pc) {
+            return block.clone();
+        }
+
+        let block = Rc::new(Self::translate(pc, memory));
+        for i in 0..block.ops.len() {
+            let addr = pc.wrapping_add(i as u16);
+            memory.watch.watch(addr as usize);
+            self.owners.entry(addr).or_default().push(pc);
+        }
+        self.blocks.insert(pc, block.clone());
+        block
+    }
+
+    fn translate(start: u16, memory: &Mem) -> Block {
+        let mut ops = Vec::new();
+        let mut addr = start;
+
+        /* device registers are never translated, fetching them has side effects */
+        while ops.len() < MAX_BLOCK && (addr as usize) < DEVICE_START {
+            let d = decode(memory.memory[addr as usize]);
+            match d.op {
+                OPCodes::OpTrap | OPCodes::OpRti | OPCodes::OpRes => break,
+                OPCodes::OpBr | OPCodes::OpJmp | OPCodes::OpJsr => {
+                    ops.push(d);
+                    break;
+                },
+                _ => ops.push(d),
+            }
+            addr = addr.wrapping_add(1);
+            if addr == 0 {
+                break;
+            }
+        }
+
+        Block { start, ops }
+    }
+
+    /* drops every block built from words that have since been stored to */
+    pub fn invalidate(&mut self, memory: &mut Mem) {
+        for addr in std::mem::take(&mut memory.watch.hits) {
+            let Some(starts) = self.owners.remove(&(addr as u16)) else { continue };
+            memory.watch.unwatch(addr);
+
+            for start in starts {
+                let Some(block) = self.blocks.remove(&start) else { continue };
+                for i in 0..block.ops.len() {
+                    let covered = start.wrapping_add(i as u16);
+                    if let Some(owners) = self.owners.get_mut(&covered) {
+                        owners.retain(|&s| s != start);
+                        if owners.is_empty() {
+                            self.owners.remove(&covered);
+                            memory.watch.unwatch(covered as usize);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.blocks.clear();
+        self.owners.clear();
+    }
+}
+
+impl Default for BlockEngine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::CPU;
+
+    fn program(words: &[u16]) -> CPU {
+        let mut cpu = CPU::new();
+        cpu.load_image(&std::iter::once(0x3000).chain(words.iter().copied()).flat_map(|w: u16| w.to_be_bytes()).collect());
+        cpu.blocks = Some(BlockEngine::new());
+        cpu
+    }
+
+    #[test]
+    fn blocks_end_at_a_branch_or_before_a_trap() {
+        let mut mem = Mem::new();
+        /* ADD; BRnzp #-2; ADD; TRAP */
+        mem.memory[0x3000..0x3004].copy_from_slice(&[0x1021, 0x0FFE, 0x1021, 0xF025]);
+
+        let mut engine = BlockEngine::new();
+        assert_eq!(engine.block(0x3000, &mut mem).ops.len(), 2);
+        assert_eq!(engine.block(0x3002, &mut mem).ops.len(), 1);
+        assert!(engine.block(0x3003, &mut mem).ops.is_empty());
+    }
+
+    #[test]
+    fn store_into_the_running_block_takes_effect() {
+        /* ST R0, #0 over the ADD R0, R0, #1 right after it, making it ADD R0, R0, #2; HALT */
+        let mut cpu = program(&[0x3000, 0x1021, 0xF025]);
+        cpu.rr0 = 0x1022;
+        cpu.run_for(100);
+        assert_eq!(cpu.rr0, 0x1024);
+    }
+
+    #[test]
+    fn stores_between_runs_drop_stale_blocks() {
+        /* ADD R0, R0, #1; HALT */
+        let mut cpu = program(&[0x1021, 0xF025]);
+        cpu.run_for(100);
+
+        /* ADD R0, R0, #2 */
+        cpu.memory.write(0x3000, 0x1022);
+        cpu.pc = 0x3000;
+        cpu.running = true;
+        cpu.run_for(100);
+        assert_eq!(cpu.rr0, 3);
+    }
+
+    #[test]
+    fn trap_rewriting_translated_code() {
+        /* TRAP x40; ADD R0, R0, #1; BRnzp #-3 */
+        let mut cpu = program(&[0xF040, 0x1021, 0x0FFD]);
+        let mut calls = 0;
+        cpu.register_trap(0x40, Box::new(move |vm: &mut CPU| {
+            calls += 1;
+            match calls {
+                /* ADD R0, R0, #2, after the loop body has been translated */
+                2 => vm.memory.write(0x3001, 0x1022),
+                3 => vm.running = false,
+                _ => {},
+            }
+        }));
+
+        cpu.run_for(100);
+        assert_eq!(cpu.rr0, 3);
+    }
+}
@@ -0,0 +1,141 @@
+use crate::disassembler::{decode, Decoded};
+
+/*
+decoded instructions keyed by address, so loops only decode once; a hit
+is trusted as it is, so every change to memory has to go through
+Mem::write (which invalidates) or be followed by `clear`, as load_image does
+*/
+pub struct DecodeCache {
+    entries: Vec<Option<Decoded>>,
+}
+
+impl DecodeCache {
+    pub const fn new() -> DecodeCache {
+        DecodeCache {
+            entries: Vec::new(),
+        }
+    }
+
+    /* `inst` is only decoded when nothing is cached for `addr` */
+    pub fn get(&mut self, addr: usize, inst: u16) -> Decoded {
+        if self.entries.is_empty() {
+            self.entries = vec![None; 1 << 16];
+        }
+
+        match self.entries[addr] {
+            Some(d) => d,
+            None => {
+                let d = decode(inst);
+                self.entries[addr] = Some(d);
+                d
+            }
+        }
+    }
+
+    /* called for every store so self-modifying code is decoded again */
+    pub fn invalidate(&mut self, addr: usize) {
+        if let Some(entry) = self.entries.get_mut(addr) {
+            *entry = None;
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
+
+impl Default for DecodeCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/* addresses some translation depends on, and which of them have been stored to since */
+pub struct CodeWatch {
+    watched: Vec<bool>,
+    pub hits: Vec<usize>,
+}
+
+impl CodeWatch {
+    pub const fn new() -> CodeWatch {
+        CodeWatch {
+            watched: Vec::new(),
+            hits: Vec::new(),
+        }
+    }
+
+    pub fn watch(&mut self, addr: usize) {
+        if self.watched.is_empty() {
+            self.watched = vec![false; 1 << 16];
+        }
+        self.watched[addr] = true;
+    }
+
+    pub fn unwatch(&mut self, addr: usize) {
+        if let Some(w) = self.watched.get_mut(addr) {
+            *w = false;
+        }
+    }
+
+    pub fn store(&mut self, addr: usize) {
+        if self.watched.get(addr).copied().unwrap_or(false) {
+            self.hits.push(addr);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.watched.clear();
+        self.hits.clear();
+    }
+}
+
+impl Default for CodeWatch {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::CPU;
+    use crate::instructions::OPCodes;
+    use crate::memory::Mem;
+
+    #[test]
+    fn hits_are_not_decoded_again_until_invalidated() {
+        let mut cache = DecodeCache::new();
+        assert_eq!(cache.get(0x3000, 0x1021).op, OPCodes::OpAdd);
+        assert_eq!(cache.get(0x3000, 0xF025).op, OPCodes::OpAdd);
+
+        cache.invalidate(0x3000);
+        assert_eq!(cache.get(0x3000, 0xF025).op, OPCodes::OpTrap);
+    }
+
+    #[test]
+    fn stores_invalidate() {
+        let mut mem = Mem::new();
+        mem.cache.get(0x3000, 0x1021);
+        mem.write(0x3000, 0xF025);
+        assert_eq!(mem.cache.get(0x3000, 0xF025).op, OPCodes::OpTrap);
+    }
+
+    #[test]
+    fn rewritten_instructions_run() {
+        let mut cpu = CPU::new();
+        /* ADD R0, R0, #1 */
+        cpu.load_image(&vec![0x30, 0x00, 0x10, 0x21]);
+        cpu.step();
+
+        /* ADD R0, R0, #2 */
+        cpu.memory.write(0x3000, 0x1022);
+        cpu.pc = 0x3000;
+        cpu.step();
+        assert_eq!(cpu.rr0, 3);
+
+        /* ADD R0, R0, #4, loaded over the top */
+        cpu.load_image(&vec![0x30, 0x00, 0x10, 0x24]);
+        cpu.step();
+        assert_eq!(cpu.rr0, 7);
+    }
+}
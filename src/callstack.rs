@@ -0,0 +1,235 @@
+use crate::symbols::SymbolTable;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FrameKind {
+    Call,
+    Trap,
+    Interrupt,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct CallFrame {
+    pub kind: FrameKind,
+    /* address of the JSR/JSRR/TRAP, or the interrupted PC */
+    pub site: u16,
+    pub target: u16,
+    pub return_addr: u16,
+}
+
+/* a RET that did not go back to where the innermost call came from */
+#[derive(Debug, Clone, Copy)]
+pub struct ReturnMismatch {
+    pub site: u16,
+    pub expected: u16,
+    pub actual: u16,
+}
+
+/* shadow of the subroutine nesting, rebuilt from JSR/JSRR/TRAP and RET/RTI */
+pub struct CallStack {
+    pub frames: Vec<CallFrame>,
+    pub mismatches: Vec<ReturnMismatch>,
+}
+
+impl CallStack {
+    pub const fn new() -> CallStack {
+        CallStack {
+            frames: Vec::new(),
+            mismatches: Vec::new(),
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.frames.clear();
+        self.mismatches.clear();
+    }
+
+    pub fn push(&mut self, kind: FrameKind, site: u16, target: u16, return_addr: u16) {
+        self.frames.push(CallFrame { kind, site, target, return_addr });
+    }
+
+    /*
+    RET at `site` jumping to `target`, closing a call or a trap routine that
+    returns through R7; returns the frame it closed, if any
+    */
+    pub fn ret(&mut self, site: u16, target: u16) -> Option<CallFrame> {
+        let returns = |f: &CallFrame| f.kind != FrameKind::Interrupt;
+        let top = self.frames.iter().rposition(returns)?;
+
+        if self.frames[top].return_addr != target {
+            self.mismatches.push(ReturnMismatch {
+                site,
+                expected: self.frames[top].return_addr,
+                actual: target,
+            });
+
+            /* a return to an outer caller unwinds everything in between */
+            match self.frames.iter().rposition(|f| returns(f) && f.return_addr == target) {
+                Some(outer) => self.frames.truncate(outer + 1),
+                None => return None,
+            }
+        } else {
+            self.frames.truncate(top + 1);
+        }
+
+        self.frames.pop()
+    }
+
+    /* RTI closes the innermost trap or interrupt frame */
+    pub fn rti(&mut self) -> Option<CallFrame> {
+        let top = self.frames.iter().rposition(|f| f.kind != FrameKind::Call)?;
+        self.frames.truncate(top + 1);
+        self.frames.pop()
+    }
+
+    /* drops frames back to `depth`, for routines that finish without RET or RTI */
+    pub fn unwind(&mut self, depth: usize) {
+        self.frames.truncate(depth);
+    }
+
+    pub fn depth(&self) -> usize {
+        self.frames.len()
+    }
+
+    /* innermost frame first, like gdb */
+    pub fn backtrace(&self, pc: u16, symbols: &SymbolTable) -> String {
+        let mut out = format!("#0  x{:04X} in {}\n", pc, symbols.describe(pc));
+
+        for (i, frame) in self.frames.iter().rev().enumerate() {
+            let kind = match frame.kind {
+                FrameKind::Call => "called",
+                FrameKind::Trap => "trapped",
+                FrameKind::Interrupt => "interrupted",
+            };
+            out += &format!("#{:<2} x{:04X} in {} ({} from x{:04X} {}, returns to x{:04X})\n",
+                i + 1,
+                frame.target,
+                symbols.describe(frame.target),
+                kind,
+                frame.site,
+                symbols.describe(frame.site),
+                frame.return_addr);
+        }
+
+        for m in &self.mismatches {
+            out += &format!("warning: RET at x{:04X} {} went to x{:04X}, expected x{:04X} (R7 clobbered?)\n",
+                m.site, symbols.describe(m.site), m.actual, m.expected);
+        }
+
+        out
+    }
+}
+
+impl Default for CallStack {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::CPU;
+    use crate::io::redirect_console;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn program(words: &[u16]) -> CPU {
+        let mut cpu = CPU::new();
+        cpu.load_image(&std::iter::once(0x3000).chain(words.iter().copied()).flat_map(|w: u16| w.to_be_bytes()).collect());
+        cpu
+    }
+
+    #[test]
+    fn ret_closes_the_innermost_call() {
+        let mut calls = CallStack::new();
+        calls.push(FrameKind::Call, 0x3000, 0x3100, 0x3001);
+        calls.push(FrameKind::Call, 0x3100, 0x3200, 0x3101);
+
+        assert_eq!(calls.ret(0x3205, 0x3101).map(|f| f.target), Some(0x3200));
+        assert_eq!(calls.depth(), 1);
+        assert!(calls.mismatches.is_empty());
+    }
+
+    #[test]
+    fn clobbered_r7_is_flagged_and_unwinds_to_the_matching_caller() {
+        let mut calls = CallStack::new();
+        calls.push(FrameKind::Call, 0x3000, 0x3100, 0x3001);
+        calls.push(FrameKind::Call, 0x3100, 0x3200, 0x3101);
+
+        /* the inner routine returns straight to main */
+        assert_eq!(calls.ret(0x3205, 0x3001).map(|f| f.target), Some(0x3100));
+        assert_eq!(calls.depth(), 0);
+        assert_eq!(calls.mismatches.len(), 1);
+        assert_eq!(calls.mismatches[0].expected, 0x3101);
+
+        let trace = calls.backtrace(0x3001, &SymbolTable::new());
+        assert!(trace.contains("warning: RET at x3205 x3205 went to x3001, expected x3101"));
+    }
+
+    #[test]
+    fn rti_skips_calls_made_by_the_service_routine() {
+        let mut calls = CallStack::new();
+        calls.push(FrameKind::Interrupt, 0x3004, 0x1000, 0x3004);
+        calls.push(FrameKind::Call, 0x1002, 0x1100, 0x1003);
+
+        assert_eq!(calls.rti().map(|f| f.kind), Some(FrameKind::Interrupt));
+        assert_eq!(calls.depth(), 0);
+    }
+
+    #[test]
+    fn jsr_and_ret_drive_the_stack() {
+        /* JSR SUB; HALT; SUB: RET */
+        let mut cpu = program(&[0x4801, 0xF025, 0xC1C0]);
+        cpu.step();
+        assert_eq!(cpu.calls.depth(), 1);
+        assert!(cpu.backtrace().contains("#1  x3002 in x3002 (called from x3000 x3000, returns to x3001)"));
+        cpu.step();
+        assert_eq!(cpu.calls.depth(), 0);
+    }
+
+    #[test]
+    fn builtin_traps_close_their_frame() {
+        /* OUT; HALT */
+        let mut cpu = program(&[0xF021, 0xF025]);
+        redirect_console(Box::new(|_| {}), Box::new(|| 0));
+
+        cpu.step();
+        assert!(cpu.running);
+        assert_eq!(cpu.calls.depth(), 0);
+        assert!(cpu.calls.mismatches.is_empty());
+    }
+
+    #[test]
+    fn native_traps_run_inside_a_trap_frame() {
+        /* TRAP x40; TRAP x21 */
+        let mut cpu = program(&[0xF040, 0xF021]);
+        redirect_console(Box::new(|_| {}), Box::new(|| 0));
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let inside = seen.clone();
+        cpu.register_trap(0x40, Box::new(move |vm: &mut CPU| {
+            inside.borrow_mut().extend(vm.calls.frames.iter().map(|f| (f.kind, f.site, f.target)));
+        }));
+
+        cpu.step();
+        assert_eq!(*seen.borrow(), vec![(FrameKind::Trap, 0x3000, 0x0040)]);
+        assert_eq!(cpu.calls.depth(), 0);
+
+        cpu.step();
+        assert_eq!(cpu.calls.depth(), 0);
+    }
+
+    #[test]
+    fn table_trap_routines_return_through_rti() {
+        /* TRAP x40; HALT, with the routine at x3010 an RTI */
+        let mut cpu = program(&[0xF040, 0xF025]);
+        cpu.memory.memory[0x0040] = 0x3010;
+        cpu.memory.memory[0x3010] = 0x8000;
+
+        cpu.step();
+        assert_eq!(cpu.calls.frames.last().map(|f| (f.kind, f.target)), Some((FrameKind::Trap, 0x3010)));
+        cpu.step();
+        assert_eq!(cpu.pc, 0x3001);
+        assert_eq!(cpu.calls.depth(), 0);
+        assert!(cpu.calls.mismatches.is_empty());
+    }
+}
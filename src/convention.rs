@@ -0,0 +1,186 @@
+use std::fmt;
+
+use crate::callstack::FrameKind;
+use crate::cpu::CPU;
+use crate::symbols::SymbolTable;
+
+/* a register a subroutine did not give back the way it found it */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Violation {
+    /* the JSR/JSRR */
+    pub site: u16,
+    /* the RET */
+    pub ret: u16,
+    pub register: u16,
+    pub expected: u16,
+    pub actual: u16,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "x{:04X}: call from x{:04X} returned with R{} = x{:04X}, expected x{:04X}",
+            self.ret, self.site, self.register, self.actual, self.expected)
+    }
+}
+
+/* what a frame looked like when it was entered */
+struct Entry {
+    kind: FrameKind,
+    site: u16,
+    registers: [u16; 8],
+}
+
+/*
+the textbook C runtime stack: a subroutine hands back R5 unchanged and R6
+one below where it was, pointing at the return value, and leaves any
+callee saved registers alone
+*/
+pub struct ConventionCheck {
+    /* bit n set when Rn must survive a call, R5 and R6 are always checked */
+    pub callee_saved: u8,
+    /* false for subroutines that return in R0 and pop everything they push */
+    pub return_slot: bool,
+    pub stop: bool,
+    /* one per frame of the CPU's call stack */
+    entries: Vec<Entry>,
+    pub violations: Vec<Violation>,
+}
+
+impl ConventionCheck {
+    pub fn new(callee_saved: u8, return_slot: bool, stop: bool) -> ConventionCheck {
+        ConventionCheck {
+            callee_saved,
+            return_slot,
+            stop,
+            entries: Vec::new(),
+            violations: Vec::new(),
+        }
+    }
+
+    /* the call stack was thrown away */
+    pub(crate) fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    fn check(&mut self, entry: &Entry, ret: u16, after: [u16; 8]) -> bool {
+        let mut new = false;
+        /* R7 is whatever the RET went through */
+        for (r, (&was, &actual)) in entry.registers.iter().zip(after.iter()).enumerate().take(7) {
+            let expected = match r {
+                6 if self.return_slot => was.wrapping_sub(1),
+                5 | 6 => was,
+                _ if self.callee_saved & (1 << r) != 0 => was,
+                _ => continue,
+            };
+            if actual != expected {
+                let violation = Violation { site: entry.site, ret, register: r as u16, expected, actual };
+                eprintln!("{}", violation);
+                self.violations.push(violation);
+                new = true;
+            }
+        }
+        new
+    }
+
+    pub fn report(&self, symbols: &SymbolTable) -> String {
+        let mut out = String::new();
+        for v in self.violations.iter() {
+            out += &format!("{}: call from {} returned with R{} = x{:04X}, expected x{:04X}\n",
+                symbols.describe(v.ret), symbols.describe(v.site), v.register, v.actual, v.expected);
+        }
+        out
+    }
+}
+
+impl CPU {
+    pub fn check_convention(&mut self, callee_saved: u8, return_slot: bool, stop: bool) {
+        let mut check = ConventionCheck::new(callee_saved, return_slot, stop);
+
+        /* frames already open when checking starts cannot be judged */
+        for frame in self.calls.frames.iter() {
+            check.entries.push(Entry { kind: FrameKind::Interrupt, site: frame.site, registers: [0; 8] });
+        }
+        self.convention = Some(check);
+    }
+
+    /*
+    follows the call stack after each instruction, `before` is R0-R7 ahead
+    of it, which for a JSR is the state of the caller at the call
+    */
+    pub(crate) fn finish_convention(&mut self, pc: u16, before: [u16; 8]) {
+        let after = self.registers();
+        let depth = self.calls.depth();
+        let Some(check) = self.convention.as_mut() else { return };
+
+        let mut new = false;
+        if depth < check.entries.len() {
+            /* a return to an outer caller closes several frames, the outermost is the one returned from */
+            let closed = check.entries.split_off(depth);
+            if closed[0].kind == FrameKind::Call {
+                new = check.check(&closed[0], pc, after);
+            }
+        }
+        for frame in self.calls.frames[check.entries.len()..].iter() {
+            check.entries.push(Entry { kind: frame.kind, site: frame.site, registers: before });
+        }
+
+        if new && check.stop {
+            self.running = false;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /* JSR SUB; HALT; SUB: `body` then RET */
+    fn program(body: &[u16]) -> CPU {
+        let mut cpu = CPU::new();
+        let words = [0x4801, 0xF025].iter().chain(body.iter()).chain([0xC1C0].iter()).copied();
+        let obj: Vec<u8> = std::iter::once(0x3000).chain(words).flat_map(|w: u16| w.to_be_bytes()).collect();
+        cpu.load_image(&obj);
+        cpu.rr6 = 0x4000;
+        cpu
+    }
+
+    #[test]
+    fn clobbered_frame_pointer_is_reported_at_the_ret() {
+        /* ADD R5, R5, #1 */
+        let mut cpu = program(&[0x1B61]);
+        cpu.check_convention(0, false, false);
+        cpu.run();
+
+        let check = cpu.convention.as_ref().unwrap();
+        assert_eq!(check.violations, vec![Violation { site: 0x3000, ret: 0x3003, register: 5, expected: 0, actual: 1 }]);
+        assert_eq!(check.report(&cpu.symbols), "x3003: call from x3000 returned with R5 = x0001, expected x0000\n");
+    }
+
+    #[test]
+    fn return_slot_expects_r6_one_lower() {
+        /* ADD R6, R6, #-1 */
+        let mut cpu = program(&[0x1DBF]);
+        cpu.check_convention(0, true, false);
+        cpu.run();
+        assert!(cpu.convention.as_ref().unwrap().violations.is_empty());
+
+        let mut cpu = program(&[]);
+        cpu.check_convention(0, true, false);
+        cpu.run();
+        let violations = &cpu.convention.as_ref().unwrap().violations;
+        assert_eq!(violations.iter().map(|v| (v.register, v.expected, v.actual)).collect::<Vec<_>>(), vec![(6, 0x3FFF, 0x4000)]);
+    }
+
+    #[test]
+    fn callee_saved_registers_stop_the_machine() {
+        /* AND R4, R4, #0; ADD R4, R4, #2 */
+        let mut cpu = program(&[0x5920, 0x1922]);
+        cpu.check_convention(1 << 4, false, true);
+        cpu.run();
+
+        /* stopped on the return, before the HALT */
+        assert_eq!(cpu.pc, 0x3001);
+        assert!(!cpu.running);
+        assert_eq!(cpu.convention.as_ref().unwrap().violations.len(), 1);
+    }
+}
@@ -0,0 +1,178 @@
+use std::collections::BTreeMap;
+
+use crate::debuginfo::DebugInfo;
+use crate::disassembler::disassembly;
+use crate::memory::Mem;
+use crate::symbols::SymbolTable;
+
+/* which instructions ran and which way every BR went */
+pub struct Coverage {
+    pub hits: Vec<u64>,
+    /* BR address -> (taken, not taken) */
+    pub branches: BTreeMap<u16, (u64, u64)>,
+}
+
+impl Coverage {
+    pub fn new() -> Coverage {
+        Coverage {
+            hits: vec![0; 1 << 16],
+            branches: BTreeMap::new(),
+        }
+    }
+
+    pub fn executed(&mut self, pc: u16) {
+        self.hits[pc as usize] += 1;
+    }
+
+    pub fn branch(&mut self, pc: u16, taken: bool) {
+        let counts = self.branches.entry(pc).or_insert((0, 0));
+        if taken {
+            counts.0 += 1;
+        } else {
+            counts.1 += 1;
+        }
+    }
+
+    /* (executed, total) instructions and (directions seen, total directions) */
+    pub fn summary(&self, start: u16, end: u16, debug: &DebugInfo) -> ((usize, usize), (usize, usize)) {
+        let code: Vec<u16> = (start..=end)
+            .filter(|&addr| debug.line(addr).map(|l| !l.is_data()).unwrap_or(true))
+            .collect();
+        let executed = code.iter().filter(|&&addr| self.hits[addr as usize] != 0).count();
+
+        let branches = self.branches.values()
+            .map(|&(t, n)| (t != 0) as usize + (n != 0) as usize)
+            .sum();
+
+        ((executed, code.len()), (branches, self.branches.len() * 2))
+    }
+
+    /* one line per word in [start, end] with hit counts and branch directions */
+    pub fn listing(&self, memory: &Mem, start: u16, end: u16, symbols: &SymbolTable, debug: &DebugInfo) -> String {
+        let mut out = String::new();
+
+        for addr in start..=end {
+            let inst = memory.memory[addr as usize];
+            let hits = self.hits[addr as usize];
+            let source = debug.line(addr);
+
+            let marker = if hits != 0 { format!("{:>8}", hits) } else { format!("{:>8}", "#####") };
+            let label = symbols.name(addr).unwrap_or("");
+            let mut line = match source {
+                Some(src) if src.is_data() => format!("{:>8}  x{:04X}  x{:04X}  {:<12} {}", "-", addr, inst, label, src.text),
+                Some(src) => format!("{}  x{:04X}  x{:04X}  {:<12} {}", marker, addr, inst, label, src.text),
+                None => format!("{}  x{:04X}  x{:04X}  {:<12} {}", marker, addr, inst, label, disassembly(inst)),
+            };
+
+            if let Some((taken, not_taken)) = self.branches.get(&addr) {
+                line += &format!("  [taken {} / not taken {}]", taken, not_taken);
+            }
+
+            out += line.trim_end();
+            out.push('\n');
+        }
+
+        out
+    }
+
+    /* lcov tracefile keyed by the source lines in the debug info */
+    pub fn lcov(&self, debug: &DebugInfo) -> String {
+        let mut lines: BTreeMap<u32, u64> = BTreeMap::new();
+        let mut branches: BTreeMap<u32, (u64, u64)> = BTreeMap::new();
+
+        for (&addr, src) in &debug.lines {
+            if src.is_data() {
+                continue;
+            }
+            let hits = lines.entry(src.line).or_insert(0);
+            *hits = (*hits).max(self.hits[addr as usize]);
+
+            if let Some(&counts) = self.branches.get(&addr) {
+                branches.insert(src.line, counts);
+            }
+        }
+
+        let mut out = format!("TN:\nSF:{}\n", debug.source);
+
+        for (line, (taken, not_taken)) in &branches {
+            let executed = lines.get(line).copied().unwrap_or(0) != 0;
+            let count = |n: u64| if executed { n.to_string() } else { "-".to_string() };
+            out += &format!("BRDA:{},0,0,{}\n", line, count(*taken));
+            out += &format!("BRDA:{},0,1,{}\n", line, count(*not_taken));
+        }
+        let hit = branches.values().map(|&(t, n)| (t != 0) as usize + (n != 0) as usize).sum::<usize>();
+        out += &format!("BRF:{}\nBRH:{}\n", branches.len() * 2, hit);
+
+        for (line, hits) in &lines {
+            out += &format!("DA:{},{}\n", line, hits);
+        }
+        out += &format!("LF:{}\nLH:{}\n", lines.len(), lines.values().filter(|&&h| h != 0).count());
+        out += "end_of_record\n";
+
+        out
+    }
+}
+
+impl Default for Coverage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::CPU;
+
+    const LISTING: &str = "\
+(0000) 3000  0011000000000000 (   1)                 .ORIG x3000
+(3000) 5020  0101000000100000 (   2)                 AND R0, R0, #0
+(3001) 0401  0000010000000001 (   3)                 BRz SKIP
+(3002) 1021  0001000000100001 (   4)                 ADD R0, R0, #1
+(3003) F025  1111000000100101 (   5) SKIP            HALT
+(3004) 0007  0000000000000111 (   6) DATA            .FILL 7
+(0000) 0000  0000000000000000 (   7)                 .END
+";
+
+    fn covered() -> CPU {
+        let mut cpu = CPU::new();
+        let words = [0x3000, 0x5020, 0x0401, 0x1021, 0xF025, 0x0007];
+        cpu.load_image(&words.iter().flat_map(|w: &u16| w.to_be_bytes()).collect());
+        cpu.load_listing("prog.asm", LISTING);
+        cpu.coverage = Some(Coverage::new());
+        cpu.run();
+        cpu
+    }
+
+    #[test]
+    fn counts_instructions_and_branch_directions() {
+        let cpu = covered();
+        let coverage = cpu.coverage.as_ref().unwrap();
+
+        assert_eq!(coverage.hits[0x3002], 0);
+        assert_eq!(coverage.branches.get(&0x3001), Some(&(1, 0)));
+        /* the .FILL is not code */
+        assert_eq!(coverage.summary(0x3000, 0x3004, &cpu.debug), ((3, 4), (1, 2)));
+    }
+
+    #[test]
+    fn listing_marks_unexecuted_code_and_data() {
+        let cpu = covered();
+        let listing = cpu.coverage.as_ref().unwrap().listing(&cpu.memory, 0x3000, 0x3004, &cpu.symbols, &cpu.debug);
+        let lines: Vec<&str> = listing.lines().collect();
+
+        assert!(lines[1].ends_with("BRz SKIP  [taken 1 / not taken 0]"));
+        assert!(lines[2].starts_with("   #####  x3002"));
+        assert!(lines[4].starts_with("       -  x3004"));
+    }
+
+    #[test]
+    fn lcov_maps_to_source_lines() {
+        let cpu = covered();
+        let lcov = cpu.coverage.as_ref().unwrap().lcov(&cpu.debug);
+
+        assert_eq!(lcov, "TN:\nSF:prog.asm\n\
+            BRDA:3,0,0,1\nBRDA:3,0,1,0\nBRF:2\nBRH:1\n\
+            DA:2,1\nDA:3,1\nDA:4,0\nDA:5,1\nLF:4\nLH:3\nend_of_record\n");
+    }
+}
@@ -1,9 +1,24 @@
 use std::io::{self, Read};
 use std::io::Write;
-use crate::disassembler::disassemble;
-use crate::io::{get_key, print, pushpc, pushreg};
+use crate::io::{get_key, print};
 use crate::memory::{Mem, self};
 use crate::instructions::*;
+use crate::disassembler::{disassembly_for, Decoded};
+use crate::trace::{destination, TraceRecord, Tracer};
+use crate::profiler::Profiler;
+use crate::symbols::SymbolTable;
+use crate::coverage::Coverage;
+use crate::debuginfo::DebugInfo;
+use crate::callstack::{CallStack, FrameKind};
+use crate::lint::{lint, Lint};
+use crate::block::BlockEngine;
+use crate::events::{Event, Observers};
+use crate::microarch::{Clock, Microarch, FETCH};
+use crate::traps::TrapExtensions;
+use crate::shadow::UninitCheck;
+use crate::stack::StackGuard;
+use crate::convention::ConventionCheck;
+use crate::interrupts::{ILLEGAL_OPCODE, SUPERVISOR_STACK};
 
 use super::*;
 
@@ -21,8 +36,40 @@ pub struct CPU {
     pub pc : usize,
     pub rcond : u16,
     pub rcount : u16,
+    /* PSR[15], set while running in user mode */
+    pub user: bool,
+    /* PSR[10:8] */
+    pub priority: u16,
+    /* whichever stack pointer is not in R6 right now */
+    pub saved_ssp: u16,
+    pub saved_usp: u16,
+    /* teaching mode, user programs may touch the system and device pages without ACV */
+    pub relaxed: bool,
+    pub isa: Isa,
     pub memory: Mem,
-    pub running: bool
+    pub running: bool,
+    pub tracer: Option<Tracer>,
+    pub profiler: Option<Profiler>,
+    pub symbols: SymbolTable,
+    pub debug: DebugInfo,
+    pub coverage: Option<Coverage>,
+    /* first and last address written by the last load_image */
+    pub image: Option<(u16, u16)>,
+    pub calls: CallStack,
+    /* translated basic blocks used by run_for, None to interpret one step at a time */
+    pub blocks: Option<BlockEngine>,
+    pub observers: Observers,
+    /* clocks instructions through the control state machine when set */
+    pub micro: Option<Microarch>,
+    pub traps: TrapExtensions,
+    /* instructions executed, read by the TICKS trap */
+    pub ticks: u64,
+    /* which of R0-R7 have been written, when checking for uninitialised reads */
+    pub uninit: Option<UninitCheck>,
+    /* R6 stack bounds, when checking for overflow */
+    pub stack: Option<StackGuard>,
+    /* register state at each open call, when checking the calling convention */
+    pub convention: Option<ConventionCheck>
 }
 
 impl CPU {
@@ -39,8 +86,29 @@ impl CPU {
             pc : PC_START,
             rcond : 0,
             rcount : 0,
+            user: false,
+            priority: 0,
+            saved_ssp: SUPERVISOR_STACK,
+            saved_usp: 0,
+            relaxed: false,
+            isa: Isa::Lc3,
             memory : Mem::new(),
-            running: true
+            running: true,
+            tracer: None,
+            profiler: None,
+            symbols: SymbolTable::new(),
+            debug: DebugInfo::new(),
+            coverage: None,
+            image: None,
+            calls: CallStack::new(),
+            blocks: None,
+            observers: Observers::new(),
+            micro: None,
+            traps: TrapExtensions::new(),
+            ticks: 0,
+            uninit: None,
+            stack: None,
+            convention: None
         }
     }
 
@@ -48,44 +116,241 @@ impl CPU {
         self.rcond = Flags::value(&Flags::FlZro);
 
         while(self.running) {
-            self.step();
+            self.run_for(10000);
         }
     }
 
+    /* per-instruction hooks that only CPU::step knows how to feed */
+    fn observed(&self) -> bool {
+        self.tracer.is_some() || self.profiler.is_some() || self.coverage.is_some() || self.observers.steps
+            || self.micro.is_some() || self.memory.clocked()
+            || self.uninit.is_some() || self.memory.code.is_some() || self.stack.is_some() || self.convention.is_some()
+    }
+
+    pub fn subscribe(&mut self, steps: bool, callback: Box<dyn FnMut(&Event)>) -> usize {
+        self.observers.subscribe(steps, callback)
+    }
+
+    pub fn unsubscribe(&mut self, id: usize) {
+        self.observers.unsubscribe(id);
+    }
+
+    /*
+    runs up to `count` instructions, through translated blocks when the
+    block engine is on and nothing needs to see every step; returns how many ran
+    */
+    pub fn run_for(&mut self, count: usize) -> usize {
+        let mut done = 0;
+
+        while done < count && self.running {
+            /* stores into translated code, from the last block or a step, drop what they touched */
+            if !self.memory.watch.hits.is_empty() {
+                if let Some(engine) = self.blocks.as_mut() {
+                    engine.invalidate(&mut self.memory);
+                }
+            }
+
+            /* blocks are LC-3 only, and an ACV part way through one would not stop the rest */
+            let interpret = self.observed() || self.isa != Isa::Lc3 || self.protected();
+            let block = match self.blocks.as_mut() {
+                Some(engine) if !interpret => engine.block(self.pc as u16, &mut self.memory),
+                _ => {
+                    self.step();
+                    done += 1;
+                    continue;
+                }
+            };
+
+            if block.ops.is_empty() || block.ops.len() > count - done {
+                self.step();
+                done += 1;
+                continue;
+            }
+
+            for &d in block.ops.iter() {
+                self.pc += 1;
+                self.ticks += 1;
+                self.dispatch(d);
+                done += 1;
+
+                /* a store into translated code ends the block right after it */
+                if !self.memory.watch.hits.is_empty() {
+                    break;
+                }
+            }
+        }
+
+        done
+    }
+
     pub fn step(&mut self) {
-        let inst = self.fetch();
+        let pc = self.pc as u16;
+        self.ticks += 1;
+
+        /* the fetch itself is an access */
+        if !self.access_ok(pc) || !self.fetch_checked(pc) {
+            return;
+        }
+
+        let before = self.registers();
+
+        let inst = if self.tracer.is_some() {
+            self.step_traced()
+        } else if self.micro.is_some() && self.isa == Isa::Lc3 {
+            self.step_micro()
+        } else {
+            let inst = self.fetch();
+            self.execute(inst);
+            inst
+        };
+
+        if self.uninit.is_some() {
+            self.finish_uninit(pc);
+        }
+        if self.memory.code.is_some() {
+            self.finish_code(pc);
+        }
+        if self.stack.is_some() {
+            self.finish_stack(pc, before[6]);
+        }
+        if self.convention.is_some() {
+            self.finish_convention(pc, before);
+        }
+        if let Some(profiler) = self.profiler.as_mut() {
+            profiler.record(pc, inst, self.pc as u16);
+        }
+        if let Some(coverage) = self.coverage.as_mut() {
+            coverage.executed(pc);
+        }
+        if self.observers.steps {
+            self.observers.emit(Event::Step { pc, inst });
+        }
+        if std::mem::take(&mut self.observers.halted) {
+            self.observers.emit(Event::Halt);
+        }
+        if self.memory.clocked() {
+            self.poll_interrupts();
+        }
+    }
 
-        disassemble(inst);
+    /* one clock of the state machine, None unless microarchitectural mode is on */
+    pub fn clock(&mut self) -> Option<Clock> {
+        let mut micro = self.micro.take()?;
+        let clock = micro.clock(self);
+        self.micro = Some(micro);
+        Some(clock)
+    }
 
-        pushpc(self.pc);
-        pushreg(self);
+    /* clocks until the state machine is back at fetch, finishing the current instruction */
+    fn step_micro(&mut self) -> u16 {
+        let Some(mut micro) = self.micro.take() else { return 0 };
+        while self.running {
+            if micro.clock(self).next == FETCH {
+                break;
+            }
+        }
+        let inst = micro.ir;
+        self.micro = Some(micro);
+        inst
+    }
 
-        let op = inst >> 12;
+    /*
+    runs one instruction while recording what it changed; accesses are
+    copied so a caller that is logging too (the GDB stub) still sees them.
+    The state machine fetches for itself, so its fetch read is left out
+    */
+    fn step_traced(&mut self) -> u16 {
+        let pc = self.pc as u16;
+        let regs = self.registers();
+        let cond = self.rcond;
+        let micro = self.micro.is_some() && self.isa == Isa::Lc3;
+
+        let inst = if micro { 0 } else { self.fetch() };
+
+        let logging = self.memory.logging;
+        let start = self.memory.accesses.len();
+        self.memory.logging = true;
+        let inst = if micro {
+            self.step_micro()
+        } else {
+            self.execute(inst);
+            inst
+        };
+        self.memory.logging = logging;
+
+        /* the destination is written even when the value does not change */
+        let dest = destination(self.isa, inst);
+        let reg_writes = self.registers().iter().zip(regs.iter()).enumerate()
+            .filter(|(r, (new, old))| new != old || dest == Some(*r as u16))
+            .map(|(r, (new, _))| (r as u16, *new))
+            .collect();
+
+        let mut mem = self.memory.accesses[start..].to_vec();
+        if micro && mem.first() == Some(&memory::Access::Read(pc as usize, inst)) {
+            mem.remove(0);
+        }
+        if !logging {
+            self.memory.accesses.truncate(start);
+        }
+
+        let rec = TraceRecord {
+            pc,
+            inst,
+            disassembly: disassembly_for(self.isa, inst),
+            reg_writes,
+            mem,
+            cond: if cond != self.rcond { Some((cond, self.rcond)) } else { None },
+        };
+
+        if let Some(tracer) = self.tracer.as_mut() {
+            tracer.record(&rec);
+        }
+
+        inst
+    }
 
-        // println!("{:?}", OPCodes::from(op));
+    fn execute(&mut self, inst: u16) {
+        if self.isa == Isa::Lc3b {
+            return self.execute_lc3b(inst);
+        }
+
+        /* fields come from the decode cache, keyed by the address the word was fetched from */
+        let d = self.memory.cache.get(self.pc.wrapping_sub(1) & 0xFFFF, inst);
+
+        // println!("{:?}", d.op);
         // println!("PC 0x{:x?}", self.pc - 0x3000);
 
-        match(OPCodes::from(op)) {
-            OPCodes::OpBr => self.br(inst),
-            OPCodes::OpAdd => self.add(inst),
-            OPCodes::OpLd => self.ld(inst),
-            OPCodes::OpSt => self.st(inst),
-            OPCodes::OpJsr => self.jsr(inst),
-            OPCodes::OpAnd => self.and_(inst),
-            OPCodes::OpLdr => self.ldr(inst),
-            OPCodes::OpStr => self.str(inst),
-            OPCodes::OpRti => self.rti(inst),
-            OPCodes::OpNot => self.not(inst),
-            OPCodes::OpLdi => self.ldi(inst),
-            OPCodes::OpSti => self.sti(inst),
-            OPCodes::OpJmp => self.jmp(inst),
-            OPCodes::OpRes => self.res(inst),
-            OPCodes::OpLea => self.lea(inst),
-            OPCodes::OpTrap => self.trap(inst),
+        self.dispatch(d);
+    }
+
+    pub(crate) fn dispatch(&mut self, d: Decoded) {
+        match d.op {
+            OPCodes::OpBr => self.br(d),
+            OPCodes::OpAdd => self.add(d),
+            OPCodes::OpLd => self.ld(d),
+            OPCodes::OpSt => self.st(d),
+            OPCodes::OpJsr => self.jsr(d),
+            OPCodes::OpAnd => self.and_(d),
+            OPCodes::OpLdr => self.ldr(d),
+            OPCodes::OpStr => self.str(d),
+            OPCodes::OpRti => self.rti(d),
+            OPCodes::OpNot => self.not(d),
+            OPCodes::OpLdi => self.ldi(d),
+            OPCodes::OpSti => self.sti(d),
+            OPCodes::OpJmp => self.jmp(d),
+            OPCodes::OpRes => self.res(d),
+            OPCodes::OpLea => self.lea(d),
+            OPCodes::OpTrap => self.trap(d),
         }
     }
 
     fn fetch(&mut self) -> u16 {
+        if self.isa == Isa::Lc3b {
+            let inst = self.memory.read(self.pc & 0xFFFE);
+            self.pc = (self.pc + 2) & 0xFFFF;
+            return inst;
+        }
+
         let inst = self.memory.read(self.pc);
         self.pc += 1;
         return inst;
@@ -94,9 +359,16 @@ impl CPU {
     pub fn load_image(&mut self, image: &Vec<u8>) {
         let origin: u16 = ((image[0] as u16) << 8) | (image[1] as u16);
 
-        println!("Program Address : {:#01x}", origin);
+        eprintln!("Program Address : {:#01x}", origin);
 
         self.pc = origin as usize;
+        self.calls.clear();
+        if let Some(check) = self.convention.as_mut() {
+            check.clear();
+        }
+
+        /* LC-3b origins are byte addresses and each word takes two of them */
+        let stride = if self.isa == Isa::Lc3b { 2 } else { 1 };
 
         let mut i = 2;
         let mut count = 0;
@@ -105,24 +377,81 @@ impl CPU {
             //self.memory.memory[origin as usize + count] = (image[i] as u16) | ((image[i+1] as u16) << 8);
 
             // big endian
-            self.memory.memory[origin as usize + count] = ((image[i] as u16) << 8) | (image[i+1] as u16);
+            self.memory.memory[(origin as usize + count * stride) & 0xFFFF] = ((image[i] as u16) << 8) | (image[i+1] as u16);
             i += 2;
             count += 1;
         }
 
+        for i in 0..count {
+            let addr = (origin as usize + i * stride) & 0xFFFF;
+            if let Some(shadow) = self.memory.shadow.as_mut() {
+                shadow.mark(addr);
+            }
+            self.memory.loaded(addr);
+        }
+
+        /* the image was written straight into memory, past the caches */
+        self.memory.cache.clear();
+        self.memory.watch.clear();
+        if let Some(engine) = self.blocks.as_mut() {
+            engine.clear();
+        }
+
+        if count > 0 {
+            let end = origin.wrapping_add((count * stride) as u16 - 1);
+            self.image = Some((origin, end));
+            self.classify(origin, end);
+        }
+
         // println!("{:x?}", &self.memory.memory);
     }
 
-    fn sign_extend(&mut self, x: u16, bit_count: u16) -> u16 {
-        let mut x = x;
-        if ((x >> (bit_count - 1)) & 1) != 0 {
-            x |= (0xFFFF << bit_count);
+    pub fn registers(&self) -> [u16; 8] {
+        [self.rr0, self.rr1, self.rr2, self.rr3, self.rr4, self.rr5, self.rr6, self.rr7]
+    }
+
+    pub fn backtrace(&self) -> String {
+        self.calls.backtrace(self.pc as u16, &self.symbols)
+    }
+
+    /* static checks over the loaded image, starting from its origin */
+    pub fn lint(&self) -> Vec<Lint> {
+        if self.isa != Isa::Lc3 {
+            return Vec::new();
         }
+        match self.image {
+            Some((origin, end)) => lint(&self.memory, origin, (origin, end), &self.debug, &self.symbols),
+            None => Vec::new(),
+        }
+    }
 
-        return x;
+    pub fn load_symbols(&mut self, text: &str) {
+        self.symbols = SymbolTable::parse(text);
+    }
+
+    pub fn load_listing(&mut self, source: &str, text: &str) {
+        self.debug = DebugInfo::parse(source, text);
+    }
+
+    /* processor status register: privilege in bit 15, priority in 10:8, condition codes in 2:0 */
+    pub fn psr(&self) -> u16 {
+        ((self.user as u16) << 15) | ((self.priority & 0x7) << 8) | (self.rcond & 0x7)
+    }
+
+    pub fn set_psr(&mut self, psr: u16) {
+        self.user = psr & 0x8000 != 0;
+        self.priority = (psr >> 8) & 0x7;
+        self.rcond = psr & 0x7;
+    }
+
+    pub fn set_register(&mut self, r: u16, val: u16) {
+        self.set_reg(r, val);
     }
 
     pub fn get_reg(&mut self, r: u16) -> &u16 {
+        if let Some(check) = self.uninit.as_mut() {
+            check.check_register(r);
+        }
         match r {
             0 => &self.rr0,
             1 => &self.rr1,
@@ -139,7 +468,10 @@ impl CPU {
         }
     }
 
-    fn set_reg(&mut self, r: u16, val: u16)  {
+    pub(crate) fn set_reg(&mut self, r: u16, val: u16)  {
+        if let Some(check) = self.uninit.as_mut() {
+            check.mark_register(r);
+        }
         match r {
             0 => self.rr0 = val,
             1 => self.rr1 = val,
@@ -152,11 +484,11 @@ impl CPU {
             // 8 => self.pc = val,
             9 => self.rcond = val,
             10 => self.rcount = val,
-            _ => println!("Cannot find register {}", r)
+            _ => eprintln!("Cannot find register {}", r)
         }
     }
 
-    fn update_flags(&mut self, r: u16) {
+    pub(crate) fn update_flags(&mut self, r: u16) {
         if (*self.get_reg(r) == 0) {
             self.rcond = Flags::value(&Flags::FlZro);
         } else if ((*self.get_reg(r) >> 15) != 0) {
@@ -167,182 +499,236 @@ impl CPU {
         }
     }
 
-    fn br(&mut self, inst: u16) {
-        let offset = self.sign_extend(inst & 0x1ff, 9);
-        let cond_flag = (inst >> 9) & 0x7;
+    fn br(&mut self, d: Decoded) {
+        let taken = (d.r0 & self.rcond) != 0;
+        if let Some(coverage) = self.coverage.as_mut() {
+            coverage.branch((self.pc as u16).wrapping_sub(1), taken);
+        }
 
-        if ((cond_flag & self.rcond) != 0) {
+        if taken {
             // self.pc += offset as usize;
-            let val = (self.pc as u16).wrapping_add(offset);
+            let val = (self.pc as u16).wrapping_add(d.imm);
             self.pc = val as usize;
         }
     }
 
-    fn add(&mut self, inst: u16) {
-        /* destination register (DR) */
-        let r0 = (inst >> 9) & 0x7;
-        /* first operand (SR1) */
-        let r1 = (inst >> 6) & 0x7;
-        /* whether we are in immediate mode */
-        let imm_flag = (inst >> 5) & 0x1;
+    fn add(&mut self, d: Decoded) {
+        let r = *self.get_reg(d.r1);
 
-        if (imm_flag != 0)
-        {
-            let imm5 = self.sign_extend(inst & 0x1F, 5);
-            let r = *self.get_reg(r1);
-            self.set_reg(r0, r.wrapping_add(imm5));
-        }
-        else
-        {
-            let r2 = inst & 0x7;
-            let r = *self.get_reg(r1);
-            let l = *self.get_reg(r2);
-            self.set_reg(r0, r.wrapping_add(l));
+        /* immediate mode */
+        if d.flag {
+            self.set_reg(d.r0, r.wrapping_add(d.imm));
+        } else {
+            let l = *self.get_reg(d.r2);
+            self.set_reg(d.r0, r.wrapping_add(l));
         }
 
-        self.update_flags(r0);
+        self.update_flags(d.r0);
     }
 
-    fn ld(&mut self, inst: u16) {
-        let r0 = (inst >> 9) & 0x7;
-        let pc_offset = self.sign_extend(inst & 0x1FF, 9);
-        let val = self.memory.read((self.pc as u16).wrapping_add(pc_offset) as usize);
-        
-        self.set_reg(r0, val);
+    fn ld(&mut self, d: Decoded) {
+        let addr = (self.pc as u16).wrapping_add(d.imm);
+        if !self.access_ok(addr) {
+            return;
+        }
+        let val = self.memory.read(addr as usize);
+
+        self.set_reg(d.r0, val);
 
-        self.update_flags(r0);
+        self.update_flags(d.r0);
     }
 
-    fn st(&mut self, inst: u16) {
-        let r0 = (inst >> 9) & 0x7;
-        let pc_offset = self.sign_extend(inst & 0x1FF, 9);
-
-        let val = (self.pc as u16).wrapping_add(pc_offset);
-        self.memory.write(val as usize, self.rr0);
+    fn st(&mut self, d: Decoded) {
+        let addr = (self.pc as u16).wrapping_add(d.imm);
+        if !self.access_ok(addr) {
+            return;
+        }
+        let val = *self.get_reg(d.r0);
+        self.memory.write(addr as usize, val);
     }
 
-    fn jsr(&mut self, inst: u16) {
-        let long_flag = (inst >> 11) & 1;
-        self.rr7 = self.pc as u16;
+    fn jsr(&mut self, d: Decoded) {
+        self.set_reg(7, self.pc as u16);
 
-        if (long_flag != 0) {
-            let long_pc_offset = self.sign_extend(inst & 0x7FF, 11);
-            self.pc = (self.pc as u16).wrapping_add(long_pc_offset) as usize;  /* JSR */
+        if d.flag {
+            self.pc = (self.pc as u16).wrapping_add(d.imm) as usize;  /* JSR */
         } else {
-            let r1 = (inst >> 6) & 0x7;
-            self.pc = *self.get_reg(r1) as usize; /* JSRR */
+            self.pc = *self.get_reg(d.r1) as usize; /* JSRR */
         }
-    }
 
-    fn and_(&mut self, inst: u16) {
-        let r0 = (inst >> 9) & 0x7;
-        let r1 = (inst >> 6) & 0x7;
-        let imm_flag = (inst >> 5) & 0x1;
+        self.calls.push(FrameKind::Call, self.rr7.wrapping_sub(1), self.pc as u16, self.rr7);
+    }
 
-        if (imm_flag != 0)
-        {
-            let imm5 = self.sign_extend(inst & 0x1F, 5);
-            let val = *self.get_reg(r1) & imm5;
+    fn and_(&mut self, d: Decoded) {
+        /* AND Rx, Ry, #0 is how registers get cleared, it does not depend on Ry */
+        let r = if d.flag && d.imm == 0 { 0 } else { *self.get_reg(d.r1) };
 
-            self.set_reg(r0, val);
+        if d.flag {
+            self.set_reg(d.r0, r & d.imm);
+        } else {
+            let l = *self.get_reg(d.r2);
+            self.set_reg(d.r0, r & l);
         }
-        else
-        {
-            let r2 = inst & 0x7;
-            let val = *self.get_reg(r1) & *self.get_reg(r2);
 
-            self.set_reg(r0, val);
-        }
-        
-        self.update_flags(r0);
+        self.update_flags(d.r0);
     }
 
-    fn ldr(&mut self, inst: u16) {
-        let r0 = (inst >> 9) & 0x7;
-        let r1 = (inst >> 6) & 0x7;
-        let offset = self.sign_extend(inst & 0x3F, 6);
+    fn ldr(&mut self, d: Decoded) {
+        let r1 = *self.get_reg(d.r1);
+        let addr = r1.wrapping_add(d.imm);
+        if !self.access_ok(addr) {
+            return;
+        }
+        let val = self.memory.read(addr as usize);
 
-        let r1 = *self.get_reg(r1);
-        let val = self.memory.read((r1 + offset) as usize);
+        self.set_reg(d.r0, val);
 
-        self.set_reg(r0, val);
-        
-        self.update_flags(r0);
+        self.update_flags(d.r0);
     }
 
-    fn str(&mut self, inst: u16) {
-        let r0 = (inst >> 9) & 0x7;
-        let r1 = (inst >> 6) & 0x7;
-        let offset = self.sign_extend(inst & 0x3F, 6);
-
-        let r0 = *self.get_reg(r0);
-        let r1 = *self.get_reg(r1);
-        self.memory.write((r1.wrapping_add(offset)) as usize, r0);
+    fn str(&mut self, d: Decoded) {
+        let r0 = *self.get_reg(d.r0);
+        let r1 = *self.get_reg(d.r1);
+        let addr = r1.wrapping_add(d.imm);
+        if !self.access_ok(addr) {
+            return;
+        }
+        self.stack_store(d.r1, addr);
+        self.memory.write(addr as usize, r0);
     }
 
-    fn rti(&mut self, inst: u16) {
-        panic!("Unused OPCode RTI");
+    fn rti(&mut self, _d: Decoded) {
+        self.return_from_interrupt();
     }
 
-    fn not(&mut self, inst: u16) {
-        let r0 = (inst >> 9) & 0x7;
-        let r1 = (inst >> 6) & 0x7;
-
-        let val = *self.get_reg(r1);
-        self.set_reg(r0, !val);
-        self.update_flags(r0);
+    fn not(&mut self, d: Decoded) {
+        let val = *self.get_reg(d.r1);
+        self.set_reg(d.r0, !val);
+        self.update_flags(d.r0);
     }
 
-    fn ldi(&mut self, inst: u16) {
-        /* destination register (DR) */
-        let r0 = (inst >> 9) & 0x7;
-
-        /* PCoffset 9*/
-        let pc_offset = self.sign_extend(inst & 0x1FF, 9);
-
+    fn ldi(&mut self, d: Decoded) {
         /* add pc_offset to the current PC, look at that memory location to get the final address */
-        let addr = self.memory.read((self.pc as u16).wrapping_add(pc_offset) as usize);
-        
+        let ptr = (self.pc as u16).wrapping_add(d.imm);
+        if !self.access_ok(ptr) {
+            return;
+        }
+        let addr = self.memory.read(ptr as usize);
+        if !self.access_ok(addr) {
+            return;
+        }
+
         let val = self.memory.read(addr as usize);
-        self.set_reg(r0, val);
-        self.update_flags(r0);
+        self.set_reg(d.r0, val);
+        self.update_flags(d.r0);
     }
 
-    fn sti(&mut self, inst: u16) {
-        let r0 = (inst >> 9) & 0x7;
-        let pc_offset = self.sign_extend(inst & 0x1FF, 9);
-        let addr = self.memory.read((self.pc as u16).wrapping_add(pc_offset) as usize);
-        
-        let val = *self.get_reg(r0);
+    fn sti(&mut self, d: Decoded) {
+        let ptr = (self.pc as u16).wrapping_add(d.imm);
+        if !self.access_ok(ptr) {
+            return;
+        }
+        let addr = self.memory.read(ptr as usize);
+        if !self.access_ok(addr) {
+            return;
+        }
+
+        let val = *self.get_reg(d.r0);
         self.memory.write(addr as usize, val);
     }
 
-    fn jmp(&mut self, inst: u16) {
-        let r = (inst >> 6) & 0x7;
-        let r = *self.get_reg(r);
+    fn jmp(&mut self, d: Decoded) {
+        let r = *self.get_reg(d.r1);
+
+        /* JMP R7 is RET */
+        if d.r1 == 7 {
+            self.calls.ret((self.pc as u16).wrapping_sub(1), r);
+        }
         self.pc = r as usize;
     }
 
-    fn res(&mut self, inst: u16) {
-        panic!("Unused OPCode RES");
+    fn res(&mut self, _d: Decoded) {
+        self.exception(ILLEGAL_OPCODE);
+    }
+
+    fn lea(&mut self, d: Decoded) {
+        self.set_reg(d.r0, (self.pc as u16).wrapping_add(d.imm));
+
+        self.update_flags(d.r0);
+    }
+
+    fn trap(&mut self, d: Decoded) {
+        let vector = d.imm as u8;
+        let code = TrapCodes::from(d.imm);
+        /* disabled extended traps go through the table like any unknown vector */
+        let native = code.is_some_and(|code| self.traps.caps.allows(code));
+        if !native && !self.traps.handles(vector) {
+            return self.trap_table(vector);
+        }
+
+        self.in_trap_frame(vector, |cpu| {
+            /* registered handlers come first, so they can replace the built in routines */
+            if cpu.run_trap_handler(vector) {
+                return;
+            }
+
+            match code {
+                Some(TrapCodes::TrapGetC) => cpu.trap_getc(),
+                Some(TrapCodes::TrapOut) => cpu.trap_out(),
+                Some(TrapCodes::TrapPuts) => cpu.trap_puts(),
+                Some(TrapCodes::TrapIn) => cpu.trap_in_(),
+                Some(TrapCodes::TrapPutsP) => cpu.trap_putsp(),
+                Some(TrapCodes::TrapHalt) => cpu.trap_halt(),
+                Some(code) => cpu.trap_extended(code),
+                None => {},
+            }
+        });
     }
 
-    fn lea(&mut self, inst: u16) {
-        let r0 = (inst >> 9) & 0x7;
-        let pc_offset = self.sign_extend(inst & 0x1FF, 9);
-        self.set_reg(r0, (self.pc as u16).wrapping_add(pc_offset));
-        
-        self.update_flags(r0);
+    /*
+    a routine run natively sits in a Trap frame while it runs, as one
+    reached through the table would, so handlers see it in backtraces
+    */
+    pub(crate) fn in_trap_frame(&mut self, vector: u8, routine: impl FnOnce(&mut CPU)) {
+        let (slot, width) = match self.isa {
+            Isa::Lc3 => (vector as u16, 1),
+            Isa::Lc3b => ((vector as u16) << 1, 2),
+        };
+        let depth = self.calls.depth();
+        let ret = self.pc as u16;
+
+        self.calls.push(FrameKind::Trap, ret.wrapping_sub(width), slot, ret);
+        routine(self);
+        self.calls.unwind(depth);
     }
 
-    fn trap(&mut self, inst: u16) {
-        match TrapCodes::from(inst & 0xFF) {
-            TrapCodes::TrapGetC => self.trap_getc(),
-            TrapCodes::TrapOut => self.trap_out(),
-            TrapCodes::TrapPuts => self.trap_puts(),
-            TrapCodes::TrapIn => self.trap_in_(),
-            TrapCodes::TrapPutsP => self.trap_putsp(),
-            TrapCodes::TrapHalt => self.trap_halt(),
+    /*
+    PC <- M[vector] as the hardware does; the LC-3 enters the routine in
+    supervisor mode with PSR and PC on the supervisor stack, for RTI to
+    return through, while the LC-3b leaves the return address in R7. An
+    empty entry stops the machine
+    */
+    pub(crate) fn trap_table(&mut self, vector: u8) {
+        let slot = match self.isa {
+            Isa::Lc3 => vector as usize,
+            Isa::Lc3b => (vector as usize) << 1,
+        };
+
+        let entry = self.memory.read(slot);
+        if entry == 0 {
+            eprintln!("Unknown TRAP vector x{:02X}", vector);
+            self.running = false;
+            return;
+        }
+
+        let ret = self.pc as u16;
+        match self.isa {
+            Isa::Lc3 => self.enter_supervisor(entry, None, FrameKind::Trap, ret.wrapping_sub(1)),
+            Isa::Lc3b => {
+                self.set_reg(7, ret);
+                self.pc = entry as usize;
+                self.calls.push(FrameKind::Trap, ret.wrapping_sub(2), entry, ret);
+            },
         }
     }
 
@@ -351,7 +737,7 @@ impl CPU {
 
         // print(ch);
 
-        self.rr0 = ch as u16;
+        self.set_reg(0, ch as u16);
 
         // self.update_flags(self.rr0);
     }
@@ -392,9 +778,9 @@ impl CPU {
         //         .map(|byte| byte as u16)
         //         .unwrap();
 
-        self.rr0 = char;
+        self.set_reg(0, char);
 
-        self.update_flags(self.rr0);
+        self.update_flags(0);
     }
 
     fn trap_putsp(&mut self) {
@@ -420,7 +806,8 @@ impl CPU {
     }
 
     fn trap_halt(&mut self) {
-        println!("HALTING");
+        eprintln!("HALTING");
         self.running = false;
+        self.observers.halted = true;
     }
 }
\ No newline at end of file
@@ -0,0 +1,655 @@
+use std::cell::RefCell;
+use std::collections::{BTreeSet, VecDeque};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+use std::rc::Rc;
+use std::sync::mpsc;
+use std::thread;
+
+use crate::cpu::CPU;
+use crate::instructions::Isa;
+use crate::io::redirect_console;
+use crate::json::Value;
+use crate::trace::cond_name;
+
+/*
+Debug Adapter Protocol over stdin/stdout.
+
+The launch request takes `program` (an .obj), and optionally `listing`,
+`symbols` and `source`; by default they are the .lst, .sym and .asm files
+next to the program. Breakpoints are set on source lines through the
+listing. Program output arrives as output events and `input <text>` in the
+debug console feeds the keyboard.
+*/
+
+/* instructions run between checks for new requests */
+const BATCH: usize = 10000;
+
+const REGISTERS_REF: i64 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Mode {
+    Paused,
+    Continue,
+    StepOver(usize),
+    StepOut(usize),
+}
+
+pub struct DapServer {
+    seq: i64,
+    mode: Mode,
+    stop_on_entry: bool,
+    /* set on resume so a breakpoint at the current PC does not stop again at once */
+    resuming: bool,
+    breakpoints: BTreeSet<u16>,
+    output: Rc<RefCell<Vec<u8>>>,
+    keys: Rc<RefCell<VecDeque<u8>>>,
+}
+
+fn read_message<R: BufRead>(input: &mut R) -> io::Result<Option<Value>> {
+    let mut len = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim();
+        if header.is_empty() {
+            break;
+        }
+        if let Some(n) = header.strip_prefix("Content-Length:") {
+            len = n.trim().parse::<usize>().ok();
+        }
+    }
+
+    let Some(len) = len else { return Ok(Some(Value::Null)) };
+    let mut body = vec![0u8; len];
+    input.read_exact(&mut body)?;
+    Ok(Some(Value::parse(&String::from_utf8_lossy(&body)).unwrap_or(Value::Null)))
+}
+
+fn base64(bytes: &[u8]) -> String {
+    const TABLE: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::new();
+
+    for chunk in bytes.chunks(3) {
+        let n = chunk.iter().enumerate().fold(0u32, |acc, (i, &b)| acc | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(TABLE[(n >> (18 - 6 * i) & 0x3F) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+
+    out
+}
+
+/* x3000, 0x3000, #12288, 12288 or a label */
+fn parse_address(cpu: &CPU, text: &str) -> Option<u16> {
+    let text = text.trim();
+    if let Some(hex) = text.strip_prefix("0x").or_else(|| text.strip_prefix('x')).or_else(|| text.strip_prefix('X')) {
+        if let Ok(addr) = u16::from_str_radix(hex, 16) {
+            return Some(addr);
+        }
+    }
+    if let Ok(n) = text.trim_start_matches('#').parse::<i32>() {
+        return Some(n as u16);
+    }
+    cpu.symbols.lookup(text)
+}
+
+/*
+the first instruction at or after a source line; a breakpoint on .FILL,
+.BLKW or .STRINGZ moves down to the next line of code
+*/
+fn breakpoint_address(cpu: &CPU, line: u32) -> Option<(u16, u32)> {
+    cpu.debug.lines.iter()
+        .filter(|(_, src)| src.line >= line && !src.is_data())
+        .map(|(&addr, src)| (addr, src.line))
+        .min_by_key(|&(addr, at)| (at, addr))
+}
+
+fn register_index(name: &str) -> Option<u16> {
+    match name.to_uppercase().as_str() {
+        "R0" => Some(0),
+        "R1" => Some(1),
+        "R2" => Some(2),
+        "R3" => Some(3),
+        "R4" => Some(4),
+        "R5" => Some(5),
+        "R6" => Some(6),
+        "R7" => Some(7),
+        _ => None,
+    }
+}
+
+impl DapServer {
+    pub fn new() -> DapServer {
+        DapServer {
+            seq: 1,
+            mode: Mode::Paused,
+            stop_on_entry: false,
+            resuming: false,
+            breakpoints: BTreeSet::new(),
+            output: Rc::new(RefCell::new(Vec::new())),
+            keys: Rc::new(RefCell::new(VecDeque::new())),
+        }
+    }
+
+    /* serves one debug session on stdin/stdout */
+    pub fn run_stdio(&mut self, cpu: &mut CPU) -> io::Result<()> {
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let mut stdin = BufReader::new(io::stdin());
+            while let Ok(Some(msg)) = read_message(&mut stdin) {
+                if tx.send(msg).is_err() {
+                    break;
+                }
+            }
+        });
+
+        let output = self.output.clone();
+        let keys = self.keys.clone();
+        redirect_console(
+            Box::new(move |c| output.borrow_mut().push(c)),
+            Box::new(move || keys.borrow_mut().pop_front().unwrap_or(0)),
+        );
+
+        let mut out = io::stdout();
+        loop {
+            let msg = if self.mode == Mode::Paused {
+                match rx.recv() {
+                    Ok(msg) => Some(msg),
+                    Err(_) => return Ok(()),
+                }
+            } else {
+                match rx.try_recv() {
+                    Ok(msg) => Some(msg),
+                    Err(mpsc::TryRecvError::Empty) => None,
+                    Err(mpsc::TryRecvError::Disconnected) => return Ok(()),
+                }
+            };
+
+            if let Some(msg) = msg {
+                if !self.handle(cpu, &msg, &mut out)? {
+                    return Ok(());
+                }
+            }
+
+            if self.mode != Mode::Paused {
+                let stop = self.run(cpu);
+                self.report(&mut out, stop)?;
+            }
+        }
+    }
+
+    /* runs a batch in the current mode, returning why it stopped if it did */
+    fn run(&mut self, cpu: &mut CPU) -> Option<&'static str> {
+        for _ in 0..BATCH {
+            if !self.resuming && self.breakpoints.contains(&(cpu.pc as u16)) {
+                return Some("breakpoint");
+            }
+            self.resuming = false;
+
+            cpu.step();
+
+            if !cpu.running {
+                return Some("exited");
+            }
+            let depth = cpu.calls.depth();
+            match self.mode {
+                Mode::StepOver(d) if depth <= d => return Some("step"),
+                Mode::StepOut(d) if depth < d => return Some("step"),
+                _ => {},
+            }
+        }
+        None
+    }
+
+    fn send<W: Write>(&mut self, out: &mut W, mut msg: Vec<(&str, Value)>) -> io::Result<()> {
+        msg.insert(0, ("seq", Value::num(self.seq as f64)));
+        self.seq += 1;
+
+        let body = Value::object(msg).to_string();
+        write!(out, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+        out.flush()
+    }
+
+    fn event<W: Write>(&mut self, out: &mut W, event: &str, body: Value) -> io::Result<()> {
+        self.send(out, vec![("type", Value::str("event")), ("event", Value::str(event)), ("body", body)])
+    }
+
+    fn respond<W: Write>(&mut self, out: &mut W, req: &Value, success: bool, body: Value) -> io::Result<()> {
+        let mut msg = vec![
+            ("type", Value::str("response")),
+            ("request_seq", req.get("seq").cloned().unwrap_or(Value::num(0))),
+            ("success", Value::Bool(success)),
+            ("command", req.get("command").cloned().unwrap_or(Value::str(""))),
+            ("body", body),
+        ];
+        if !success {
+            msg.push(("message", Value::str("request failed")));
+        }
+        self.send(out, msg)
+    }
+
+    fn stopped<W: Write>(&mut self, out: &mut W, reason: &str) -> io::Result<()> {
+        self.mode = Mode::Paused;
+        self.event(out, "stopped", Value::object(vec![
+            ("reason", Value::str(reason)),
+            ("threadId", Value::num(1)),
+            ("allThreadsStopped", Value::Bool(true)),
+        ]))
+    }
+
+    /* program output, then why execution stopped if it did */
+    fn report<W: Write>(&mut self, out: &mut W, stop: Option<&str>) -> io::Result<()> {
+        self.flush_output(out)?;
+        match stop {
+            Some("exited") => {
+                self.mode = Mode::Paused;
+                self.event(out, "exited", Value::object(vec![("exitCode", Value::num(0))]))?;
+                self.event(out, "terminated", Value::object(vec![]))
+            },
+            Some(reason) => self.stopped(out, reason),
+            None => Ok(()),
+        }
+    }
+
+    fn flush_output<W: Write>(&mut self, out: &mut W) -> io::Result<()> {
+        let text = String::from_utf8_lossy(&std::mem::take(&mut *self.output.borrow_mut())).into_owned();
+        if text.is_empty() {
+            return Ok(());
+        }
+        self.event(out, "output", Value::object(vec![("category", Value::str("stdout")), ("output", Value::String(text))]))
+    }
+
+    fn launch(&mut self, cpu: &mut CPU, args: &Value) -> bool {
+        let Some(program) = args.get("program").and_then(|p| p.as_str()) else { return false };
+        let Ok(image) = std::fs::read(program) else { return false };
+
+        let sibling = |key: &str, ext: &str| {
+            args.get(key)
+                .and_then(|v| v.as_str())
+                .map(|s| s.to_string())
+                .unwrap_or_else(|| Path::new(program).with_extension(ext).to_string_lossy().into_owned())
+        };
+        let listing = sibling("listing", "lst");
+        let symbols = sibling("symbols", "sym");
+        let source = sibling("source", "asm");
+
+        if args.get("isa").and_then(|v| v.as_str()) == Some("lc3b") {
+            cpu.isa = Isa::Lc3b;
+        }
+        cpu.load_image(&image);
+        if let Ok(text) = std::fs::read_to_string(&listing) {
+            cpu.load_listing(&source, &text);
+        }
+        if let Ok(text) = std::fs::read_to_string(&symbols) {
+            cpu.load_symbols(&text);
+        }
+
+        cpu.running = true;
+        self.stop_on_entry = args.get("stopOnEntry").and_then(|v| v.as_bool()).unwrap_or(false);
+        true
+    }
+
+    fn source(&self, cpu: &CPU) -> Value {
+        let name = Path::new(&cpu.debug.source).file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+        Value::object(vec![("name", Value::String(name)), ("path", Value::str(&cpu.debug.source))])
+    }
+
+    fn frame(&self, cpu: &CPU, id: usize, addr: u16, name: String) -> Value {
+        let mut fields = vec![
+            ("id", Value::num(id as f64)),
+            ("name", Value::String(name)),
+            ("line", Value::num(0)),
+            ("column", Value::num(1)),
+            ("instructionPointerReference", Value::String(format!("x{:04X}", addr))),
+        ];
+        if let Some(line) = cpu.debug.line(addr) {
+            fields[2] = ("line", Value::num(line.line));
+            fields.push(("source", self.source(cpu)));
+        }
+        Value::object(fields)
+    }
+
+    fn stack_trace(&self, cpu: &CPU) -> Value {
+        let pc = cpu.pc as u16;
+        let mut frames = Vec::new();
+        let mut function = cpu.calls.frames.last().map(|f| f.target).unwrap_or(pc);
+        frames.push(self.frame(cpu, 0, pc, cpu.symbols.describe(function)));
+
+        for (i, call) in cpu.calls.frames.iter().rev().enumerate() {
+            let caller = cpu.calls.frames.len().checked_sub(i + 2).map(|j| cpu.calls.frames[j].target);
+            function = caller.unwrap_or_else(|| cpu.image.map(|(origin, _)| origin).unwrap_or(call.site));
+            frames.push(self.frame(cpu, i + 1, call.site, cpu.symbols.describe(function)));
+        }
+
+        let total = frames.len();
+        Value::object(vec![("stackFrames", Value::Array(frames)), ("totalFrames", Value::num(total as f64))])
+    }
+
+    fn variables(&self, cpu: &CPU) -> Value {
+        let var = |name: &str, val: u16| Value::object(vec![
+            ("name", Value::str(name)),
+            ("value", Value::String(format!("x{:04X} ({})", val, val as i16))),
+            ("variablesReference", Value::num(0)),
+            ("memoryReference", Value::String(format!("x{:04X}", val))),
+        ]);
+
+        let mut vars: Vec<Value> = cpu.registers().iter().enumerate()
+            .map(|(i, &val)| var(&format!("R{}", i), val))
+            .collect();
+        vars.push(var("PC", cpu.pc as u16));
+        vars.push(Value::object(vec![
+            ("name", Value::str("CC")),
+            ("value", Value::str(cond_name(cpu.rcond))),
+            ("variablesReference", Value::num(0)),
+        ]));
+        vars.push(var("PSR", cpu.psr()));
+
+        Value::object(vec![("variables", Value::Array(vars))])
+    }
+
+    fn set_variable(&self, cpu: &mut CPU, args: &Value) -> Option<Value> {
+        let name = args.get("name")?.as_str()?;
+        let val = parse_address(cpu, args.get("value")?.as_str()?)?;
+
+        match name {
+            "PC" => cpu.pc = val as usize,
+            "PSR" => cpu.set_psr(val),
+            _ => cpu.set_register(register_index(name)?, val),
+        }
+        Some(Value::object(vec![("value", Value::String(format!("x{:04X} ({})", val, val as i16)))]))
+    }
+
+    /* memory is presented as two little endian bytes per word, LC-3b memory is already bytes */
+    fn read_memory(&self, cpu: &CPU, args: &Value) -> Option<Value> {
+        let base = parse_address(cpu, args.get("memoryReference")?.as_str()?)?;
+        let offset = args.get("offset").and_then(|o| o.as_i64()).unwrap_or(0);
+        let count = args.get("count")?.as_i64()?.clamp(0, 1 << 17) as usize;
+
+        if cpu.isa == Isa::Lc3b {
+            let start = base.wrapping_add(offset as u16);
+            let bytes: Vec<u8> = (0..count).map(|i| cpu.memory.peek_byte(start.wrapping_add(i as u16) as usize)).collect();
+            return Some(Value::object(vec![
+                ("address", Value::String(format!("x{:04X}", start))),
+                ("data", Value::String(base64(&bytes))),
+            ]));
+        }
+
+        /* an odd offset would start half way through a word */
+        if offset % 2 != 0 {
+            return None;
+        }
+        let start = base.wrapping_add((offset / 2) as u16);
+        let bytes: Vec<u8> = (0..count.div_ceil(2))
+            .flat_map(|i| {
+                let word = cpu.memory.memory[start.wrapping_add(i as u16) as usize];
+                [word as u8, (word >> 8) as u8]
+            })
+            .take(count)
+            .collect();
+
+        Some(Value::object(vec![
+            ("address", Value::String(format!("x{:04X}", start))),
+            ("data", Value::String(base64(&bytes))),
+        ]))
+    }
+
+    fn evaluate(&self, cpu: &CPU, expr: &str) -> Option<String> {
+        if let Some(text) = expr.strip_prefix("input ") {
+            let mut keys = self.keys.borrow_mut();
+            keys.extend(text.bytes());
+            keys.push_back(b'\n');
+            return Some(format!("queued {} keys", text.len() + 1));
+        }
+        if let Some(r) = register_index(expr) {
+            let val = cpu.registers()[r as usize];
+            return Some(format!("x{:04X} ({})", val, val as i16));
+        }
+
+        let addr = parse_address(cpu, expr)?;
+        let val = cpu.memory.memory[addr as usize];
+        Some(format!("[x{:04X}] = x{:04X} ({})", addr, val, val as i16))
+    }
+
+    /* false once the client has disconnected */
+    fn handle<W: Write>(&mut self, cpu: &mut CPU, req: &Value, out: &mut W) -> io::Result<bool> {
+        let command = req.get("command").and_then(|c| c.as_str()).unwrap_or("");
+        let empty = Value::object(vec![]);
+        let args = req.get("arguments").unwrap_or(&empty);
+
+        match command {
+            "initialize" => {
+                self.respond(out, req, true, Value::object(vec![
+                    ("supportsConfigurationDoneRequest", Value::Bool(true)),
+                    ("supportsReadMemoryRequest", Value::Bool(true)),
+                    ("supportsSetVariable", Value::Bool(true)),
+                    ("supportsEvaluateForHovers", Value::Bool(true)),
+                ]))?;
+                self.event(out, "initialized", Value::object(vec![]))?;
+            },
+            "launch" => {
+                let ok = self.launch(cpu, args);
+                self.respond(out, req, ok, Value::object(vec![]))?;
+            },
+            "setBreakpoints" => {
+                self.breakpoints.clear();
+                let mut verified = Vec::new();
+                for bp in args.get("breakpoints").and_then(|b| b.as_array()).unwrap_or(&[]) {
+                    let line = bp.get("line").and_then(|l| l.as_i64()).unwrap_or(0);
+                    let found = breakpoint_address(cpu, line as u32);
+                    if let Some((addr, _)) = found {
+                        self.breakpoints.insert(addr);
+                    }
+                    verified.push(Value::object(vec![
+                        ("verified", Value::Bool(found.is_some())),
+                        ("line", Value::num(found.map_or(line as f64, |(_, at)| at as f64))),
+                    ]));
+                }
+                self.respond(out, req, true, Value::object(vec![("breakpoints", Value::Array(verified))]))?;
+            },
+            "setExceptionBreakpoints" => self.respond(out, req, true, Value::object(vec![]))?,
+            "configurationDone" => {
+                self.respond(out, req, true, Value::object(vec![]))?;
+                if self.stop_on_entry {
+                    self.stopped(out, "entry")?;
+                } else {
+                    self.mode = Mode::Continue;
+                    self.resuming = false;
+                }
+            },
+            "threads" => {
+                let thread = Value::object(vec![("id", Value::num(1)), ("name", Value::str("LC-3"))]);
+                self.respond(out, req, true, Value::object(vec![("threads", Value::Array(vec![thread]))]))?;
+            },
+            "stackTrace" => {
+                let body = self.stack_trace(cpu);
+                self.respond(out, req, true, body)?;
+            },
+            "scopes" => {
+                let scope = Value::object(vec![
+                    ("name", Value::str("Registers")),
+                    ("variablesReference", Value::num(REGISTERS_REF as f64)),
+                    ("expensive", Value::Bool(false)),
+                ]);
+                self.respond(out, req, true, Value::object(vec![("scopes", Value::Array(vec![scope]))]))?;
+            },
+            "variables" => {
+                let body = self.variables(cpu);
+                self.respond(out, req, true, body)?;
+            },
+            "setVariable" => match self.set_variable(cpu, args) {
+                Some(body) => self.respond(out, req, true, body)?,
+                None => self.respond(out, req, false, Value::object(vec![]))?,
+            },
+            "readMemory" => match self.read_memory(cpu, args) {
+                Some(body) => self.respond(out, req, true, body)?,
+                None => self.respond(out, req, false, Value::object(vec![]))?,
+            },
+            "evaluate" => {
+                let expr = args.get("expression").and_then(|e| e.as_str()).unwrap_or("");
+                match self.evaluate(cpu, expr) {
+                    Some(result) => self.respond(out, req, true, Value::object(vec![
+                        ("result", Value::String(result)),
+                        ("variablesReference", Value::num(0)),
+                    ]))?,
+                    None => self.respond(out, req, false, Value::object(vec![]))?,
+                }
+            },
+            "continue" => {
+                self.respond(out, req, true, Value::object(vec![("allThreadsContinued", Value::Bool(true))]))?;
+                self.mode = Mode::Continue;
+                self.resuming = true;
+            },
+            "next" => {
+                self.respond(out, req, true, Value::object(vec![]))?;
+                self.mode = Mode::StepOver(cpu.calls.depth());
+                self.resuming = true;
+            },
+            "stepIn" => {
+                self.respond(out, req, true, Value::object(vec![]))?;
+                cpu.step();
+                self.report(out, Some(if cpu.running { "step" } else { "exited" }))?;
+            },
+            "stepOut" => {
+                self.respond(out, req, true, Value::object(vec![]))?;
+                self.mode = Mode::StepOut(cpu.calls.depth());
+                self.resuming = true;
+            },
+            "pause" => {
+                self.respond(out, req, true, Value::object(vec![]))?;
+                self.stopped(out, "pause")?;
+            },
+            "disconnect" | "terminate" => {
+                self.respond(out, req, true, Value::object(vec![]))?;
+                return Ok(false);
+            },
+            _ => self.respond(out, req, false, Value::object(vec![]))?,
+        }
+
+        Ok(true)
+    }
+}
+
+impl Default for DapServer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LISTING: &str = "\
+(3000) 1021  0001000000100001 (   2)                 ADD R0, R0, #1
+(3001) 0001  0000000000000001 (   3) ONE             .FILL 1
+(3002) F025  1111000000100101 (   4)                 HALT
+";
+
+    fn program(words: &[u16]) -> CPU {
+        let mut cpu = CPU::new();
+        cpu.load_image(&std::iter::once(0x3000).chain(words.iter().copied()).flat_map(|w: u16| w.to_be_bytes()).collect());
+        cpu
+    }
+
+    /* every message the server sent in answer to one request */
+    fn request(server: &mut DapServer, cpu: &mut CPU, json: &str) -> Vec<Value> {
+        let mut out = Vec::new();
+        server.handle(cpu, &Value::parse(json).unwrap(), &mut out).unwrap();
+
+        let mut input = io::Cursor::new(out);
+        let mut messages = Vec::new();
+        while let Some(msg) = read_message(&mut input).unwrap() {
+            messages.push(msg);
+        }
+        messages
+    }
+
+    fn events(messages: &[Value]) -> Vec<&str> {
+        messages.iter().filter_map(|m| m.get("event").and_then(|e| e.as_str())).collect()
+    }
+
+    #[test]
+    fn messages_are_framed_by_content_length() {
+        let body = r#"{"seq":1,"type":"request","command":"threads"}"#;
+        let text = format!("Content-Length: {}\r\n\r\n{}", body.len(), body);
+        let msg = read_message(&mut io::Cursor::new(text)).unwrap().unwrap();
+        assert_eq!(msg.get("command").and_then(|c| c.as_str()), Some("threads"));
+        assert_eq!(base64(b"LC-3"), "TEMtMw==");
+    }
+
+    #[test]
+    fn step_into_halt_exits() {
+        let mut cpu = program(&[0x1021, 0xF025]);
+        let mut server = DapServer::new();
+
+        let step = r#"{"seq":1,"type":"request","command":"stepIn","arguments":{"threadId":1}}"#;
+        assert_eq!(events(&request(&mut server, &mut cpu, step)), vec!["stopped"]);
+        assert_eq!(events(&request(&mut server, &mut cpu, step)), vec!["exited", "terminated"]);
+    }
+
+    #[test]
+    fn breakpoints_on_data_move_to_the_next_instruction() {
+        let mut cpu = program(&[0x1021, 0x0001, 0xF025]);
+        cpu.load_listing("prog.asm", LISTING);
+        let mut server = DapServer::new();
+
+        let set = r#"{"seq":1,"type":"request","command":"setBreakpoints","arguments":{"breakpoints":[{"line":3},{"line":9}]}}"#;
+        let reply = request(&mut server, &mut cpu, set);
+        let bps = reply[0].get("body").and_then(|b| b.get("breakpoints")).and_then(|b| b.as_array()).unwrap();
+
+        assert_eq!(bps[0].get("verified").and_then(|v| v.as_bool()), Some(true));
+        assert_eq!(bps[0].get("line").and_then(|l| l.as_i64()), Some(4));
+        assert_eq!(bps[1].get("verified").and_then(|v| v.as_bool()), Some(false));
+        assert_eq!(server.breakpoints.iter().copied().collect::<Vec<_>>(), vec![0x3002]);
+    }
+
+    #[test]
+    fn continue_stops_at_a_breakpoint() {
+        let mut cpu = program(&[0x1021, 0x1021, 0xF025]);
+        let mut server = DapServer::new();
+        server.breakpoints.insert(0x3001);
+
+        request(&mut server, &mut cpu, r#"{"seq":1,"type":"request","command":"continue"}"#);
+        assert_eq!(server.run(&mut cpu), Some("breakpoint"));
+        assert_eq!(cpu.pc, 0x3001);
+    }
+
+    #[test]
+    fn variables_show_registers_pc_and_cc() {
+        let mut cpu = program(&[0x1021]);
+        cpu.step();
+        let mut server = DapServer::new();
+
+        let reply = request(&mut server, &mut cpu, r#"{"seq":1,"type":"request","command":"variables","arguments":{"variablesReference":1}}"#);
+        let vars = reply[0].get("body").and_then(|b| b.get("variables")).and_then(|v| v.as_array()).unwrap();
+        let value = |name: &str| vars.iter()
+            .find(|v| v.get("name").and_then(|n| n.as_str()) == Some(name))
+            .and_then(|v| v.get("value")).and_then(|v| v.as_str()).map(|s| s.to_string());
+
+        assert_eq!(value("R0").as_deref(), Some("x0001 (1)"));
+        assert_eq!(value("PC").as_deref(), Some("x3001 (12289)"));
+        assert_eq!(value("CC").as_deref(), Some("P"));
+    }
+
+    #[test]
+    fn read_memory_takes_whole_words() {
+        let mut cpu = program(&[0x1021, 0xF025]);
+        let mut server = DapServer::new();
+
+        let read = |offset| format!(r#"{{"seq":1,"type":"request","command":"readMemory","arguments":{{"memoryReference":"x3000","offset":{},"count":2}}}}"#, offset);
+        let reply = request(&mut server, &mut cpu, &read(2));
+        let body = reply[0].get("body").unwrap();
+        assert_eq!(body.get("address").and_then(|a| a.as_str()), Some("x3001"));
+        assert_eq!(body.get("data").and_then(|d| d.as_str()), Some(base64(&[0x25, 0xF0]).as_str()));
+
+        let reply = request(&mut server, &mut cpu, &read(1));
+        assert_eq!(reply[0].get("success").and_then(|s| s.as_bool()), Some(false));
+    }
+}
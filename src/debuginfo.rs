@@ -0,0 +1,149 @@
+use std::collections::BTreeMap;
+
+/* a source line that produced the word at some address */
+#[derive(Debug, Clone)]
+pub struct SourceLine {
+    pub line: u32,
+    pub text: String,
+}
+
+impl SourceLine {
+    /* true for lines assembled from .FILL, .BLKW and .STRINGZ */
+    pub fn is_data(&self) -> bool {
+        let code = self.code().to_uppercase();
+        code.split_whitespace().any(|w| w == ".FILL" || w == ".BLKW" || w == ".STRINGZ")
+    }
+
+    /* .ORIG and .END generate no words even though the listing shows them */
+    fn is_placement(&self) -> bool {
+        let code = self.code().to_uppercase();
+        code.split_whitespace().any(|w| w == ".ORIG" || w == ".END")
+    }
+
+    /* the line up to its comment; a ';' inside a string is not one */
+    fn code(&self) -> &str {
+        let mut quoted = false;
+        let mut escaped = false;
+        for (i, c) in self.text.char_indices() {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' if quoted => escaped = true,
+                '"' => quoted = !quoted,
+                ';' if !quoted => return &self.text[..i],
+                _ => {},
+            }
+        }
+        &self.text
+    }
+}
+
+/* address to source mapping taken from an assembler listing */
+pub struct DebugInfo {
+    pub source: String,
+    pub lines: BTreeMap<u16, SourceLine>,
+}
+
+impl DebugInfo {
+    pub const fn new() -> DebugInfo {
+        DebugInfo {
+            source: String::new(),
+            lines: BTreeMap::new(),
+        }
+    }
+
+    /*
+    lc3as style listing, one word per line:
+        (3000) 5020  0101000000100000 (   4)                 AND R0, R0, #0
+    words after the first of a .STRINGZ or .BLKW carry no line number and
+    belong to the last numbered line
+    */
+    pub fn parse(source: &str, text: &str) -> DebugInfo {
+        let mut info = DebugInfo::new();
+        info.source = source.to_string();
+        let mut last: Option<SourceLine> = None;
+
+        for raw in text.lines() {
+            let line = raw.trim_start();
+            if !line.starts_with('(') {
+                continue;
+            }
+            let Some(close) = line.find(')') else { continue };
+            let Ok(addr) = u16::from_str_radix(line[1..close].trim(), 16) else { continue };
+
+            let rest = &line[close + 1..];
+            let current = match (rest.find('('), rest.find(')')) {
+                (Some(open), Some(close)) if open < close => {
+                    match rest[open + 1..close].trim().parse::<u32>() {
+                        Ok(n) => Some(SourceLine { line: n, text: rest[close + 1..].trim().to_string() }),
+                        Err(_) => last.clone(),
+                    }
+                },
+                _ => last.clone(),
+            };
+
+            if let Some(current) = current {
+                if current.is_placement() {
+                    continue;
+                }
+                info.lines.insert(addr, current.clone());
+                last = Some(current);
+            }
+        }
+
+        info
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lines.is_empty()
+    }
+
+    pub fn line(&self, addr: u16) -> Option<&SourceLine> {
+        self.lines.get(&addr)
+    }
+
+    /* first address generated by a source line */
+    pub fn address(&self, line: u32) -> Option<u16> {
+        self.lines.iter().find(|(_, l)| l.line == line).map(|(&addr, _)| addr)
+    }
+}
+
+impl Default for DebugInfo {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn continuation_words_belong_to_their_line() {
+        let listing = "\
+(0000) 3000  0011000000000000 (   1)                 .ORIG x3000
+(3000) E002  1110000000000010 (   2)                 LEA R0, MSG
+(3001) F025  1111000000100101 (   3)                 HALT
+(3002) 0068  0000000001101000 (   4) MSG             .STRINGZ \"hi\"
+(3003) 0069  0000000001101001
+(3004) 0000  0000000000000000
+";
+        let info = DebugInfo::parse("hi.asm", listing);
+
+        assert_eq!(info.lines.len(), 5);
+        assert_eq!(info.line(0x3004).map(|l| l.line), Some(4));
+        assert!(info.line(0x3003).unwrap().is_data());
+        assert!(!info.line(0x3001).unwrap().is_data());
+        assert_eq!(info.address(3), Some(0x3001));
+        assert_eq!(info.address(1), None);
+    }
+
+    #[test]
+    fn semicolons_in_strings_are_not_comments() {
+        let line = |text: &str| SourceLine { line: 1, text: text.to_string() };
+        assert_eq!(line("MSG .STRINGZ \"a;b\" ; greeting").code(), "MSG .STRINGZ \"a;b\" ");
+        assert_eq!(line(".STRINGZ \"\\\";\"").code(), ".STRINGZ \"\\\";\"");
+        assert!(line(".STRINGZ \";.END\"").is_data());
+        assert!(!line(".STRINGZ \";.END\"").is_placement());
+        assert!(!line("HALT ; .FILL").is_data());
+    }
+}
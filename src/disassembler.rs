@@ -1,4 +1,4 @@
-use crate::{instructions::{OPCodes, TrapCodes}, io::printstr};
+use crate::{instructions::{Isa, Lc3bOPCodes, OPCodes, TrapCodes}, io::printstr};
 
 fn get_reg(r: u16) -> &'static str {
     match r {
@@ -26,24 +26,136 @@ fn sign_extend(x: u16, bit_count: u16) -> u16 {
     return x;
 }
 
+/* the fields of an instruction, with its immediate or offset already sign extended */
+#[derive(Debug, Clone, Copy)]
+pub struct Decoded {
+    pub op: OPCodes,
+    /* bits 11:9, DR / SR / nzp */
+    pub r0: u16,
+    /* bits 8:6, SR1 / BaseR */
+    pub r1: u16,
+    /* bits 2:0, SR2 */
+    pub r2: u16,
+    /* bit 5 for ADD/AND, bit 11 for JSR */
+    pub flag: bool,
+    /* imm5, offset6, PCoffset9, PCoffset11 or trapvect8 depending on op */
+    pub imm: u16,
+}
+
+pub fn decode(inst: u16) -> Decoded {
+    let op = OPCodes::from(inst >> 12);
+
+    let (flag, imm) = match op {
+        OPCodes::OpAdd | OPCodes::OpAnd => ((inst >> 5) & 1 != 0, sign_extend(inst & 0x1F, 5)),
+        OPCodes::OpLdr | OPCodes::OpStr => (false, sign_extend(inst & 0x3F, 6)),
+        OPCodes::OpJsr => ((inst >> 11) & 1 != 0, sign_extend(inst & 0x7FF, 11)),
+        OPCodes::OpTrap => (false, inst & 0xFF),
+        _ => (false, sign_extend(inst & 0x1FF, 9)),
+    };
+
+    Decoded {
+        op,
+        r0: (inst >> 9) & 0x7,
+        r1: (inst >> 6) & 0x7,
+        r2: inst & 0x7,
+        flag,
+        imm,
+    }
+}
+
+/* LC-3b fields; word offsets are left unscaled, the CPU shifts them */
+#[derive(Debug, Clone, Copy)]
+pub struct Lc3bDecoded {
+    pub op: Lc3bOPCodes,
+    /* bits 11:9, DR / SR / nzp */
+    pub r0: u16,
+    /* bits 8:6, SR1 / BaseR */
+    pub r1: u16,
+    /* bits 2:0 SR2, or bits 5:4 of SHF */
+    pub r2: u16,
+    /* bit 5 for ADD/AND/XOR, bit 11 for JSR */
+    pub flag: bool,
+    /* imm5, boffset6, offset6, PCoffset9, PCoffset11, amount4 or trapvect8 depending on op */
+    pub imm: u16,
+}
+
+pub fn decode_lc3b(inst: u16) -> Lc3bDecoded {
+    let op = Lc3bOPCodes::from(inst >> 12);
+
+    let (flag, imm) = match op {
+        Lc3bOPCodes::OpAdd | Lc3bOPCodes::OpAnd | Lc3bOPCodes::OpXor => ((inst >> 5) & 1 != 0, sign_extend(inst & 0x1F, 5)),
+        Lc3bOPCodes::OpLdb | Lc3bOPCodes::OpStb | Lc3bOPCodes::OpLdw | Lc3bOPCodes::OpStw => (false, sign_extend(inst & 0x3F, 6)),
+        Lc3bOPCodes::OpJsr => ((inst >> 11) & 1 != 0, sign_extend(inst & 0x7FF, 11)),
+        Lc3bOPCodes::OpShf => (false, inst & 0xF),
+        Lc3bOPCodes::OpTrap => (false, inst & 0xFF),
+        _ => (false, sign_extend(inst & 0x1FF, 9)),
+    };
+
+    Lc3bDecoded {
+        op,
+        r0: (inst >> 9) & 0x7,
+        r1: (inst >> 6) & 0x7,
+        r2: if op == Lc3bOPCodes::OpShf { (inst >> 4) & 0x3 } else { inst & 0x7 },
+        flag,
+        imm,
+    }
+}
+
+pub fn disassembly_for(isa: Isa, inst: u16) -> String {
+    match isa {
+        Isa::Lc3 => disassembly(inst),
+        Isa::Lc3b => disassembly_lc3b(inst),
+    }
+}
+
+pub fn disassembly_lc3b(inst: u16) -> String {
+    let d = decode_lc3b(inst);
+    let r0 = get_reg(d.r0);
+    let r1 = get_reg(d.r1);
+
+    match d.op {
+        /* the shared encodings print the same either way */
+        Lc3bOPCodes::OpBr | Lc3bOPCodes::OpJsr | Lc3bOPCodes::OpJmp | Lc3bOPCodes::OpTrap | Lc3bOPCodes::OpRti => disassembly(inst),
+        Lc3bOPCodes::OpAdd | Lc3bOPCodes::OpAnd | Lc3bOPCodes::OpXor => {
+            if d.flag {
+                format!("{:?} : {}  {} #{}", d.op, r0, r1, d.imm as i16)
+            } else {
+                format!("{:?} : {}  {} {}", d.op, r0, r1, get_reg(d.r2))
+            }
+        },
+        Lc3bOPCodes::OpLdb | Lc3bOPCodes::OpStb | Lc3bOPCodes::OpLdw | Lc3bOPCodes::OpStw => {
+            format!("{:?} : {}  {} #{}", d.op, r0, r1, d.imm as i16)
+        },
+        Lc3bOPCodes::OpShf => {
+            let kind = match d.r2 {
+                1 => "RSHFL",
+                3 => "RSHFA",
+                _ => "LSHF",
+            };
+            format!("{} : {}  {} #{}", kind, r0, r1, d.imm)
+        },
+        Lc3bOPCodes::OpLea => format!("{:?} : {} 0x{:x}", d.op, r0, d.imm),
+        Lc3bOPCodes::OpRes => format!("{:?}", d.op),
+    }
+}
+
 pub fn disassemble(inst: u16) {
+    printstr(disassembly(inst));
+}
+
+pub fn disassembly(inst: u16) -> String {
     let op = inst >> 12;
 
     match OPCodes::from(op) {
-        OPCodes::OpTrap => {
-            match TrapCodes::from(inst & 0xFF) {
-                TrapCodes::TrapGetC => printstr(format!("{:?}", TrapCodes::TrapGetC)),
-                TrapCodes::TrapOut => printstr(format!("{:?}", TrapCodes::TrapOut)),
-                TrapCodes::TrapPuts => printstr(format!("{:?}", TrapCodes::TrapPuts)),
-                TrapCodes::TrapIn => printstr(format!("{:?}", TrapCodes::TrapIn)),
-                TrapCodes::TrapPutsP => printstr(format!("{:?}", TrapCodes::TrapPutsP)),
-                TrapCodes::TrapHalt => printstr(format!("{:?}", TrapCodes::TrapHalt)),
-            }
+        OPCodes::OpTrap => match TrapCodes::from(inst & 0xFF) {
+            Some(code) => format!("{:?}", code),
+            /* any word can end up here from a memory view, not just the known traps */
+            None => format!("TRAP x{:02X}", inst & 0xFF),
         },
         OPCodes::OpBr => {
             let offset = sign_extend(inst & 0x1ff, 9);
             
-            printstr(format!("{:?}  : 0x{:x}", OPCodes::from(op), offset));
+            format!("{:?}  : 0x{:x}", OPCodes::from(op), offset)
         },
         OPCodes::OpAdd | OPCodes::OpAnd => {
             /* destination register (DR) */
@@ -53,23 +165,23 @@ pub fn disassemble(inst: u16) {
             /* whether we are in immediate mode */
             let imm_flag = ((inst >> 5) & 0x1) as u8;
 
-            printstr(format!("{:?} : {}  {} #0x{:x}", OPCodes::from(op), r0, r1, imm_flag));
+            format!("{:?} : {}  {} #0x{:x}", OPCodes::from(op), r0, r1, imm_flag)
         },
         OPCodes::OpLd | OPCodes::OpSt => {
             let r0 = get_reg((inst >> 9) & 0x7);
             let pc_offset = sign_extend(inst & 0x1FF, 9);
 
-            printstr(format!("{:?}  : {} 0x{:x}", OPCodes::from(op), r0, pc_offset));
+            format!("{:?}  : {} 0x{:x}", OPCodes::from(op), r0, pc_offset)
         },
         OPCodes::OpJsr => {
             let long_flag = (inst >> 11) & 1;
 
             if (long_flag != 0) {
                 let long_pc_offset = sign_extend(inst & 0x7FF, 11);
-                printstr(format!("{:?} : 0x{:x}", OPCodes::from(op), long_pc_offset));
+                format!("{:?} : 0x{:x}", OPCodes::from(op), long_pc_offset)
             } else {
                 let r1 = get_reg((inst >> 6) & 0x7);
-                printstr(format!("{:?} : {}", OPCodes::from(op), r1));
+                format!("{:?} : {}", OPCodes::from(op), r1)
             }
         },
         OPCodes::OpLdr | OPCodes::OpStr => {
@@ -77,13 +189,13 @@ pub fn disassemble(inst: u16) {
             let r1 = get_reg((inst >> 6) & 0x7);
             let offset = sign_extend(inst & 0x3F, 6);
             
-            printstr(format!("{:?} : {}  {} 0x{:x}", OPCodes::from(op), r0, r1, offset));
+            format!("{:?} : {}  {} 0x{:x}", OPCodes::from(op), r0, r1, offset)
         },
         OPCodes::OpNot => {
             let r0 = get_reg((inst >> 9) & 0x7);
             let r1 = get_reg((inst >> 6) & 0x7);
 
-            printstr(format!("{:?} : {}  {}", OPCodes::from(op), r0, r1));
+            format!("{:?} : {}  {}", OPCodes::from(op), r0, r1)
         },
         OPCodes::OpLdi | OPCodes::OpSti => {
             /* destination register (DR) */
@@ -92,19 +204,45 @@ pub fn disassemble(inst: u16) {
             /* PCoffset 9*/
             let pc_offset = sign_extend(inst & 0x1FF, 9);
             
-            printstr(format!("{:?} : {} 0x{:x}", OPCodes::from(op), r0, pc_offset));
+            format!("{:?} : {} 0x{:x}", OPCodes::from(op), r0, pc_offset)
         },
         OPCodes::OpJmp => {
             let r = get_reg((inst >> 6) & 0x7);
 
-            printstr(format!("{:?} : {}", OPCodes::from(op), r));
+            format!("{:?} : {}", OPCodes::from(op), r)
         },
         OPCodes::OpLea => {
             let r0 = get_reg((inst >> 9) & 0x7);
             let pc_offset = sign_extend(inst & 0x1FF, 9);
 
-            printstr(format!("{:?} : {} 0x{:x}", OPCodes::from(op), r0, pc_offset));
+            format!("{:?} : {} 0x{:x}", OPCodes::from(op), r0, pc_offset)
         },
-        _ => printstr(format!("{:?}", OPCodes::from(op)))
+        _ => format!("{:?}", OPCodes::from(op))
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unknown_trap_vectors_show_the_vector() {
+        assert_eq!(disassembly(0xF040), "TRAP x40");
+        assert_eq!(disassembly(0xF0FF), "TRAP xFF");
+        assert_eq!(disassembly(0xF000), "TRAP x00");
+    }
+
+    #[test]
+    fn known_traps_keep_their_names() {
+        assert_eq!(disassembly(0xF025), format!("{:?}", TrapCodes::TrapHalt));
+        assert_eq!(disassembly(0xF020), format!("{:?}", TrapCodes::TrapGetC));
+    }
+
+    #[test]
+    fn every_word_disassembles() {
+        for isa in [Isa::Lc3, Isa::Lc3b] {
+            for word in 0..=0xFFFF {
+                assert!(!disassembly_for(isa, word).is_empty());
+            }
+        }
+    }
+}
@@ -0,0 +1,116 @@
+use crate::selfmod::CodeEvent;
+
+/* things a frontend or tool can subscribe to instead of being pushed state every step */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Event {
+    /* an instruction retired, only sent to subscribers that asked for steps */
+    Step { pc: u16, inst: u16 },
+    Halt,
+    /* a new problem found by the code/data check */
+    Code(CodeEvent),
+}
+
+struct Subscriber {
+    id: usize,
+    steps: bool,
+    callback: Box<dyn FnMut(&Event)>,
+}
+
+pub struct Observers {
+    subscribers: Vec<Subscriber>,
+    next_id: usize,
+    /* true while any subscriber wants Step events, checked on the hot path */
+    pub steps: bool,
+    /* a HALT that is reported once its own Step has gone out */
+    pub(crate) halted: bool,
+}
+
+impl Observers {
+    pub const fn new() -> Observers {
+        Observers {
+            subscribers: Vec::new(),
+            next_id: 0,
+            steps: false,
+            halted: false,
+        }
+    }
+
+    /* returns an id for unsubscribe; `steps` opts into a callback per instruction */
+    pub fn subscribe(&mut self, steps: bool, callback: Box<dyn FnMut(&Event)>) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.subscribers.push(Subscriber { id, steps, callback });
+        self.steps |= steps;
+        id
+    }
+
+    pub fn unsubscribe(&mut self, id: usize) {
+        self.subscribers.retain(|s| s.id != id);
+        self.steps = self.subscribers.iter().any(|s| s.steps);
+    }
+
+    pub fn emit(&mut self, event: Event) {
+        let is_step = matches!(event, Event::Step { .. });
+        for s in self.subscribers.iter_mut() {
+            if s.steps || !is_step {
+                (s.callback)(&event);
+            }
+        }
+    }
+}
+
+impl Default for Observers {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::CPU;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn recorder(log: &Rc<RefCell<Vec<Event>>>) -> Box<dyn FnMut(&Event)> {
+        let log = log.clone();
+        Box::new(move |event| log.borrow_mut().push(*event))
+    }
+
+    #[test]
+    fn only_step_subscribers_see_steps() {
+        let mut cpu = CPU::new();
+        /* ADD R0, R0, #1; HALT */
+        cpu.load_image(&vec![0x30, 0x00, 0x10, 0x21, 0xF0, 0x25]);
+        let steps = Rc::new(RefCell::new(Vec::new()));
+        let halts = Rc::new(RefCell::new(Vec::new()));
+        cpu.subscribe(true, recorder(&steps));
+        cpu.subscribe(false, recorder(&halts));
+
+        cpu.run();
+        assert_eq!(*steps.borrow(), vec![
+            Event::Step { pc: 0x3000, inst: 0x1021 },
+            Event::Step { pc: 0x3001, inst: 0xF025 },
+            Event::Halt,
+        ]);
+        assert_eq!(*halts.borrow(), vec![Event::Halt]);
+    }
+
+    #[test]
+    fn unsubscribing_the_last_step_subscriber_clears_steps() {
+        let mut observers = Observers::new();
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let a = observers.subscribe(true, recorder(&log));
+        let b = observers.subscribe(false, recorder(&log));
+        assert!(observers.steps);
+
+        observers.unsubscribe(a);
+        assert!(!observers.steps);
+        observers.emit(Event::Step { pc: 0, inst: 0 });
+        assert!(log.borrow().is_empty());
+
+        observers.unsubscribe(b);
+        observers.emit(Event::Halt);
+        assert!(log.borrow().is_empty());
+    }
+}
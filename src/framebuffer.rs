@@ -0,0 +1,250 @@
+use std::cell::RefCell;
+use std::io::{self, Write};
+use std::rc::Rc;
+
+use crate::memory::Device;
+
+/* video memory, one word per pixel, row major */
+pub const VIDEO_START: usize = 0xC000;
+pub const WIDTH: usize = 128;
+pub const HEIGHT: usize = 124;
+const VIDEO_END: usize = VIDEO_START + WIDTH * HEIGHT;
+
+/* pixels touched since the host last redrew, inclusive bounds */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rect {
+    pub x0: usize,
+    pub y0: usize,
+    pub x1: usize,
+    pub y1: usize,
+}
+
+impl Rect {
+    pub fn width(&self) -> usize {
+        self.x1 - self.x0 + 1
+    }
+
+    pub fn height(&self) -> usize {
+        self.y1 - self.y0 + 1
+    }
+}
+
+/* 15 bit colour, red in 14:10, green in 9:5, blue in 4:0 */
+pub fn rgb(pixel: u16) -> [u8; 3] {
+    let expand = |c: u16| ((c << 3) | (c >> 2)) as u8;
+    [expand((pixel >> 10) & 0x1F), expand((pixel >> 5) & 0x1F), expand(pixel & 0x1F)]
+}
+
+/* what the host sees of the display */
+pub struct Screen {
+    pub pixels: Vec<u16>,
+    dirty: Option<Rect>,
+    /* the pixels as RGBA, and the rows of it that are out of date */
+    rgba: Vec<u8>,
+    stale: Option<(usize, usize)>,
+}
+
+impl Screen {
+    fn new() -> Screen {
+        Screen {
+            pixels: vec![0; WIDTH * HEIGHT],
+            dirty: None,
+            rgba: [0, 0, 0, 0xFF].repeat(WIDTH * HEIGHT),
+            stale: None,
+        }
+    }
+
+    fn mark(&mut self, x: usize, y: usize) {
+        self.dirty = Some(match self.dirty {
+            Some(r) => Rect { x0: r.x0.min(x), y0: r.y0.min(y), x1: r.x1.max(x), y1: r.y1.max(y) },
+            None => Rect { x0: x, y0: y, x1: x, y1: y },
+        });
+        self.stale = Some(match self.stale {
+            Some((first, last)) => (first.min(y), last.max(y)),
+            None => (y, y),
+        });
+    }
+
+    /* the region to redraw, cleared by taking it */
+    pub fn take_dirty(&mut self) -> Option<Rect> {
+        self.dirty.take()
+    }
+
+    /* RGBA, 4 bytes a pixel, ready for canvas ImageData; only rows changed since the last call are converted */
+    pub fn rgba(&mut self) -> &[u8] {
+        if let Some((first, last)) = self.stale.take() {
+            let rows = first * WIDTH..(last + 1) * WIDTH;
+            for (out, &p) in self.rgba[rows.start * 4..rows.end * 4].chunks_exact_mut(4).zip(self.pixels[rows].iter()) {
+                out[..3].copy_from_slice(&rgb(p));
+            }
+        }
+        &self.rgba
+    }
+
+    pub fn write_ppm<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        write!(out, "P6\n{} {}\n255\n", WIDTH, HEIGHT)?;
+        for &p in self.pixels.iter() {
+            out.write_all(&rgb(p))?;
+        }
+        Ok(())
+    }
+
+    /* RGB, 8 bits a channel, with the image data in stored (uncompressed) deflate blocks */
+    pub fn write_png<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let mut raw = Vec::with_capacity(HEIGHT * (1 + WIDTH * 3));
+        for row in self.pixels.chunks(WIDTH) {
+            raw.push(0);
+            for &p in row {
+                raw.extend_from_slice(&rgb(p));
+            }
+        }
+
+        let mut zlib = vec![0x78, 0x01];
+        let blocks: Vec<&[u8]> = raw.chunks(0xFFFF).collect();
+        for (i, block) in blocks.iter().enumerate() {
+            let len = block.len() as u16;
+            zlib.push((i + 1 == blocks.len()) as u8);
+            zlib.extend_from_slice(&len.to_le_bytes());
+            zlib.extend_from_slice(&(!len).to_le_bytes());
+            zlib.extend_from_slice(block);
+        }
+        zlib.extend_from_slice(&adler32(&raw).to_be_bytes());
+
+        let mut header = Vec::new();
+        header.extend_from_slice(&(WIDTH as u32).to_be_bytes());
+        header.extend_from_slice(&(HEIGHT as u32).to_be_bytes());
+        header.extend_from_slice(&[8, 2, 0, 0, 0]);
+
+        out.write_all(b"\x89PNG\r\n\x1a\n")?;
+        png_chunk(out, b"IHDR", &header)?;
+        png_chunk(out, b"IDAT", &zlib)?;
+        png_chunk(out, b"IEND", &[])
+    }
+}
+
+fn png_chunk<W: Write>(out: &mut W, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    out.write_all(&(data.len() as u32).to_be_bytes())?;
+    out.write_all(kind)?;
+    out.write_all(data)?;
+    let crc = crc32(&[&kind[..], data].concat());
+    out.write_all(&crc.to_be_bytes())
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for &b in data {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in data {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
+
+/*
+the 128x124 display over xC000-xFDFF; the CPU keeps using ordinary memory
+for the region and this device watches the stores, the host reads the
+shared Screen
+*/
+pub struct Framebuffer {
+    screen: Rc<RefCell<Screen>>,
+}
+
+impl Framebuffer {
+    pub fn new() -> Framebuffer {
+        Framebuffer {
+            screen: Rc::new(RefCell::new(Screen::new())),
+        }
+    }
+
+    pub fn screen(&self) -> Rc<RefCell<Screen>> {
+        self.screen.clone()
+    }
+}
+
+impl Default for Framebuffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Device for Framebuffer {
+    fn start(&self) -> usize {
+        VIDEO_START
+    }
+
+    fn claims(&self, addr: usize) -> bool {
+        (VIDEO_START..VIDEO_END).contains(&addr)
+    }
+
+    fn clocked(&self) -> bool {
+        false
+    }
+
+    fn read(&mut self, addr: usize) -> u16 {
+        self.screen.borrow().pixels[addr - VIDEO_START]
+    }
+
+    fn write(&mut self, addr: usize, val: u16) {
+        let mut screen = self.screen.borrow_mut();
+        let i = addr - VIDEO_START;
+        if screen.pixels[i] != val {
+            screen.pixels[i] = val;
+            screen.mark(i % WIDTH, i / WIDTH);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::CPU;
+
+    #[test]
+    fn stores_reach_the_screen_and_its_rgba() {
+        let display = Framebuffer::new();
+        let screen = display.screen();
+        let mut cpu = CPU::new();
+        cpu.memory.attach(Box::new(display));
+
+        assert_eq!(screen.borrow_mut().rgba()[..4], [0, 0, 0, 0xFF]);
+        cpu.memory.write(VIDEO_START + WIDTH + 2, 0x7C00);
+        assert_eq!(screen.borrow_mut().take_dirty(), Some(Rect { x0: 2, y0: 1, x1: 2, y1: 1 }));
+        let at = (WIDTH + 2) * 4;
+        assert_eq!(screen.borrow_mut().rgba()[at..at + 4], [0xFF, 0, 0, 0xFF]);
+
+        /* a second store to the same row after the conversion is picked up too */
+        cpu.memory.write(VIDEO_START + WIDTH + 3, 0x001F);
+        assert_eq!(screen.borrow_mut().rgba()[at + 4..at + 8], [0, 0, 0xFF, 0xFF]);
+    }
+
+    #[test]
+    fn loaded_images_reach_the_screen() {
+        let display = Framebuffer::new();
+        let screen = display.screen();
+        let mut cpu = CPU::new();
+        cpu.memory.attach(Box::new(display));
+
+        cpu.load_image(&vec![0xC0, 0x00, 0x03, 0xE0]);
+        assert_eq!(screen.borrow().pixels[0], 0x03E0);
+        assert_eq!(screen.borrow_mut().take_dirty(), Some(Rect { x0: 0, y0: 0, x1: 0, y1: 0 }));
+        assert_eq!(screen.borrow_mut().rgba()[..4], [0, 0xFF, 0, 0xFF]);
+    }
+
+    #[test]
+    fn png_has_the_right_header() {
+        let mut png = Vec::new();
+        Framebuffer::new().screen().borrow().write_png(&mut png).unwrap();
+        assert_eq!(png[..8], *b"\x89PNG\r\n\x1a\n");
+        assert_eq!(png[16..24], [0, 0, 0, WIDTH as u8, 0, 0, 0, HEIGHT as u8]);
+    }
+}
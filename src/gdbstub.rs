@@ -0,0 +1,629 @@
+use std::collections::BTreeSet;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+use crate::cpu::CPU;
+use crate::instructions::Isa;
+use crate::memory::Access;
+
+/*
+GDB remote serial protocol over TCP on localhost.
+
+Registers are r0-r7, pc and psr, 16 bits each, sent little endian.
+LC-3 memory is word addressed, so GDB addresses are word addresses and
+every word takes two bytes (little endian) in m/M packets; `m 3000,4`
+returns the words at x3000 and x3001. An LC-3b target is byte addressed,
+so there GDB addresses are plain byte addresses.
+*/
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.lc3.core">
+    <reg name="r0" bitsize="16" type="int16" regnum="0"/>
+    <reg name="r1" bitsize="16" type="int16"/>
+    <reg name="r2" bitsize="16" type="int16"/>
+    <reg name="r3" bitsize="16" type="int16"/>
+    <reg name="r4" bitsize="16" type="int16"/>
+    <reg name="r5" bitsize="16" type="int16"/>
+    <reg name="r6" bitsize="16" type="data_ptr"/>
+    <reg name="r7" bitsize="16" type="code_ptr"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+    <reg name="psr" bitsize="16" type="int16"/>
+  </feature>
+</target>
+"#;
+
+const NUM_REGS: usize = 10;
+const REG_PC: usize = 8;
+const REG_PSR: usize = 9;
+
+/* how often a running target checks the socket for ^C */
+const POLL_INTERVAL: u64 = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WatchKind {
+    Write,
+    Read,
+    Access,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Watchpoint {
+    pub kind: WatchKind,
+    pub addr: u16,
+    /* in words, or bytes on an LC-3b */
+    pub len: u16,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Stop {
+    Step,
+    Breakpoint,
+    Watch(WatchKind, u16),
+    Halted,
+    Interrupted,
+}
+
+struct Connection {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+    ack: bool,
+}
+
+impl Connection {
+    fn new(stream: TcpStream) -> io::Result<Connection> {
+        stream.set_nodelay(true)?;
+        Ok(Connection {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: stream,
+            ack: true,
+        })
+    }
+
+    fn byte(&mut self) -> io::Result<u8> {
+        let mut b = [0u8];
+        self.reader.read_exact(&mut b)?;
+        Ok(b[0])
+    }
+
+    /* next packet body; None for a ^C received while idle */
+    fn read_packet(&mut self) -> io::Result<Option<String>> {
+        loop {
+            match self.byte()? {
+                b'$' => break,
+                0x03 => return Ok(None),
+                _ => continue,
+            }
+        }
+
+        let mut body = Vec::new();
+        loop {
+            match self.byte()? {
+                b'#' => break,
+                b => body.push(b),
+            }
+        }
+        let hi = self.byte()?;
+        let lo = self.byte()?;
+
+        let sum = body.iter().fold(0u8, |acc, &b| acc.wrapping_add(b));
+        let sent = u8::from_str_radix(&String::from_utf8_lossy(&[hi, lo]), 16).unwrap_or(!sum);
+
+        if self.ack {
+            self.writer.write_all(if sum == sent { b"+" } else { b"-" })?;
+        }
+        Ok(Some(String::from_utf8_lossy(&body).into_owned()))
+    }
+
+    fn send(&mut self, body: &str) -> io::Result<()> {
+        let sum = body.bytes().fold(0u8, |acc, b| acc.wrapping_add(b));
+        write!(self.writer, "${}#{:02x}", body, sum)?;
+        self.writer.flush()
+    }
+
+    /* true, having consumed it, when GDB sent ^C while the target was running */
+    fn interrupted(&mut self) -> io::Result<bool> {
+        if !self.reader.buffer().is_empty() {
+            let Some(at) = self.reader.buffer().iter().position(|&b| b == 0x03) else { return Ok(false) };
+            self.reader.consume(at + 1);
+            return Ok(true);
+        }
+
+        self.writer.set_nonblocking(true)?;
+        let mut b = [0u8];
+        let got = match self.writer.peek(&mut b) {
+            Ok(1) => b[0] == 0x03,
+            Ok(_) => false,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => false,
+            Err(e) => return Err(e),
+        };
+        self.writer.set_nonblocking(false)?;
+
+        if got {
+            self.byte()?;
+        }
+        Ok(got)
+    }
+}
+
+pub struct GdbStub {
+    pub breakpoints: BTreeSet<u16>,
+    pub watchpoints: Vec<Watchpoint>,
+}
+
+fn hex_u16(s: &str) -> Option<u16> {
+    u16::from_str_radix(s, 16).ok()
+}
+
+fn encode_le(val: u16) -> String {
+    format!("{:02x}{:02x}", val & 0xFF, val >> 8)
+}
+
+fn decode_le(s: &str) -> Option<u16> {
+    if s.len() != 4 {
+        return None;
+    }
+    let lo = u16::from_str_radix(&s[0..2], 16).ok()?;
+    let hi = u16::from_str_radix(&s[2..4], 16).ok()?;
+    Some(lo | (hi << 8))
+}
+
+impl GdbStub {
+    pub fn new() -> GdbStub {
+        GdbStub {
+            breakpoints: BTreeSet::new(),
+            watchpoints: Vec::new(),
+        }
+    }
+
+    /* waits for one GDB connection on 127.0.0.1:port and serves it until it detaches */
+    pub fn listen(&mut self, cpu: &mut CPU, port: u16) -> io::Result<()> {
+        let listener = TcpListener::bind(("127.0.0.1", port))?;
+        eprintln!("Waiting for gdb on 127.0.0.1:{}", port);
+
+        let (stream, _) = listener.accept()?;
+        self.serve(cpu, stream)
+    }
+
+    pub fn serve(&mut self, cpu: &mut CPU, stream: TcpStream) -> io::Result<()> {
+        let mut conn = Connection::new(stream)?;
+
+        loop {
+            let packet = match conn.read_packet() {
+                Ok(Some(packet)) => packet,
+                Ok(None) => {
+                    conn.send("S02")?;
+                    continue;
+                },
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+                Err(e) => return Err(e),
+            };
+
+            match self.handle(cpu, &mut conn, &packet)? {
+                Some(reply) => conn.send(&reply)?,
+                None => return Ok(()),
+            }
+        }
+    }
+
+    fn read_register(cpu: &CPU, n: usize) -> u16 {
+        match n {
+            0..=7 => cpu.registers()[n],
+            REG_PC => cpu.pc as u16,
+            REG_PSR => cpu.psr(),
+            _ => 0,
+        }
+    }
+
+    fn write_register(cpu: &mut CPU, n: usize, val: u16) {
+        match n {
+            0..=7 => cpu.set_register(n as u16, val),
+            REG_PC => cpu.pc = val as usize,
+            REG_PSR => cpu.set_psr(val),
+            _ => {}
+        }
+    }
+
+    /* the reply to send, or None once GDB has detached or killed the target */
+    fn handle(&mut self, cpu: &mut CPU, conn: &mut Connection, packet: &str) -> io::Result<Option<String>> {
+        let (cmd, args) = packet.split_at(packet.len().min(1));
+
+        let reply = match cmd {
+            "?" => "S05".to_string(),
+            "g" => (0..NUM_REGS).map(|n| encode_le(Self::read_register(cpu, n))).collect(),
+            "G" => {
+                for n in 0..NUM_REGS {
+                    if let Some(val) = args.get(n * 4..n * 4 + 4).and_then(decode_le) {
+                        Self::write_register(cpu, n, val);
+                    }
+                }
+                "OK".to_string()
+            },
+            "p" => match usize::from_str_radix(args, 16) {
+                Ok(n) if n < NUM_REGS => encode_le(Self::read_register(cpu, n)),
+                _ => "E00".to_string(),
+            },
+            "P" => match args.split_once('=') {
+                Some((n, val)) => match (usize::from_str_radix(n, 16), decode_le(val)) {
+                    (Ok(n), Some(val)) if n < NUM_REGS => {
+                        Self::write_register(cpu, n, val);
+                        "OK".to_string()
+                    },
+                    _ => "E00".to_string(),
+                },
+                None => "E00".to_string(),
+            },
+            "m" => match args.split_once(',') {
+                Some((addr, len)) => match (hex_u16(addr), usize::from_str_radix(len, 16)) {
+                    (Some(addr), Ok(len)) if cpu.isa == Isa::Lc3b => (0..len)
+                        .map(|i| format!("{:02x}", cpu.memory.peek_byte(addr.wrapping_add(i as u16) as usize)))
+                        .collect(),
+                    (Some(addr), Ok(len)) => (0..len.div_ceil(2))
+                        .map(|i| encode_le(cpu.memory.memory[addr.wrapping_add(i as u16) as usize]))
+                        .collect::<String>()[..len * 2]
+                        .to_string(),
+                    _ => "E00".to_string(),
+                },
+                None => "E00".to_string(),
+            },
+            "M" => self.write_memory(cpu, args),
+            "s" => {
+                if let Some(addr) = hex_u16(args) {
+                    cpu.pc = addr as usize;
+                }
+                let stop = self.resume(cpu, conn, true)?;
+                self.stop_reply(stop)
+            },
+            "c" => {
+                if let Some(addr) = hex_u16(args) {
+                    cpu.pc = addr as usize;
+                }
+                let stop = self.resume(cpu, conn, false)?;
+                self.stop_reply(stop)
+            },
+            "Z" | "z" => self.breakpoint(cmd == "Z", args, cpu.isa),
+            "H" => "OK".to_string(),
+            "T" => "OK".to_string(),
+            "D" => {
+                conn.send("OK")?;
+                return Ok(None);
+            },
+            "k" => return Ok(None),
+            "q" => self.query(args),
+            "Q" if args == "StartNoAckMode" => {
+                /* this packet has already been acked, the reply is the last one GDB acks */
+                conn.ack = false;
+                "OK".to_string()
+            },
+            _ => String::new(),
+        };
+
+        Ok(Some(reply))
+    }
+
+    fn write_memory(&mut self, cpu: &mut CPU, args: &str) -> String {
+        let Some((range, data)) = args.split_once(':') else { return "E00".to_string() };
+        let Some((addr, len)) = range.split_once(',') else { return "E00".to_string() };
+        let (Some(addr), Ok(len)) = (hex_u16(addr), usize::from_str_radix(len, 16)) else {
+            return "E00".to_string();
+        };
+
+        let bytes: Vec<u8> = (0..len)
+            .filter_map(|i| data.get(i * 2..i * 2 + 2))
+            .filter_map(|b| u8::from_str_radix(b, 16).ok())
+            .collect();
+        if bytes.len() != len {
+            return "E01".to_string();
+        }
+
+        if cpu.isa == Isa::Lc3b {
+            for (i, b) in bytes.iter().enumerate() {
+                cpu.memory.write_byte(addr.wrapping_add(i as u16) as usize, *b);
+            }
+            return "OK".to_string();
+        }
+
+        for (i, pair) in bytes.chunks(2).enumerate() {
+            let word = addr.wrapping_add(i as u16) as usize;
+            let old = cpu.memory.memory[word];
+            let val = match pair {
+                [lo, hi] => (*lo as u16) | ((*hi as u16) << 8),
+                [lo] => (old & 0xFF00) | *lo as u16,
+                _ => old,
+            };
+            cpu.memory.write(word, val);
+        }
+
+        "OK".to_string()
+    }
+
+    fn breakpoint(&mut self, insert: bool, args: &str, isa: Isa) -> String {
+        let fields: Vec<&str> = args.split(',').collect();
+        let (Some(kind), Some(addr), Some(len)) = (fields.first(), fields.get(1).and_then(|a| hex_u16(a)), fields.get(2)) else {
+            return "E00".to_string();
+        };
+        /* GDB sends a length in bytes */
+        let len = usize::from_str_radix(len, 16).unwrap_or(2);
+        let units = match isa {
+            Isa::Lc3 => len.div_ceil(2),
+            Isa::Lc3b => len,
+        }.clamp(1, 0xFFFF) as u16;
+
+        let watch = match *kind {
+            "0" | "1" => {
+                if insert {
+                    self.breakpoints.insert(addr);
+                } else {
+                    self.breakpoints.remove(&addr);
+                }
+                return "OK".to_string();
+            },
+            "2" => WatchKind::Write,
+            "3" => WatchKind::Read,
+            "4" => WatchKind::Access,
+            _ => return String::new(),
+        };
+
+        if insert {
+            self.watchpoints.push(Watchpoint { kind: watch, addr, len: units });
+        } else {
+            self.watchpoints.retain(|w| !(w.kind == watch && w.addr == addr));
+        }
+        "OK".to_string()
+    }
+
+    fn query(&self, args: &str) -> String {
+        if args.starts_with("Supported") {
+            "PacketSize=4000;qXfer:features:read+;swbreak+;hwbreak+;QStartNoAckMode+".to_string()
+        } else if let Some(rest) = args.strip_prefix("Xfer:features:read:target.xml:") {
+            let Some((off, len)) = rest.split_once(',') else { return "E00".to_string() };
+            let (Ok(off), Ok(len)) = (usize::from_str_radix(off, 16), usize::from_str_radix(len, 16)) else {
+                return "E00".to_string();
+            };
+            if off >= TARGET_XML.len() {
+                "l".to_string()
+            } else {
+                let end = (off + len).min(TARGET_XML.len());
+                let prefix = if end == TARGET_XML.len() { "l" } else { "m" };
+                format!("{}{}", prefix, &TARGET_XML[off..end])
+            }
+        } else if args == "Attached" {
+            "1".to_string()
+        } else if args == "C" {
+            "QC1".to_string()
+        } else if args == "fThreadInfo" {
+            "m1".to_string()
+        } else if args == "sThreadInfo" {
+            "l".to_string()
+        } else {
+            String::new()
+        }
+    }
+
+    /*
+    the first access overlapping a watchpoint; an LC-3b access is to the
+    word at an even address, so it covers two bytes
+    */
+    fn watch_hit(&self, accesses: &[Access], isa: Isa) -> Option<(WatchKind, u16)> {
+        let span = match isa {
+            Isa::Lc3 => 1,
+            Isa::Lc3b => 2,
+        };
+        for access in accesses {
+            let (addr, is_write) = match *access {
+                Access::Read(addr, _) => (addr as u16, false),
+                Access::Write(addr, _) => (addr as u16, true),
+            };
+            for w in &self.watchpoints {
+                let start = w.addr as u32;
+                if addr as u32 + span <= start || addr as u32 >= start + w.len as u32 {
+                    continue;
+                }
+                match (w.kind, is_write) {
+                    (WatchKind::Write, true) | (WatchKind::Read, false) | (WatchKind::Access, _) => return Some((w.kind, addr)),
+                    _ => {}
+                }
+            }
+        }
+        None
+    }
+
+    /* runs until something worth reporting happens */
+    fn resume(&mut self, cpu: &mut CPU, conn: &mut Connection, single: bool) -> io::Result<Stop> {
+        let mut steps: u64 = 0;
+        cpu.running = true;
+
+        loop {
+            let watching = !self.watchpoints.is_empty();
+            if watching {
+                cpu.memory.accesses.clear();
+                cpu.memory.logging = true;
+            }
+            cpu.step();
+            if watching {
+                cpu.memory.logging = false;
+                if let Some((kind, addr)) = self.watch_hit(&cpu.memory.accesses, cpu.isa) {
+                    return Ok(Stop::Watch(kind, addr));
+                }
+            }
+            steps += 1;
+
+            if !cpu.running {
+                return Ok(Stop::Halted);
+            }
+            if single {
+                return Ok(Stop::Step);
+            }
+            if self.breakpoints.contains(&(cpu.pc as u16)) {
+                return Ok(Stop::Breakpoint);
+            }
+            if steps.is_multiple_of(POLL_INTERVAL) && conn.interrupted()? {
+                return Ok(Stop::Interrupted);
+            }
+        }
+    }
+
+    fn stop_reply(&self, stop: Stop) -> String {
+        match stop {
+            Stop::Step => "S05".to_string(),
+            Stop::Breakpoint => "T05swbreak:;".to_string(),
+            Stop::Watch(kind, addr) => {
+                let name = match kind {
+                    WatchKind::Write => "watch",
+                    WatchKind::Read => "rwatch",
+                    WatchKind::Access => "awatch",
+                };
+                format!("T05{}:{:x};", name, addr)
+            },
+            Stop::Halted => "W00".to_string(),
+            Stop::Interrupted => "S02".to_string(),
+        }
+    }
+}
+
+impl Default for GdbStub {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    fn read_reply(stream: &mut TcpStream) -> String {
+        let mut b = [0u8];
+        loop {
+            stream.read_exact(&mut b).unwrap();
+            if b[0] == b'$' {
+                break;
+            }
+        }
+        let mut body = Vec::new();
+        loop {
+            stream.read_exact(&mut b).unwrap();
+            if b[0] == b'#' {
+                break;
+            }
+            body.push(b[0]);
+        }
+        let mut sum = [0u8; 2];
+        stream.read_exact(&mut sum).unwrap();
+        stream.write_all(b"+").unwrap();
+        String::from_utf8(body).unwrap()
+    }
+
+    /* sends each packet in turn from a client thread, returning the replies */
+    fn session(cpu: &mut CPU, packets: &[&str]) -> Vec<String> {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let packets: Vec<String> = packets.iter().map(|p| p.to_string()).collect();
+
+        let client = thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            let mut replies = Vec::new();
+            for packet in packets.iter().map(String::as_str).chain(std::iter::once("D")) {
+                let sum = packet.bytes().fold(0u8, |acc, b| acc.wrapping_add(b));
+                write!(stream, "${}#{:02x}", packet, sum).unwrap();
+                replies.push(read_reply(&mut stream));
+            }
+            replies
+        });
+
+        let (stream, _) = listener.accept().unwrap();
+        GdbStub::new().serve(cpu, stream).unwrap();
+        let mut replies = client.join().unwrap();
+        assert_eq!(replies.pop().as_deref(), Some("OK"));
+        replies
+    }
+
+    fn program(words: &[u16]) -> CPU {
+        let mut cpu = CPU::new();
+        cpu.load_image(&std::iter::once(0x3000).chain(words.iter().copied()).flat_map(|w: u16| w.to_be_bytes()).collect());
+        cpu
+    }
+
+    #[test]
+    fn little_endian_words() {
+        assert_eq!(encode_le(0x3001), "0130");
+        assert_eq!(decode_le("0130"), Some(0x3001));
+        assert_eq!(decode_le("013"), None);
+    }
+
+    #[test]
+    fn registers_and_memory() {
+        /* ADD R1, R1, #5; HALT */
+        let mut cpu = program(&[0x1265, 0xF025]);
+        let replies = session(&mut cpu, &["g", "P1=3412", "p1", "m3000,4", "M3002,2:2110", "m3002,2", "s", "p8"]);
+
+        assert_eq!(replies[0], format!("{}0030{}", "0000".repeat(8), "0000"));
+        assert_eq!(replies[1], "OK");
+        assert_eq!(replies[2], "3412");
+        assert_eq!(replies[3], "651225f0");
+        assert_eq!(replies[4], "OK");
+        assert_eq!(replies[5], "2110");
+        assert_eq!(replies[6], "S05");
+        assert_eq!(replies[7], "0130");
+        assert_eq!(cpu.rr1, 0x1239);
+    }
+
+    #[test]
+    fn breakpoints_watchpoints_and_halt() {
+        /* ADD R0, R0, #1; ST R0, #2; HALT */
+        let mut cpu = program(&[0x1021, 0x3002, 0xF025]);
+        let replies = session(&mut cpu, &["Z0,3001,2", "c", "z0,3001,2", "Z2,3004,2", "c", "c"]);
+
+        assert_eq!(replies[1], "T05swbreak:;");
+        assert_eq!(replies[4], "T05watch:3004;");
+        assert_eq!(replies[5], "W00");
+        assert_eq!(cpu.memory.memory[0x3004], 1);
+    }
+
+    #[test]
+    fn watchpoints_fire_while_tracing() {
+        let mut cpu = program(&[0x1021, 0x3002, 0xF025]);
+        cpu.tracer = Some(crate::trace::Tracer::new(Box::new(crate::trace::TextTrace::new(io::sink()))));
+        let replies = session(&mut cpu, &["Z2,3004,2", "c"]);
+        assert_eq!(replies[1], "T05watch:3004;");
+    }
+
+    #[test]
+    fn target_description() {
+        let mut cpu = CPU::new();
+        let replies = session(&mut cpu, &["qSupported:xmlRegisters=i386", "qXfer:features:read:target.xml:0,20", "qXfer:features:read:target.xml:0,1000"]);
+
+        assert!(replies[0].contains("qXfer:features:read+"));
+        assert_eq!(replies[1], format!("m{}", &TARGET_XML[..0x20]));
+        assert_eq!(replies[2], format!("l{}", TARGET_XML));
+    }
+
+    #[test]
+    fn watchpoints_reach_the_top_of_memory_and_count_lc3b_bytes() {
+        let mut stub = GdbStub::new();
+        stub.breakpoint(true, "2,ffff,2", Isa::Lc3);
+        assert_eq!(stub.watch_hit(&[Access::Write(0xFFFF, 0)], Isa::Lc3), Some((WatchKind::Write, 0xFFFF)));
+
+        let mut stub = GdbStub::new();
+        stub.breakpoint(true, "3,3005,1", Isa::Lc3b);
+        assert_eq!(stub.watchpoints[0].len, 1);
+        assert_eq!(stub.watch_hit(&[Access::Read(0x3004, 0)], Isa::Lc3b), Some((WatchKind::Read, 0x3004)));
+        assert_eq!(stub.watch_hit(&[Access::Read(0x3006, 0)], Isa::Lc3b), None);
+    }
+
+    #[test]
+    fn an_interrupt_is_consumed_when_reported() {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let mut conn = Connection::new(stream).unwrap();
+
+        client.write_all(b"$c#63\x03").unwrap();
+        assert_eq!(conn.read_packet().unwrap().as_deref(), Some("c"));
+        while !conn.interrupted().unwrap() {}
+
+        client.write_all(b"$g#67").unwrap();
+        assert_eq!(conn.read_packet().unwrap().as_deref(), Some("g"));
+    }
+}
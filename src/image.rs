@@ -0,0 +1,359 @@
+use std::io::{self, Write};
+
+use crate::cpu::CPU;
+use crate::instructions::Isa;
+
+/*
+program and memory dump formats; every one starts a block of words at an
+origin, and all but Intel HEX hold exactly one block
+*/
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ImageFormat {
+    /* lc3as output, big endian origin then words */
+    Obj,
+    /* like .obj but every word little endian */
+    RawLe,
+    /* text, one hex word per line, the origin first */
+    Hex,
+    /* text, one 16 digit binary word per line, the origin first */
+    Bin,
+    /* Intel HEX records, byte addresses of big endian words */
+    IntelHex,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Segment {
+    pub origin: u16,
+    pub words: Vec<u16>,
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Image {
+    pub segments: Vec<Segment>,
+}
+
+/* a text line with any ; or // comment and surrounding blanks removed */
+fn strip(line: &str) -> &str {
+    let line = line.split(';').next().unwrap_or("");
+    line.split("//").next().unwrap_or("").trim()
+}
+
+fn parse_hex_word(word: &str) -> Option<u16> {
+    let digits = word.trim_start_matches("0x").trim_start_matches("0X").trim_start_matches(['x', 'X']);
+    if digits.is_empty() || digits.len() > 4 {
+        return None;
+    }
+    u16::from_str_radix(digits, 16).ok()
+}
+
+fn parse_bin_word(word: &str) -> Option<u16> {
+    if word.len() != 16 {
+        return None;
+    }
+    u16::from_str_radix(word, 2).ok()
+}
+
+fn hex_byte(text: &str, at: usize) -> Option<u8> {
+    u8::from_str_radix(text.get(at..at + 2)?, 16).ok()
+}
+
+/* addresses a word takes up: the LC-3 addresses words, the LC-3b bytes */
+fn stride(isa: Isa) -> u16 {
+    if isa == Isa::Lc3b { 2 } else { 1 }
+}
+
+impl Image {
+    /* the first segment's origin, where execution starts */
+    pub fn entry(&self) -> Option<u16> {
+        self.segments.first().map(|s| s.origin)
+    }
+
+    /*
+    text formats are recognised by their content; binary files are .obj
+    unless the origin only makes sense read little endian, as in 00 30
+    */
+    pub fn detect(data: &[u8]) -> ImageFormat {
+        if let Ok(text) = std::str::from_utf8(data) {
+            let mut lines = text.lines().map(strip).filter(|l| !l.is_empty()).peekable();
+            if lines.peek().is_some_and(|l| l.starts_with(':')) {
+                return ImageFormat::IntelHex;
+            }
+            let words: Vec<&str> = lines.collect();
+            if !words.is_empty() {
+                if words.iter().all(|w| parse_bin_word(w).is_some()) {
+                    return ImageFormat::Bin;
+                }
+                if words.iter().all(|w| parse_hex_word(w).is_some()) {
+                    return ImageFormat::Hex;
+                }
+            }
+        }
+
+        match data {
+            [0, b, ..] if *b != 0 => ImageFormat::RawLe,
+            _ => ImageFormat::Obj,
+        }
+    }
+
+    /* None when the data is not in the given format; `isa` says how Intel HEX byte addresses map to origins */
+    pub fn parse(data: &[u8], format: ImageFormat, isa: Isa) -> Option<Image> {
+        let words: Vec<u16> = match format {
+            ImageFormat::Obj | ImageFormat::RawLe => {
+                if data.len() < 2 || !data.len().is_multiple_of(2) {
+                    return None;
+                }
+                data.chunks_exact(2).map(|b| match format {
+                    ImageFormat::Obj => u16::from_be_bytes([b[0], b[1]]),
+                    _ => u16::from_le_bytes([b[0], b[1]]),
+                }).collect()
+            },
+            ImageFormat::Hex | ImageFormat::Bin => {
+                let text = std::str::from_utf8(data).ok()?;
+                let word = if format == ImageFormat::Hex { parse_hex_word } else { parse_bin_word };
+                text.lines().map(strip).filter(|l| !l.is_empty()).map(word).collect::<Option<_>>()?
+            },
+            ImageFormat::IntelHex => return Image::parse_intel_hex(std::str::from_utf8(data).ok()?, isa),
+        };
+
+        let (&origin, words) = words.split_first()?;
+        Some(Image { segments: vec![Segment { origin, words: words.to_vec() }] })
+    }
+
+    /* detect then parse */
+    pub fn read(data: &[u8], isa: Isa) -> Option<Image> {
+        Image::parse(data, Image::detect(data), isa)
+    }
+
+    /* data (00), end of file (01) and the two address extension records (02, 04) */
+    fn parse_intel_hex(text: &str, isa: Isa) -> Option<Image> {
+        let stride = stride(isa);
+        let mut image = Image::default();
+        let mut base = 0u32;
+        let mut next = None;
+
+        for line in text.lines().map(strip).filter(|l| !l.is_empty()) {
+            let record = line.strip_prefix(':')?;
+            let len = hex_byte(record, 0)? as usize;
+            let bytes: Vec<u8> = (0..len + 5).map(|i| hex_byte(record, i * 2)).collect::<Option<_>>()?;
+            if bytes.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)) != 0 {
+                return None;
+            }
+
+            let offset = u16::from_be_bytes([bytes[1], bytes[2]]) as u32;
+            let data = &bytes[4..4 + len];
+            match bytes[3] {
+                0x00 => {
+                    let addr = base + offset;
+                    if !addr.is_multiple_of(2) || !len.is_multiple_of(2) {
+                        return None;
+                    }
+                    let origin = (addr / (2 / stride) as u32) as u16;
+                    let words = data.chunks_exact(2).map(|b| u16::from_be_bytes([b[0], b[1]]));
+
+                    /* consecutive records run on in the same segment */
+                    match image.segments.last_mut() {
+                        Some(segment) if next == Some(origin) => segment.words.extend(words),
+                        _ => image.segments.push(Segment { origin, words: words.collect() }),
+                    }
+                    next = Some(origin.wrapping_add((len / 2) as u16 * stride));
+                },
+                0x01 => break,
+                0x02 => base = (u16::from_be_bytes([data.first().copied()?, data.get(1).copied()?]) as u32) << 4,
+                0x04 => base = (u16::from_be_bytes([data.first().copied()?, data.get(1).copied()?]) as u32) << 16,
+                _ => {},
+            }
+        }
+
+        if image.segments.is_empty() { None } else { Some(image) }
+    }
+
+    /* one block from the first origin to the end of the last segment, gaps filled with 0 */
+    fn flatten(&self, isa: Isa) -> Segment {
+        let Some(origin) = self.entry() else { return Segment { origin: 0, words: Vec::new() } };
+        let mut words: Vec<u16> = Vec::new();
+        for segment in self.segments.iter() {
+            let at = (segment.origin.wrapping_sub(origin) / stride(isa)) as usize;
+            if words.len() < at + segment.words.len() {
+                words.resize(at + segment.words.len(), 0);
+            }
+            words[at..at + segment.words.len()].copy_from_slice(&segment.words);
+        }
+        Segment { origin, words }
+    }
+
+    pub fn write<W: Write>(&self, format: ImageFormat, isa: Isa, out: &mut W) -> io::Result<()> {
+        if format == ImageFormat::IntelHex {
+            return self.write_intel_hex(isa, out);
+        }
+
+        let segment = self.flatten(isa);
+        let mut words = std::iter::once(segment.origin).chain(segment.words.iter().copied());
+        match format {
+            ImageFormat::Obj => words.try_for_each(|w| out.write_all(&w.to_be_bytes())),
+            ImageFormat::RawLe => words.try_for_each(|w| out.write_all(&w.to_le_bytes())),
+            ImageFormat::Hex => words.try_for_each(|w| writeln!(out, "{:04X}", w)),
+            _ => words.try_for_each(|w| writeln!(out, "{:016b}", w)),
+        }
+    }
+
+    /*
+    16 data bytes a record, with an extended linear address record once past
+    byte x10000; LC-3 word addresses are doubled, LC-3b ones already count bytes
+    */
+    fn write_intel_hex<W: Write>(&self, isa: Isa, out: &mut W) -> io::Result<()> {
+        let stride = stride(isa);
+        let mut high = 0u16;
+        for segment in self.segments.iter() {
+            for (i, chunk) in segment.words.chunks(8).enumerate() {
+                let addr = (segment.origin.wrapping_add((i * 8) as u16 * stride) as u32) * (2 / stride) as u32;
+                if (addr >> 16) as u16 != high {
+                    high = (addr >> 16) as u16;
+                    intel_record(out, 0x04, 0, &high.to_be_bytes())?;
+                }
+                let data: Vec<u8> = chunk.iter().flat_map(|w| w.to_be_bytes()).collect();
+                intel_record(out, 0x00, addr as u16, &data)?;
+            }
+        }
+        intel_record(out, 0x01, 0, &[])
+    }
+}
+
+fn intel_record<W: Write>(out: &mut W, kind: u8, addr: u16, data: &[u8]) -> io::Result<()> {
+    let mut bytes = vec![data.len() as u8];
+    bytes.extend_from_slice(&addr.to_be_bytes());
+    bytes.push(kind);
+    bytes.extend_from_slice(data);
+    let sum = bytes.iter().fold(0u8, |sum, &b| sum.wrapping_add(b));
+    bytes.push(sum.wrapping_neg());
+
+    write!(out, ":")?;
+    for b in bytes {
+        write!(out, "{:02X}", b)?;
+    }
+    writeln!(out)
+}
+
+impl CPU {
+    /*
+    loads an image in any of the formats; each segment goes through
+    load_image so the caches and checkers see it, then execution starts at
+    the first segment
+    */
+    pub fn load(&mut self, image: &Image) {
+        let Some(entry) = image.entry() else { return };
+
+        let mut span: Option<(u16, u16)> = None;
+        for segment in image.segments.iter() {
+            let mut obj = segment.origin.to_be_bytes().to_vec();
+            obj.extend(segment.words.iter().flat_map(|w| w.to_be_bytes()));
+            self.load_image(&obj);
+
+            if let Some((start, end)) = self.image {
+                span = Some(match span {
+                    Some((low, high)) => (low.min(start), high.max(end)),
+                    None => (start, end),
+                });
+            }
+        }
+
+        self.pc = entry as usize;
+        if image.segments.len() > 1 {
+            self.image = span;
+        }
+    }
+
+    /* detects the format, false if nothing could be made of the data */
+    pub fn load_any(&mut self, data: &[u8]) -> bool {
+        match Image::read(data, self.isa) {
+            Some(image) => {
+                self.load(&image);
+                true
+            },
+            None => false,
+        }
+    }
+
+    /* start..=end as it is in memory, without touching devices */
+    pub fn dump(&self, start: u16, end: u16) -> Image {
+        let words = (start as usize..=end as usize).step_by(stride(self.isa) as usize).map(|addr| self.memory.memory[addr]).collect();
+        Image { segments: vec![Segment { origin: start, words }] }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image() -> Image {
+        Image { segments: vec![Segment { origin: 0x3000, words: (0..20).map(|i| 0x1000 + i * 0x0111).collect() }] }
+    }
+
+    fn round_trip(image: &Image, format: ImageFormat, isa: Isa) -> Image {
+        let mut out = Vec::new();
+        image.write(format, isa, &mut out).unwrap();
+        assert_eq!(Image::detect(&out), format);
+        Image::read(&out, isa).unwrap()
+    }
+
+    #[test]
+    fn every_format_round_trips() {
+        for format in [ImageFormat::Obj, ImageFormat::RawLe, ImageFormat::Hex, ImageFormat::Bin, ImageFormat::IntelHex] {
+            for isa in [Isa::Lc3, Isa::Lc3b] {
+                assert_eq!(round_trip(&image(), format, isa), image(), "{:?} {:?}", format, isa);
+            }
+        }
+    }
+
+    #[test]
+    fn intel_hex_addresses_bytes() {
+        let mut lc3 = Vec::new();
+        image().write(ImageFormat::IntelHex, Isa::Lc3, &mut lc3).unwrap();
+        let lc3 = String::from_utf8(lc3).unwrap();
+        let records: Vec<&str> = lc3.lines().collect();
+        /* word x3000 is byte x6000, and each record of 16 bytes is 8 words on */
+        assert!(records[0].starts_with(":10600000"));
+        assert!(records[1].starts_with(":10601000"));
+        assert!(records[2].starts_with(":08602000"));
+        assert_eq!(*records.last().unwrap(), ":00000001FF");
+
+        let mut lc3b = Vec::new();
+        image().write(ImageFormat::IntelHex, Isa::Lc3b, &mut lc3b).unwrap();
+        let lc3b = String::from_utf8(lc3b).unwrap();
+        let records: Vec<&str> = lc3b.lines().collect();
+        assert!(records[0].starts_with(":10300000"));
+        assert!(records[1].starts_with(":10301000"));
+        assert!(records[2].starts_with(":08302000"));
+    }
+
+    #[test]
+    fn intel_hex_keeps_separate_segments_and_flattens_the_gap() {
+        for isa in [Isa::Lc3, Isa::Lc3b] {
+            let two = Image { segments: vec![
+                Segment { origin: 0x3000, words: vec![1, 2] },
+                Segment { origin: 0x3000 + 4 * stride(isa), words: vec![5] },
+            ] };
+            assert_eq!(round_trip(&two, ImageFormat::IntelHex, isa), two);
+
+            let mut obj = Vec::new();
+            two.write(ImageFormat::Obj, isa, &mut obj).unwrap();
+            assert_eq!(Image::read(&obj, isa).unwrap().segments[0].words, vec![1, 2, 0, 0, 5]);
+        }
+    }
+
+    #[test]
+    fn loading_and_dumping_agree() {
+        for isa in [Isa::Lc3, Isa::Lc3b] {
+            let mut cpu = CPU::new();
+            cpu.isa = isa;
+            cpu.load(&image());
+            let end = 0x3000 + 20 * stride(isa) - 1;
+            assert_eq!(cpu.dump(0x3000, end), image());
+        }
+    }
+
+    #[test]
+    fn bad_checksums_are_refused() {
+        assert_eq!(Image::read(b":0260000012345A\n:00000001FF\n", Isa::Lc3), None);
+        assert!(Image::read(b":02600000123458\n:00000001FF\n", Isa::Lc3).is_some());
+    }
+}
@@ -15,7 +15,14 @@ impl Flags {
     }
 }
 
-#[derive(Debug)]
+/* instruction set the CPU decodes; the LC-3b is byte addressed */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Isa {
+    Lc3,
+    Lc3b
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum OPCodes {
     OpBr,     /* branch */
     OpAdd,    /* add  */
@@ -25,7 +32,7 @@ pub enum OPCodes {
     OpAnd,    /* bitwise and */
     OpLdr,    /* load register */
     OpStr,    /* store register */
-    OpRti,    /* unused */
+    OpRti,    /* return from interrupt */
     OpNot,    /* bitwise not */
     OpLdi,    /* load indirect */
     OpSti,    /* store indirect */
@@ -80,7 +87,49 @@ impl OPCodes {
     }
 }
 
-#[derive(Debug)]
+/* LC-3b opcodes, TRAP, BR, JSR, JMP and RTI share their LC-3 encodings */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Lc3bOPCodes {
+    OpBr,     /* branch */
+    OpAdd,    /* add */
+    OpLdb,    /* load byte */
+    OpStb,    /* store byte */
+    OpJsr,    /* jump register */
+    OpAnd,    /* bitwise and */
+    OpLdw,    /* load word */
+    OpStw,    /* store word */
+    OpRti,    /* return from interrupt */
+    OpXor,    /* bitwise xor, NOT with an immediate of -1 */
+    OpRes,    /* 1010 and 1011 are unused */
+    OpJmp,    /* jump */
+    OpShf,    /* shift */
+    OpLea,    /* load effective address */
+    OpTrap    /* execute trap */
+}
+
+impl Lc3bOPCodes {
+    pub fn from(op: u16) -> Lc3bOPCodes {
+        match op {
+            0x0 => Self::OpBr,
+            0x1 => Self::OpAdd,
+            0x2 => Self::OpLdb,
+            0x3 => Self::OpStb,
+            0x4 => Self::OpJsr,
+            0x5 => Self::OpAnd,
+            0x6 => Self::OpLdw,
+            0x7 => Self::OpStw,
+            0x8 => Self::OpRti,
+            0x9 => Self::OpXor,
+            0xc => Self::OpJmp,
+            0xd => Self::OpShf,
+            0xe => Self::OpLea,
+            0xf => Self::OpTrap,
+            _ => Self::OpRes,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum TrapCodes{
     TrapGetC,
     TrapOut,
@@ -88,6 +137,16 @@ pub enum TrapCodes{
     TrapIn,
     TrapPutsP,
     TrapHalt,
+    /* extensions, each only runs when its capability is on */
+    TrapInDec,
+    TrapOutDec,
+    TrapRandom,
+    TrapClock,
+    TrapTicks,
+    TrapOpen,
+    TrapRead,
+    TrapWrite,
+    TrapClose,
 }
 
 impl TrapCodes {
@@ -98,18 +157,39 @@ impl TrapCodes {
             Self::TrapPuts => 0x22,  /* output a word string */
             Self::TrapIn => 0x23,    /* get character from keyboard, echoed onto the terminal */
             Self::TrapPutsP => 0x24, /* output a byte string */
-            Self::TrapHalt => 0x25   /* halt the program */
+            Self::TrapHalt => 0x25,  /* halt the program */
+            Self::TrapInDec => 0x26, /* read a signed decimal into R0 */
+            Self::TrapOutDec => 0x27, /* print R0 as a signed decimal */
+            Self::TrapRandom => 0x28, /* pseudo random number in R0 */
+            Self::TrapClock => 0x29, /* wall clock seconds, high word in R0, low in R1 */
+            Self::TrapTicks => 0x2A, /* instructions executed, high word in R0, low in R1 */
+            Self::TrapOpen => 0x30,  /* open the file named at R0, mode in R1 */
+            Self::TrapRead => 0x31,  /* read up to R2 chars from handle R0 into R1 */
+            Self::TrapWrite => 0x32, /* write R2 chars at R1 to handle R0 */
+            Self::TrapClose => 0x33  /* close handle R0 */
         }
     }
-    pub fn from(inst: u16) -> TrapCodes {
-        match inst {
+
+    /* None for vectors with no built in routine */
+    pub fn from(inst: u16) -> Option<TrapCodes> {
+        let code = match inst {
             0x20 => Self::TrapGetC,
             0x21 => Self::TrapOut,
             0x22 => Self::TrapPuts,
             0x23 => Self::TrapIn,
             0x24 => Self::TrapPutsP,
             0x25 => Self::TrapHalt,
-            _ => panic!("Illegal instruction")
-        }
+            0x26 => Self::TrapInDec,
+            0x27 => Self::TrapOutDec,
+            0x28 => Self::TrapRandom,
+            0x29 => Self::TrapClock,
+            0x2A => Self::TrapTicks,
+            0x30 => Self::TrapOpen,
+            0x31 => Self::TrapRead,
+            0x32 => Self::TrapWrite,
+            0x33 => Self::TrapClose,
+            _ => return None
+        };
+        Some(code)
     }
 }
\ No newline at end of file
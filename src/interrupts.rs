@@ -0,0 +1,242 @@
+use crate::callstack::FrameKind;
+use crate::cpu::CPU;
+use crate::instructions::Isa;
+
+/* interrupt and exception service routine addresses, indexed by vector */
+pub const VECTOR_TABLE: u16 = 0x0100;
+
+/* exception vectors */
+pub const PRIVILEGE_VIOLATION: u8 = 0x00;
+pub const ILLEGAL_OPCODE: u8 = 0x01;
+pub const ACCESS_VIOLATION: u8 = 0x02;
+
+/* what user mode may touch, everything else is system space or devices */
+const USER_SPACE: std::ops::Range<u16> = 0x3000..0xFE00;
+
+/* where the supervisor stack starts before an OS sets one up */
+pub const SUPERVISOR_STACK: u16 = 0x3000;
+
+impl CPU {
+    /* takes the highest priority device request if it outranks the running program */
+    pub(crate) fn poll_interrupts(&mut self) {
+        self.memory.tick();
+
+        let Some((device, req)) = self.memory.interrupt() else { return };
+        if req.priority as u16 <= self.priority {
+            return;
+        }
+
+        self.memory.devices[device].acknowledge();
+        self.enter_service(req.vector, Some(req.priority as u16), FrameKind::Interrupt);
+    }
+
+    /* user mode with access control on */
+    pub(crate) fn protected(&self) -> bool {
+        self.user && !self.relaxed
+    }
+
+    /* false, after raising ACV, when a protected program reaches outside user space */
+    pub(crate) fn access_ok(&mut self, addr: u16) -> bool {
+        if !self.protected() || USER_SPACE.contains(&addr) {
+            return true;
+        }
+        self.exception(ACCESS_VIOLATION);
+        false
+    }
+
+    /* an exception keeps the running priority */
+    pub(crate) fn exception(&mut self, vector: u8) {
+        self.enter_service(vector, None, FrameKind::Interrupt);
+    }
+
+    /* the LC-3b counts bytes, so its table is at x0200 with two bytes an entry */
+    fn word_size(&self) -> u16 {
+        match self.isa {
+            Isa::Lc3 => 1,
+            Isa::Lc3b => 2,
+        }
+    }
+
+    /* goes through the vector table, raising the priority for an interrupt */
+    fn enter_service(&mut self, vector: u8, priority: Option<u16>, kind: FrameKind) {
+        let slot = (VECTOR_TABLE + vector as u16) * self.word_size();
+        let target = self.memory.read(slot as usize);
+        if target == 0 {
+            eprintln!("No service routine for vector x{:02X}", vector);
+            self.running = false;
+            return;
+        }
+
+        let pc = self.pc as u16;
+        self.enter_supervisor(target, priority, kind, pc);
+    }
+
+    /*
+    the hardware sequence interrupts, exceptions and TRAP share: switch to
+    the supervisor stack, push PSR then PC and jump to `target`; RTI undoes
+    it. `site` is where the frame shows it was entered from
+    */
+    pub(crate) fn enter_supervisor(&mut self, target: u16, priority: Option<u16>, kind: FrameKind, site: u16) {
+        let psr = self.psr();
+        let pc = self.pc as u16;
+        let word = self.word_size();
+
+        let mut sp = self.rr6;
+        if self.user {
+            self.saved_usp = sp;
+            sp = self.saved_ssp;
+            self.user = false;
+        } else if sp == 0 {
+            /* a supervisor program that never set up R6 would push over the devices at xFFFF */
+            sp = self.saved_ssp;
+        }
+
+        sp = sp.wrapping_sub(word);
+        self.memory.write(sp as usize, psr);
+        sp = sp.wrapping_sub(word);
+        self.memory.write(sp as usize, pc);
+        self.set_reg(6, sp);
+
+        if let Some(priority) = priority {
+            self.priority = priority;
+        }
+        self.pc = target as usize;
+        self.calls.push(kind, site, target, pc);
+    }
+
+    /* RTI: pops PC and PSR, going back to the user stack if that is where we came from */
+    pub(crate) fn return_from_interrupt(&mut self) {
+        if self.user {
+            self.exception(PRIVILEGE_VIOLATION);
+            return;
+        }
+
+        let word = self.word_size();
+        let sp = self.rr6;
+        let pc = self.memory.read(sp as usize);
+        let psr = self.memory.read(sp.wrapping_add(word) as usize);
+        let sp = sp.wrapping_add(2 * word);
+
+        self.pc = pc as usize;
+        self.set_psr(psr);
+        if self.user {
+            self.saved_ssp = sp;
+            self.set_reg(6, self.saved_usp);
+        } else {
+            self.set_reg(6, sp);
+        }
+        self.calls.rti();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn program(words: &[u16]) -> CPU {
+        let mut cpu = CPU::new();
+        let obj: Vec<u8> = std::iter::once(0x3000).chain(words.iter().copied()).flat_map(|w: u16| w.to_be_bytes()).collect();
+        cpu.load_image(&obj);
+        cpu
+    }
+
+    #[test]
+    fn supervisor_without_a_stack_uses_the_saved_ssp() {
+        let mut cpu = program(&[0x1021]);
+        cpu.memory.memory[(VECTOR_TABLE + ILLEGAL_OPCODE as u16) as usize] = 0x1000;
+        let psr = cpu.psr();
+
+        cpu.exception(ILLEGAL_OPCODE);
+        assert_eq!(cpu.pc, 0x1000);
+        assert_eq!(cpu.rr6, SUPERVISOR_STACK - 2);
+        assert_eq!(cpu.memory.memory[(SUPERVISOR_STACK - 1) as usize], psr);
+        assert_eq!(cpu.memory.memory[(SUPERVISOR_STACK - 2) as usize], 0x3000);
+        assert_eq!(cpu.memory.memory[0xFFFE], 0);
+    }
+
+    #[test]
+    fn user_interrupts_switch_stacks_and_rti_switches_back() {
+        /* RTI */
+        let mut cpu = program(&[0x1021]);
+        cpu.memory.memory[(VECTOR_TABLE + ILLEGAL_OPCODE as u16) as usize] = 0x1000;
+        cpu.memory.memory[0x1000] = 0x8000;
+        cpu.user = true;
+        cpu.rr6 = 0xF000;
+
+        cpu.exception(ILLEGAL_OPCODE);
+        assert!(!cpu.user);
+        assert_eq!(cpu.saved_usp, 0xF000);
+        assert_eq!(cpu.rr6, SUPERVISOR_STACK - 2);
+
+        cpu.step();
+        assert!(cpu.user);
+        assert_eq!(cpu.pc, 0x3000);
+        assert_eq!(cpu.rr6, 0xF000);
+        assert_eq!(cpu.saved_ssp, SUPERVISOR_STACK);
+    }
+
+    #[test]
+    fn protected_traps_run_the_routine_in_supervisor_mode() {
+        /* TRAP x40; ADD R0, R0, #1, with the routine in system space an LDR from the vector table then RTI */
+        let mut cpu = program(&[0xF040, 0x1021]);
+        cpu.memory.memory[0x0040] = 0x0400;
+        cpu.memory.memory[0x0400] = 0x6200; /* LDR R1, R0, #0 */
+        cpu.memory.memory[0x0401] = 0x8000;
+        cpu.user = true;
+        cpu.rr6 = 0xF000;
+        cpu.rr0 = 0x0040;
+        let psr = cpu.psr();
+
+        cpu.step();
+        assert!(!cpu.user);
+        assert_eq!(cpu.pc, 0x0400);
+        assert_eq!(cpu.rr6, SUPERVISOR_STACK - 2);
+        assert_eq!(cpu.memory.memory[(SUPERVISOR_STACK - 1) as usize], psr);
+        assert_eq!(cpu.memory.memory[(SUPERVISOR_STACK - 2) as usize], 0x3001);
+
+        /* system space is open to the routine */
+        cpu.step();
+        assert_eq!(cpu.rr1, 0x0400);
+        assert_eq!(cpu.pc, 0x0401);
+
+        cpu.step();
+        assert!(cpu.user);
+        assert_eq!((cpu.pc, cpu.rr6, cpu.calls.depth()), (0x3001, 0xF000, 0));
+
+        /* and closed again to the program */
+        cpu.memory.memory[(VECTOR_TABLE + ACCESS_VIOLATION as u16) as usize] = 0x1000;
+        cpu.memory.write(0x3001, 0x6200);
+        cpu.step();
+        assert_eq!(cpu.pc, 0x1000);
+    }
+
+    #[test]
+    fn user_programs_may_not_reach_system_space_or_devices_unless_relaxed() {
+        /* STR R0, R1, #0 with R1 = x0000; LDI R2, PTR; HALT; PTR .FILL xFE00 */
+        let words = [0x7040, 0xA401, 0xF025, 0xFE00];
+        let mut cpu = program(&words);
+        cpu.memory.memory[(VECTOR_TABLE + ACCESS_VIOLATION as u16) as usize] = 0x1000;
+        cpu.memory.memory[0x1000] = 0x8000; /* RTI */
+        cpu.user = true;
+        cpu.rr6 = 0xF000;
+        cpu.rr0 = 0x1234;
+
+        cpu.step();
+        assert_eq!((cpu.pc, cpu.user), (0x1000, false));
+        assert_eq!(cpu.memory.memory[0x0000], 0);
+        cpu.step();
+        assert_eq!((cpu.pc, cpu.user), (0x3001, true));
+        cpu.step();
+        assert_eq!(cpu.pc, 0x1000);
+        assert_eq!(cpu.rr2, 0);
+
+        let mut cpu = program(&words);
+        cpu.user = true;
+        cpu.relaxed = true;
+        cpu.rr0 = 0x1234;
+        cpu.step();
+        cpu.step();
+        assert_eq!(cpu.pc, 0x3002);
+        assert_eq!(cpu.memory.memory[0x0000], 0x1234);
+    }
+}
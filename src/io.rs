@@ -1,28 +1,191 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
 use std::io::{Read, self, Write};
 
 use crate::cpu::CPU;
 
 use super::*;
 
+#[cfg(target_arch = "wasm32")]
 #[link(wasm_import_module = "../utils.js")]
 extern { fn printlog(data: char); }
 
+#[cfg(target_arch = "wasm32")]
 #[link(wasm_import_module = "../utils.js")]
 extern { fn printdisassembly(data: char); }
 
+#[cfg(target_arch = "wasm32")]
 #[link(wasm_import_module = "../utils.js")]
 extern { fn getkey() -> u8; }
 
+#[cfg(target_arch = "wasm32")]
 #[link(wasm_import_module = "../utils.js")]
 extern { fn printpc(pc: usize); }
 
+#[cfg(target_arch = "wasm32")]
 #[link(wasm_import_module = "../utils.js")]
 extern { fn printreg(number: u32, value: u16); }
 
+/* outside the browser the console is stdin/stdout unless redirected, and the UI hooks do nothing */
+#[cfg(not(target_arch = "wasm32"))]
+mod host {
+    use std::cell::RefCell;
+    use std::io::{self, Read, Write};
+    use std::sync::mpsc::{self, Receiver};
+    use std::thread;
+
+    type Output = Box<dyn FnMut(u8)>;
+    type Input = Box<dyn FnMut() -> u8>;
+
+    thread_local! {
+        pub static CONSOLE: RefCell<Option<(Output, Input)>> = RefCell::new(None);
+        static STDIN: RefCell<Option<Receiver<u8>>> = const { RefCell::new(None) };
+    }
+
+    pub unsafe fn printlog(data: char) {
+        let redirected = CONSOLE.with(|c| match c.borrow_mut().as_mut() {
+            Some((output, _)) => {
+                output(data as u8);
+                true
+            },
+            None => false,
+        });
+
+        if !redirected {
+            print!("{}", data);
+            let _ = io::stdout().flush();
+        }
+    }
+
+    pub unsafe fn printdisassembly(_data: char) {}
+
+    pub unsafe fn getkey() -> u8 {
+        if let Some(key) = CONSOLE.with(|c| c.borrow_mut().as_mut().map(|(_, input)| input())) {
+            return key;
+        }
+        stdin_key(true)
+    }
+
+    /* like getkey, but 0 straight away when no key has been typed */
+    pub unsafe fn pollkey() -> u8 {
+        if let Some(key) = CONSOLE.with(|c| c.borrow_mut().as_mut().map(|(_, input)| input())) {
+            return key;
+        }
+        stdin_key(false)
+    }
+
+    /* stdin is read on a thread of its own so polling never blocks, 0 once it is closed */
+    fn stdin_key(wait: bool) -> u8 {
+        STDIN.with(|keys| {
+            let mut keys = keys.borrow_mut();
+            let keys = keys.get_or_insert_with(|| {
+                let (tx, rx) = mpsc::channel();
+                thread::spawn(move || {
+                    let mut byte = [0u8];
+                    while let Ok(1) = io::stdin().read(&mut byte) {
+                        if tx.send(byte[0]).is_err() {
+                            break;
+                        }
+                    }
+                });
+                rx
+            });
+
+            if wait {
+                keys.recv().unwrap_or(0)
+            } else {
+                keys.try_recv().unwrap_or(0)
+            }
+        })
+    }
+
+    pub unsafe fn printpc(_pc: usize) {}
+
+    pub unsafe fn printreg(_number: u32, _value: u16) {}
+}
+
+/* sends program output and keyboard input somewhere other than stdout/stdin */
+#[cfg(not(target_arch = "wasm32"))]
+pub fn redirect_console(output: Box<dyn FnMut(u8)>, input: Box<dyn FnMut() -> u8>) {
+    host::CONSOLE.with(|c| *c.borrow_mut() = Some((output, input)));
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+use host::*;
+
+/* keys taken from the console on their way to one machine, to hand the same ones to another */
+enum Tape {
+    Recording(VecDeque<u8>),
+    Playing(VecDeque<u8>),
+}
+
+thread_local! {
+    static TAPE: RefCell<Option<Tape>> = const { RefCell::new(None) };
+}
+
+/* from here on every key read, or 0 polled, is also kept */
+pub fn record_keys() {
+    TAPE.set(Some(Tape::Recording(VecDeque::new())));
+}
+
+/* reads now get the recorded keys in order instead of the console, then 0 */
+pub fn play_keys() {
+    TAPE.with_borrow_mut(|tape| {
+        if let Some(Tape::Recording(keys)) = tape.take() {
+            *tape = Some(Tape::Playing(keys));
+        }
+    });
+}
+
+/* back to the console, dropping whatever was not played */
+pub fn stop_keys() {
+    TAPE.set(None);
+}
+
+fn tape(read: impl FnOnce() -> u8) -> u8 {
+    let played = TAPE.with_borrow_mut(|tape| match tape {
+        Some(Tape::Playing(keys)) => Some(keys.pop_front().unwrap_or(0)),
+        _ => None,
+    });
+    if let Some(key) = played {
+        return key;
+    }
+
+    let key = read();
+    TAPE.with_borrow_mut(|tape| {
+        if let Some(Tape::Recording(keys)) = tape {
+            keys.push_back(key);
+        }
+    });
+    key
+}
 
 pub fn get_key() -> u8 {
-    let key = unsafe { getkey() };
-    return key;
+    tape(|| unsafe { getkey() })
+}
+
+/* a key if one has been typed, 0 otherwise; KBSR polling must not block */
+#[cfg(target_arch = "wasm32")]
+pub fn poll_key() -> u8 {
+    tape(|| unsafe { getkey() })
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub fn poll_key() -> u8 {
+    tape(|| unsafe { pollkey() })
+}
+
+/* wall clock in milliseconds since the Unix epoch */
+#[cfg(target_arch = "wasm32")]
+pub fn now_ms() -> f64 {
+    js_sys::Date::now()
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub fn now_ms() -> f64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0.0, |d| d.as_millis() as f64)
 }
 
 pub fn print(data: u8) {
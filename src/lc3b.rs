@@ -0,0 +1,258 @@
+use crate::callstack::FrameKind;
+use crate::cpu::CPU;
+use crate::disassembler::{decode, decode_lc3b, Lc3bDecoded};
+use crate::instructions::{Lc3bOPCodes, TrapCodes};
+use crate::interrupts::ILLEGAL_OPCODE;
+use crate::io::print;
+
+fn sign_extend_byte(b: u8) -> u16 {
+    b as i8 as i16 as u16
+}
+
+/*
+the LC-3b half of the CPU: PC and addresses count bytes, word accesses
+ignore bit 0 and PC-relative offsets are scaled to words
+*/
+impl CPU {
+    pub(crate) fn execute_lc3b(&mut self, inst: u16) {
+        let d = decode_lc3b(inst);
+
+        match d.op {
+            Lc3bOPCodes::OpBr => {
+                if (d.r0 & self.rcond) != 0 {
+                    self.pc = (self.pc as u16).wrapping_add(d.imm << 1) as usize;
+                }
+            },
+            Lc3bOPCodes::OpAdd | Lc3bOPCodes::OpAnd | Lc3bOPCodes::OpXor => self.alu_lc3b(d),
+            Lc3bOPCodes::OpLdb => {
+                let addr = self.get_reg(d.r1).wrapping_add(d.imm);
+                if !self.access_ok(addr) {
+                    return;
+                }
+                let val = sign_extend_byte(self.memory.read_byte(addr as usize));
+                self.set_reg(d.r0, val);
+                self.update_flags(d.r0);
+            },
+            Lc3bOPCodes::OpStb => {
+                let addr = self.get_reg(d.r1).wrapping_add(d.imm);
+                if !self.access_ok(addr) {
+                    return;
+                }
+                self.stack_store(d.r1, addr);
+                let val = *self.get_reg(d.r0) as u8;
+                self.memory.write_byte(addr as usize, val);
+            },
+            Lc3bOPCodes::OpLdw => {
+                let addr = self.get_reg(d.r1).wrapping_add(d.imm << 1) & 0xFFFE;
+                if !self.access_ok(addr) {
+                    return;
+                }
+                let val = self.memory.read(addr as usize);
+                self.set_reg(d.r0, val);
+                self.update_flags(d.r0);
+            },
+            Lc3bOPCodes::OpStw => {
+                let addr = self.get_reg(d.r1).wrapping_add(d.imm << 1) & 0xFFFE;
+                if !self.access_ok(addr) {
+                    return;
+                }
+                self.stack_store(d.r1, addr);
+                let val = *self.get_reg(d.r0);
+                self.memory.write(addr as usize, val);
+            },
+            Lc3bOPCodes::OpJsr => {
+                let ret = self.pc as u16;
+                let target = if d.flag {
+                    ret.wrapping_add(d.imm << 1)
+                } else {
+                    *self.get_reg(d.r1)
+                };
+                self.set_reg(7, ret);
+                self.pc = target as usize;
+                self.calls.push(FrameKind::Call, ret.wrapping_sub(2), target, ret);
+            },
+            Lc3bOPCodes::OpJmp => {
+                let target = *self.get_reg(d.r1);
+                if d.r1 == 7 {
+                    self.calls.ret((self.pc as u16).wrapping_sub(2), target);
+                }
+                self.pc = target as usize;
+            },
+            Lc3bOPCodes::OpShf => {
+                let val = *self.get_reg(d.r1);
+                let val = match d.r2 {
+                    1 => val >> d.imm,
+                    3 => ((val as i16) >> d.imm) as u16,
+                    _ => val << d.imm,
+                };
+                self.set_reg(d.r0, val);
+                self.update_flags(d.r0);
+            },
+            /* unlike the LC-3, LEA leaves the condition codes alone */
+            Lc3bOPCodes::OpLea => {
+                let val = (self.pc as u16).wrapping_add(d.imm << 1);
+                self.set_reg(d.r0, val);
+            },
+            Lc3bOPCodes::OpTrap => self.trap_lc3b(inst, d),
+            Lc3bOPCodes::OpRti => self.return_from_interrupt(),
+            Lc3bOPCodes::OpRes => self.exception(ILLEGAL_OPCODE),
+        }
+    }
+
+    fn alu_lc3b(&mut self, d: Lc3bDecoded) {
+        /* AND with #0 clears without depending on the source */
+        let a = if d.op == Lc3bOPCodes::OpAnd && d.flag && d.imm == 0 { 0 } else { *self.get_reg(d.r1) };
+        let b = if d.flag { d.imm } else { *self.get_reg(d.r2) };
+
+        let val = match d.op {
+            Lc3bOPCodes::OpAdd => a.wrapping_add(b),
+            Lc3bOPCodes::OpAnd => a & b,
+            _ => a ^ b,
+        };
+        self.set_reg(d.r0, val);
+        self.update_flags(d.r0);
+    }
+
+    /*
+    registered handlers first, then the trap table at trapvect8 << 1, then
+    the built in routines when the table entry is empty
+    */
+    fn trap_lc3b(&mut self, inst: u16, d: Lc3bDecoded) {
+        let vector = d.imm as u8;
+        if self.traps.handles(vector) {
+            return self.in_trap_frame(vector, |cpu| {
+                cpu.run_trap_handler(vector);
+            });
+        }
+        if self.memory.memory[(d.imm << 1) as usize] != 0 {
+            return self.trap_table(vector);
+        }
+
+        match TrapCodes::from(d.imm) {
+            /* strings are one char per byte, so PUTS and PUTSP are the same */
+            Some(TrapCodes::TrapPuts) | Some(TrapCodes::TrapPutsP) => self.in_trap_frame(vector, |cpu| {
+                let mut addr = cpu.rr0;
+                loop {
+                    let c = cpu.memory.read_byte(addr as usize);
+                    if c == 0 {
+                        break;
+                    }
+                    print(c);
+                    addr = addr.wrapping_add(1);
+                }
+            }),
+            /* the shared routines open their own frame */
+            Some(_) => self.dispatch(decode(inst)),
+            None => self.trap_table(vector),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cpu::CPU;
+    use crate::instructions::Isa;
+    use crate::io::redirect_console;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn program(words: &[u16]) -> CPU {
+        let mut cpu = CPU::new();
+        cpu.isa = Isa::Lc3b;
+        let obj: Vec<u8> = std::iter::once(0x3000).chain(words.iter().copied()).flat_map(|w: u16| w.to_be_bytes()).collect();
+        cpu.load_image(&obj);
+        cpu
+    }
+
+    #[test]
+    fn byte_loads_sign_extend_and_byte_stores_keep_the_other_half() {
+        let mut cpu = program(&[
+            0xE207, /* LEA R1, DATA */
+            0x2441, /* LDB R2, R1, #1 */
+            0x2640, /* LDB R3, R1, #0 */
+            0x3442, /* STB R2, R1, #2 */
+            0x1A61, /* ADD R5, R1, #1 */
+            0x6940, /* LDW R4, R5, #0 */
+            0xF025, /* HALT */
+            0x0000,
+            0x80FF, /* DATA */
+            0x1234,
+        ]);
+        cpu.run();
+
+        assert_eq!(cpu.rr1, 0x3010);
+        assert_eq!(cpu.rr2, 0xFF80);
+        assert_eq!(cpu.rr3, 0xFFFF);
+        /* the low byte of the word at x3012 */
+        assert_eq!(cpu.memory.memory[0x3012], 0x1280);
+        /* word accesses ignore bit 0 */
+        assert_eq!(cpu.rr4, 0x80FF);
+    }
+
+    #[test]
+    fn puts_prints_one_char_per_byte() {
+        let out = Rc::new(RefCell::new(Vec::new()));
+        let sink = out.clone();
+        redirect_console(Box::new(move |c| sink.borrow_mut().push(c)), Box::new(|| 0));
+
+        let mut cpu = program(&[
+            0xE002, /* LEA R0, TEXT */
+            0xF022, /* PUTS */
+            0xF025, /* HALT */
+            0x6948, /* TEXT "Hi!" */
+            0x0021,
+            0x0000,
+        ]);
+        cpu.run();
+
+        assert!(String::from_utf8_lossy(&out.borrow()).starts_with("Hi!"));
+        assert_eq!(cpu.calls.depth(), 0);
+    }
+
+    #[test]
+    fn lea_leaves_the_condition_codes() {
+        let mut cpu = program(&[
+            0x5020, /* AND R0, R0, #0 */
+            0xE201, /* LEA R1, +1 */
+            0xF025, /* HALT */
+        ]);
+        cpu.step();
+        cpu.step();
+        assert_eq!(cpu.rr1, 0x3006);
+        assert_eq!(cpu.rcond, 2);
+    }
+
+    #[test]
+    fn reserved_opcodes_raise_illegal_opcode_through_the_byte_vector_table() {
+        for op in [0xA000, 0xB000] {
+            let mut cpu = program(&[op]);
+            cpu.memory.memory[0x0202] = 0x4000;
+            cpu.step();
+
+            assert!(cpu.running);
+            assert_eq!(cpu.pc, 0x4000);
+            /* PSR then PC, two bytes apart below the supervisor stack */
+            assert_eq!(cpu.rr6, 0x2FFC);
+            assert_eq!(cpu.memory.memory[0x2FFC], 0x3002);
+        }
+    }
+
+    #[test]
+    fn rti_returns_from_a_service_routine() {
+        let mut cpu = program(&[
+            0xA000, /* reserved */
+            0x1021, /* ADD R0, R0, #1 */
+            0xF025, /* HALT */
+            0x8000, /* RTI */
+        ]);
+        cpu.memory.memory[0x0202] = 0x3006;
+        cpu.step();
+        cpu.step();
+
+        assert_eq!(cpu.pc, 0x3002);
+        assert_eq!(cpu.rr6, 0x3000);
+        assert_eq!(cpu.calls.depth(), 0);
+        cpu.step();
+        assert_eq!(cpu.rr0, 1);
+    }
+}
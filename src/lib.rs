@@ -5,5 +5,33 @@ pub mod instructions;
 pub mod io;
 pub mod wasm;
 pub mod disassembler;
+pub mod cache;
+pub mod block;
+pub mod events;
+pub mod trace;
+pub mod symbols;
+pub mod profiler;
+pub mod debuginfo;
+pub mod coverage;
+pub mod callstack;
+pub mod lint;
+pub mod microarch;
+pub mod lc3b;
+pub mod traps;
+pub mod interrupts;
+pub mod timer;
+pub mod framebuffer;
+pub mod shadow;
+pub mod selfmod;
+pub mod stack;
+pub mod convention;
+pub mod image;
+pub mod memdiff;
+pub mod lockstep;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod gdbstub;
+pub mod json;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod dap;
 
 use wasm_bindgen::prelude::*;
\ No newline at end of file
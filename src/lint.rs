@@ -0,0 +1,285 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+use crate::debuginfo::DebugInfo;
+use crate::disassembler::{decode, disassembly, Decoded};
+use crate::instructions::OPCodes;
+use crate::memory::{Mem, DEVICE_START};
+use crate::symbols::SymbolTable;
+
+const TRAP_HALT: u16 = 0x25;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LintKind {
+    R7Clobbered,
+    FallsOffEnd,
+    ExecutesData,
+    UninitialisedRead,
+    NopBranch,
+    IllegalOpcode,
+}
+
+#[derive(Debug, Clone)]
+pub struct Lint {
+    pub addr: u16,
+    pub kind: LintKind,
+    pub message: String,
+}
+
+impl fmt::Display for Lint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "x{:04X}: warning: {}", self.addr, self.message)
+    }
+}
+
+/* where control can go after one instruction */
+struct Node {
+    decoded: Decoded,
+    succ: Vec<u16>,
+}
+
+/* control flow graph of everything reachable from an entry point */
+pub struct Cfg {
+    nodes: BTreeMap<u16, Node>,
+    /* JSR targets, each one the entry of a subroutine */
+    pub subroutines: BTreeSet<u16>,
+}
+
+impl Cfg {
+    /* addresses outside `image` end up as successors but are never decoded */
+    pub fn build(memory: &Mem, entry: u16, image: (u16, u16)) -> Cfg {
+        let mut cfg = Cfg { nodes: BTreeMap::new(), subroutines: BTreeSet::new() };
+        let mut work = vec![entry];
+
+        while let Some(addr) = work.pop() {
+            if cfg.nodes.contains_key(&addr) || addr < image.0 || addr > image.1 {
+                continue;
+            }
+
+            let decoded = decode(memory.memory[addr as usize]);
+            let next = addr.wrapping_add(1);
+            let target = |offset: u16| next.wrapping_add(offset);
+            let mut call = None;
+
+            let succ = match decoded.op {
+                OPCodes::OpBr => match decoded.r0 {
+                    0 => vec![next],
+                    7 => vec![target(decoded.imm)],
+                    _ => vec![next, target(decoded.imm)],
+                },
+                OPCodes::OpJsr => {
+                    if decoded.flag {
+                        call = Some(target(decoded.imm));
+                    }
+                    vec![next]
+                },
+                /* RET and computed jumps leave the graph */
+                OPCodes::OpJmp | OPCodes::OpRti | OPCodes::OpRes => vec![],
+                OPCodes::OpTrap if decoded.imm == TRAP_HALT => vec![],
+                _ => vec![next],
+            };
+
+            work.extend(succ.iter().copied());
+            if let Some(sub) = call {
+                cfg.subroutines.insert(sub);
+                work.push(sub);
+            }
+            cfg.nodes.insert(addr, Node { decoded, succ });
+        }
+
+        cfg
+    }
+
+    pub fn reachable(&self) -> impl Iterator<Item = &u16> {
+        self.nodes.keys()
+    }
+
+    pub fn successors(&self, addr: u16) -> &[u16] {
+        self.nodes.get(&addr).map(|n| n.succ.as_slice()).unwrap_or(&[])
+    }
+}
+
+fn writes_r7(d: &Decoded) -> bool {
+    match d.op {
+        OPCodes::OpAdd | OPCodes::OpAnd | OPCodes::OpNot | OPCodes::OpLea => d.r0 == 7,
+        OPCodes::OpJsr | OPCodes::OpTrap => true,
+        _ => false,
+    }
+}
+
+fn restores_r7(d: &Decoded) -> bool {
+    matches!(d.op, OPCodes::OpLd | OPCodes::OpLdr | OPCodes::OpLdi) && d.r0 == 7
+}
+
+/* every RET reachable from `entry` along a path where R7 no longer holds the return address */
+fn clobbered_returns(cfg: &Cfg, entry: u16) -> BTreeMap<u16, u16> {
+    /* RET address -> instruction that clobbered R7 */
+    let mut found = BTreeMap::new();
+    let mut seen = BTreeSet::new();
+    let mut work: Vec<(u16, Option<u16>)> = vec![(entry, None)];
+
+    while let Some((addr, clobber)) = work.pop() {
+        if !seen.insert((addr, clobber)) {
+            continue;
+        }
+        let Some(node) = cfg.nodes.get(&addr) else { continue };
+        let d = &node.decoded;
+
+        if d.op == OPCodes::OpJmp && d.r1 == 7 {
+            if let Some(at) = clobber {
+                found.entry(addr).or_insert(at);
+            }
+            continue;
+        }
+
+        let clobber = if restores_r7(d) {
+            None
+        } else if writes_r7(d) {
+            Some(addr)
+        } else {
+            clobber
+        };
+
+        for &next in &node.succ {
+            work.push((next, clobber));
+        }
+    }
+
+    found
+}
+
+/*
+walks the image from `entry` and reports the usual student mistakes;
+`image` is the first and last loaded address, data regions come from `debug`
+*/
+pub fn lint(memory: &Mem, entry: u16, image: (u16, u16), debug: &DebugInfo, symbols: &SymbolTable) -> Vec<Lint> {
+    let cfg = Cfg::build(memory, entry, image);
+    let mut lints = Vec::new();
+    let in_image = |addr: u16| addr >= image.0 && addr <= image.1;
+    let name = |addr: u16| symbols.describe(addr);
+
+    for (&addr, node) in &cfg.nodes {
+        let d = &node.decoded;
+        let inst = memory.memory[addr as usize];
+
+        if let Some(src) = debug.line(addr).filter(|l| l.is_data()) {
+            lints.push(Lint {
+                addr,
+                kind: LintKind::ExecutesData,
+                message: format!("executes data at {} (line {}: {})", name(addr), src.line, src.text),
+            });
+            continue;
+        }
+
+        match d.op {
+            OPCodes::OpBr if d.r0 == 0 => lints.push(Lint {
+                addr,
+                kind: LintKind::NopBranch,
+                message: format!("BR with no condition codes never branches ({})", disassembly(inst)),
+            }),
+            OPCodes::OpRti | OPCodes::OpRes => lints.push(Lint {
+                addr,
+                kind: LintKind::IllegalOpcode,
+                message: format!("{:?} is not usable from a user program", d.op),
+            }),
+            OPCodes::OpLd | OPCodes::OpLdi => {
+                let src = addr.wrapping_add(1).wrapping_add(d.imm);
+                if !in_image(src) && (src as usize) < DEVICE_START {
+                    lints.push(Lint {
+                        addr,
+                        kind: LintKind::UninitialisedRead,
+                        message: format!("reads x{:04X} which the program never initialises", src),
+                    });
+                }
+            },
+            _ => {}
+        }
+
+        for &next in &node.succ {
+            if !in_image(next) {
+                lints.push(Lint {
+                    addr,
+                    kind: LintKind::FallsOffEnd,
+                    message: format!("control runs off the program from {} to x{:04X}", name(addr), next),
+                });
+            }
+        }
+    }
+
+    for &sub in &cfg.subroutines {
+        for (ret, at) in clobbered_returns(&cfg, sub) {
+            lints.push(Lint {
+                addr: ret,
+                kind: LintKind::R7Clobbered,
+                message: format!("RET in {} after R7 was overwritten at {} without being restored", name(sub), name(at)),
+            });
+        }
+    }
+
+    lints.sort_by_key(|l| l.addr);
+    lints
+}
+
+pub fn report(lints: &[Lint]) -> String {
+    lints.iter().map(|l| format!("{}\n", l)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::CPU;
+
+    fn linted(words: &[u16], listing: &str) -> Vec<Lint> {
+        let mut cpu = CPU::new();
+        cpu.load_image(&std::iter::once(0x3000).chain(words.iter().copied()).flat_map(|w: u16| w.to_be_bytes()).collect());
+        cpu.load_listing("prog.asm", listing);
+        cpu.lint()
+    }
+
+    fn kinds(words: &[u16], listing: &str) -> Vec<(u16, LintKind)> {
+        linted(words, listing).iter().map(|l| (l.addr, l.kind)).collect()
+    }
+
+    #[test]
+    fn clean_program_has_no_warnings() {
+        /* JSR SUB; HALT; SUB: RET */
+        assert_eq!(kinds(&[0x4801, 0xF025, 0xC1C0], ""), vec![]);
+    }
+
+    #[test]
+    fn r7_overwritten_before_ret() {
+        /* JSR SUB; HALT; SUB: ADD R7, R0, #1; RET */
+        assert_eq!(kinds(&[0x4801, 0xF025, 0x1E21, 0xC1C0], ""), vec![(0x3003, LintKind::R7Clobbered)]);
+    }
+
+    #[test]
+    fn r7_saved_and_restored_is_fine() {
+        /* JSR SUB; HALT; SUB: ST R7, SAVE; TRAP x21; LD R7, SAVE; RET; SAVE: .FILL 0 */
+        assert_eq!(kinds(&[0x4801, 0xF025, 0x3E03, 0xF021, 0x2E01, 0xC1C0, 0x0000], ""), vec![]);
+    }
+
+    #[test]
+    fn nop_branch_and_falling_off_the_end() {
+        /* BR #1; ADD R0, R0, #1 */
+        assert_eq!(kinds(&[0x0001, 0x1021], ""), vec![
+            (0x3000, LintKind::NopBranch),
+            (0x3001, LintKind::FallsOffEnd),
+        ]);
+    }
+
+    #[test]
+    fn runs_into_fill_data() {
+        let listing = "\
+(3000) 1021  0001000000100001 (   2)                 ADD R0, R0, #1
+(3001) 0007  0000000000000111 (   3) DATA            .FILL 7
+";
+        assert_eq!(kinds(&[0x1021, 0x0007], listing), vec![(0x3001, LintKind::ExecutesData)]);
+    }
+
+    #[test]
+    fn loads_from_outside_the_image() {
+        /* LD R0, #5; HALT */
+        let lints = linted(&[0x2005, 0xF025], "");
+        assert_eq!(report(&lints), "x3000: warning: reads x3006 which the program never initialises\n");
+    }
+}
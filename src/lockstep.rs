@@ -0,0 +1,285 @@
+use std::fmt;
+
+use crate::cpu::CPU;
+use crate::instructions::Isa;
+use crate::io::{play_keys, record_keys, stop_keys};
+use crate::json::Value;
+use crate::memory::Access;
+use crate::trace::{cond_name, destination};
+
+/*
+what one instruction did that another implementation has to agree on;
+registers when written and CC when it changed, as in a trace
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub struct Effect {
+    pub pc: u16,
+    pub inst: u16,
+    pub regs: Vec<(u16, u16)>,
+    pub writes: Vec<(u16, u16)>,
+    pub cc: Option<u16>,
+}
+
+impl Effect {
+    /* one line of a JSON trace, reads are ignored */
+    pub fn parse(line: &str) -> Option<Effect> {
+        let v = Value::parse(line)?;
+        let word = |v: &Value, key: &str| v.get(key).and_then(Value::as_i64).map(|n| n as u16);
+
+        let regs = v.get("regs")?.as_array()?.iter()
+            .map(|r| Some((word(r, "reg")?, word(r, "value")?)))
+            .collect::<Option<_>>()?;
+        let writes = v.get("mem")?.as_array()?.iter()
+            .filter(|m| m.get("op").and_then(Value::as_str) == Some("write"))
+            .map(|m| Some((word(m, "addr")?, word(m, "value")?)))
+            .collect::<Option<_>>()?;
+        let cc = match v.get("cc") {
+            Some(Value::Null) | None => None,
+            Some(cc) => Some(match cc.get("new")?.as_str()? {
+                "N" => 4,
+                "Z" => 2,
+                "P" => 1,
+                _ => 0,
+            }),
+        };
+
+        Some(Effect { pc: word(&v, "pc")?, inst: word(&v, "inst")?, regs, writes, cc })
+    }
+}
+
+/* the first instruction the two sides disagreed on, numbered from 0 */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Divergence {
+    pub index: u64,
+    pub expected: Option<Effect>,
+    pub actual: Option<Effect>,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (expected, actual) = match (&self.expected, &self.actual) {
+            (Some(e), Some(a)) => (e, a),
+            (Some(e), None) => return write!(f, "#{}: halted, the reference ran x{:04X}", self.index, e.pc),
+            (None, Some(a)) => return write!(f, "#{}: ran x{:04X}, the reference halted", self.index, a.pc),
+            (None, None) => return write!(f, "#{}: no divergence", self.index),
+        };
+
+        write!(f, "#{} x{:04X}:", self.index, expected.pc)?;
+        if expected.pc != actual.pc {
+            return write!(f, " PC x{:04X}, expected x{:04X}", actual.pc, expected.pc);
+        }
+        if expected.inst != actual.inst {
+            return write!(f, " fetched x{:04X}, expected x{:04X}", actual.inst, expected.inst);
+        }
+        if expected.regs != actual.regs {
+            let show = |regs: &[(u16, u16)]| regs.iter().map(|(r, v)| format!("R{}<-x{:04X}", r, v)).collect::<Vec<_>>().join(" ");
+            write!(f, " registers [{}], expected [{}]", show(&actual.regs), show(&expected.regs))?;
+        }
+        if expected.writes != actual.writes {
+            let show = |writes: &[(u16, u16)]| writes.iter().map(|(a, v)| format!("[x{:04X}]<-x{:04X}", a, v)).collect::<Vec<_>>().join(" ");
+            write!(f, " stores [{}], expected [{}]", show(&actual.writes), show(&expected.writes))?;
+        }
+        if expected.cc != actual.cc {
+            let show = |cc: Option<u16>| cc.map_or("unchanged", cond_name);
+            write!(f, " CC {}, expected {}", show(actual.cc), show(expected.cc))?;
+        }
+        Ok(())
+    }
+}
+
+impl CPU {
+    /* one step with its effect recorded, None if the machine has stopped */
+    pub fn step_effect(&mut self) -> Option<Effect> {
+        if !self.running {
+            return None;
+        }
+
+        let pc = self.pc as u16;
+        let inst = self.memory.memory[if self.isa == Isa::Lc3b { pc & 0xFFFE } else { pc } as usize];
+        let regs = self.registers();
+        let cond = self.rcond;
+
+        let logging = self.memory.logging;
+        let start = self.memory.accesses.len();
+        self.memory.logging = true;
+        self.step();
+        self.memory.logging = logging;
+
+        let dest = destination(self.isa, inst);
+        let regs = self.registers().iter().zip(regs.iter()).enumerate()
+            .filter(|(r, (new, old))| new != old || dest == Some(*r as u16))
+            .map(|(r, (new, _))| (r as u16, *new))
+            .collect();
+        let writes = self.memory.accesses[start..].iter()
+            .filter_map(|access| match *access {
+                Access::Write(addr, val) => Some((addr as u16, val)),
+                Access::Read(..) => None,
+            })
+            .collect();
+        if !logging {
+            self.memory.accesses.truncate(start);
+        }
+
+        Some(Effect { pc, inst, regs, writes, cc: (cond != self.rcond).then_some(self.rcond) })
+    }
+
+    /*
+    a second machine with the same memory and architectural state, to run
+    against this one; nothing else (devices, checkers, symbols) is copied,
+    and lockstep hands it the keys this one reads
+    */
+    pub fn fork(&self) -> CPU {
+        let mut other = CPU::new();
+        other.memory.memory = self.memory.memory;
+        other.rr0 = self.rr0;
+        other.rr1 = self.rr1;
+        other.rr2 = self.rr2;
+        other.rr3 = self.rr3;
+        other.rr4 = self.rr4;
+        other.rr5 = self.rr5;
+        other.rr6 = self.rr6;
+        other.rr7 = self.rr7;
+        other.pc = self.pc;
+        other.set_psr(self.psr());
+        other.saved_ssp = self.saved_ssp;
+        other.saved_usp = self.saved_usp;
+        other.relaxed = self.relaxed;
+        other.isa = self.isa;
+        other.running = self.running;
+        other
+    }
+
+    /*
+    steps this machine and `reference` together, up to `limit`
+    instructions, stopping at the first instruction they disagree on; this
+    one gets the same keys the reference read
+    */
+    pub fn lockstep(&mut self, reference: &mut CPU, limit: u64) -> Option<Divergence> {
+        for index in 0..limit {
+            record_keys();
+            let expected = reference.step_effect();
+            play_keys();
+            let actual = self.step_effect();
+            stop_keys();
+            if expected != actual {
+                return Some(Divergence { index, expected, actual });
+            }
+            if actual.is_none() {
+                break;
+            }
+        }
+        None
+    }
+
+    /*
+    runs against a JSON trace of the reference, one record per line, until
+    the trace ends; a record that does not parse is an error, not a match
+    */
+    pub fn replay(&mut self, trace: &str) -> Result<Option<Divergence>, String> {
+        let lines = trace.lines().filter(|l| !l.trim().is_empty());
+        for (index, line) in lines.enumerate() {
+            let Some(expected) = Effect::parse(line) else {
+                return Err(format!("bad trace record #{}", index));
+            };
+            let actual = self.step_effect();
+            if actual.as_ref() != Some(&expected) {
+                return Ok(Some(Divergence { index: index as u64, expected: Some(expected), actual }));
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::redirect_console;
+    use crate::trace::{JsonTrace, TraceBuffer, Tracer};
+
+    fn program(words: &[u16]) -> CPU {
+        let mut cpu = CPU::new();
+        let obj: Vec<u8> = std::iter::once(0x3000).chain(words.iter().copied()).flat_map(|w: u16| w.to_be_bytes()).collect();
+        cpu.load_image(&obj);
+        cpu
+    }
+
+    #[test]
+    fn ldr_wraps_past_xffff() {
+        /* LDR R0, R1, #1 */
+        let mut cpu = program(&[0x6041]);
+        cpu.rr1 = 0xFFFF;
+        cpu.memory.memory[0x0000] = 0x1234;
+        let effect = cpu.step_effect().unwrap();
+        assert_eq!(effect.regs, vec![(0, 0x1234)]);
+        assert_eq!(effect.cc, Some(1));
+    }
+
+    #[test]
+    fn st_stores_its_source_register() {
+        /* ST R3, +1 */
+        let mut cpu = program(&[0x3601]);
+        cpu.rr0 = 0x0001;
+        cpu.rr3 = 0x00AB;
+        let effect = cpu.step_effect().unwrap();
+        assert_eq!(effect.writes, vec![(0x3002, 0x00AB)]);
+        assert!(effect.regs.is_empty());
+    }
+
+    #[test]
+    fn step_effect_leaves_an_outer_access_log_alone() {
+        /* ST R0, +1 */
+        let mut cpu = program(&[0x3001]);
+        cpu.memory.logging = true;
+        cpu.memory.accesses.push(Access::Read(0x4000, 1));
+        cpu.step_effect();
+        assert!(cpu.memory.logging);
+        assert_eq!(cpu.memory.accesses, vec![Access::Read(0x4000, 1), Access::Read(0x3000, 0x3001), Access::Write(0x3002, 0)]);
+    }
+
+    #[test]
+    fn lockstep_shares_keys_and_finds_the_first_difference() {
+        let mut keys = b"ab".iter().copied();
+        redirect_console(Box::new(|_| {}), Box::new(move || keys.next().unwrap_or(0)));
+
+        /* GETC; ADD R1, R0, #1; HALT */
+        let mut reference = program(&[0xF020, 0x1221, 0xF025]);
+        let mut cpu = reference.fork();
+        assert_eq!(cpu.lockstep(&mut reference, 10), None);
+        assert_eq!((cpu.rr0, reference.rr0), (b'a' as u16, b'a' as u16));
+
+        /* ADD R1, R0, #2 in the copy */
+        let mut reference = program(&[0xF020, 0x1221, 0xF025]);
+        let mut cpu = reference.fork();
+        cpu.memory.memory[0x3001] = 0x1222;
+        let divergence = cpu.lockstep(&mut reference, 10).unwrap();
+        assert_eq!(divergence.index, 1);
+        assert_eq!(divergence.to_string(), "#1 x3001: fetched x1222, expected x1221");
+    }
+
+    #[test]
+    fn a_recorded_trace_replays_and_bad_records_are_errors() {
+        /* AND R0, R0, #0; ADD R0, R0, #3; ST R0, +1; HALT */
+        let words = [0x5020, 0x1023, 0x3001, 0xF025];
+        let buffer = TraceBuffer::default();
+        let mut reference = program(&words);
+        reference.tracer = Some(Tracer::new(Box::new(JsonTrace::new(buffer.clone()))));
+        reference.run();
+        let trace = buffer.take();
+
+        /* run() starts from Z, so the replaying CPU does too */
+        let fresh = || {
+            let mut cpu = program(&words);
+            cpu.rcond = 2;
+            cpu
+        };
+        assert_eq!(fresh().replay(&trace), Ok(None));
+
+        let mut cpu = fresh();
+        cpu.memory.memory[0x3001] = 0x1024;
+        let divergence = cpu.replay(&trace).unwrap().unwrap();
+        assert_eq!(divergence.index, 1);
+        assert_eq!(divergence.to_string(), "#1 x3001: fetched x1024, expected x1023");
+
+        assert_eq!(program(&words).replay("{\"pc\": 12288}\n"), Err("bad trace record #0".to_string()));
+    }
+}
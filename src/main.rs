@@ -0,0 +1,151 @@
+use std::path::Path;
+use std::process::exit;
+
+use lc3_core::cpu::CPU;
+#[cfg(not(target_arch = "wasm32"))]
+use lc3_core::dap::DapServer;
+#[cfg(not(target_arch = "wasm32"))]
+use lc3_core::gdbstub::GdbStub;
+use lc3_core::lint::report;
+use lc3_core::profiler::Profiler;
+
+/*
+lc3 [options] program.obj
+lc3 --dap
+
+runs a program with the console on stdin/stdout, reports go to stderr once
+it halts; the .sym and .lst next to the program are loaded when there are
+any. With --dap stdin/stdout carry the Debug Adapter Protocol and the
+program comes from the launch request.
+*/
+const USAGE: &str = "usage: lc3 [--sym FILE] [--lst FILE] [--user] [--profile | --lint | --gdb PORT] program.obj
+       lc3 --dap
+
+  --sym FILE    labels for reports, instead of the .sym next to the program
+  --lst FILE    assembler listing, instead of the .lst next to the program
+  --user        start in user mode, where system space and devices raise ACV
+  --profile     hot spots, subroutines and the call graph
+  --lint        check the program without running it, exits 1 on warnings
+  --gdb PORT    wait for gdb on 127.0.0.1:PORT and run under its control
+  --dap         serve one debug session to an editor over stdin/stdout";
+
+struct Options {
+    program: String,
+    symbols: Option<String>,
+    listing: Option<String>,
+    user: bool,
+    profile: bool,
+    lint: bool,
+    gdb: Option<u16>,
+    dap: bool,
+}
+
+fn parse_args() -> Result<Options, String> {
+    let mut options = Options { program: String::new(), symbols: None, listing: None, user: false, profile: false, lint: false, gdb: None, dap: false };
+    let mut args = std::env::args().skip(1);
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--sym" => options.symbols = Some(args.next().ok_or("--sym needs a file")?),
+            "--lst" => options.listing = Some(args.next().ok_or("--lst needs a file")?),
+            "--user" => options.user = true,
+            "--profile" => options.profile = true,
+            "--lint" => options.lint = true,
+            "--dap" => options.dap = true,
+            "--gdb" => options.gdb = Some(args.next().and_then(|p| p.parse().ok()).ok_or("--gdb needs a port")?),
+            "-h" | "--help" => return Err(String::new()),
+            _ if arg.starts_with('-') => return Err(format!("unknown option {}", arg)),
+            _ if options.program.is_empty() => options.program = arg,
+            _ => return Err(format!("unexpected argument {}", arg)),
+        }
+    }
+
+    if options.program.is_empty() && !options.dap {
+        return Err("no program given".to_string());
+    }
+    Ok(options)
+}
+
+/* the named file, or the one next to the program with extension `ext` if there is one */
+fn sibling(program: &str, named: &Option<String>, ext: &str) -> Option<String> {
+    let path = named.clone().unwrap_or_else(|| Path::new(program).with_extension(ext).to_string_lossy().into_owned());
+    match std::fs::read_to_string(&path) {
+        Ok(text) => Some(text),
+        Err(e) => {
+            if named.is_some() {
+                eprintln!("{}: {}", path, e);
+            }
+            None
+        },
+    }
+}
+
+fn main() {
+    let options = match parse_args() {
+        Ok(options) => options,
+        Err(e) => {
+            if !e.is_empty() {
+                eprintln!("{}", e);
+            }
+            eprintln!("{}", USAGE);
+            exit(2);
+        },
+    };
+
+    #[cfg(not(target_arch = "wasm32"))]
+    if options.dap {
+        if let Err(e) = DapServer::new().run_stdio(&mut CPU::new()) {
+            eprintln!("dap: {}", e);
+            exit(1);
+        }
+        return;
+    }
+
+    let image = match std::fs::read(&options.program) {
+        Ok(image) => image,
+        Err(e) => {
+            eprintln!("{}: {}", options.program, e);
+            exit(1);
+        },
+    };
+
+    let mut cpu = CPU::new();
+    if !cpu.load_any(&image) {
+        eprintln!("{}: not a program image", options.program);
+        exit(1);
+    }
+
+    if let Some(text) = sibling(&options.program, &options.symbols, "sym") {
+        cpu.load_symbols(&text);
+    }
+    if let Some(text) = sibling(&options.program, &options.listing, "lst") {
+        let source = Path::new(&options.program).with_extension("asm");
+        cpu.load_listing(&source.to_string_lossy(), &text);
+    }
+
+    if options.lint {
+        let lints = cpu.lint();
+        print!("{}", report(&lints));
+        exit(if lints.is_empty() { 0 } else { 1 });
+    }
+
+    cpu.user = options.user;
+    if options.profile {
+        cpu.profiler = Some(Profiler::new());
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    if let Some(port) = options.gdb {
+        if let Err(e) = GdbStub::new().listen(&mut cpu, port) {
+            eprintln!("gdb: {}", e);
+            exit(1);
+        }
+        return;
+    }
+
+    cpu.run();
+
+    if let Some(profiler) = cpu.profiler.as_ref() {
+        eprint!("\n{}", profiler.report(&cpu.symbols));
+    }
+}
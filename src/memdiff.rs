@@ -0,0 +1,132 @@
+use crate::cpu::CPU;
+use crate::disassembler::disassembly_for;
+use crate::image::Image;
+use crate::instructions::Isa;
+use crate::symbols::SymbolTable;
+
+/* a copy of all of memory, from a running CPU or an image file */
+#[derive(Clone, PartialEq)]
+pub struct Snapshot {
+    pub memory: Vec<u16>,
+}
+
+impl Snapshot {
+    /* what loading the image into zeroed memory would give */
+    pub fn from_image(image: &Image, isa: Isa) -> Snapshot {
+        let stride = if isa == Isa::Lc3b { 2 } else { 1 };
+        let mut memory = vec![0; 1 << 16];
+        for segment in image.segments.iter() {
+            for (i, &word) in segment.words.iter().enumerate() {
+                memory[(segment.origin as usize + i * stride) & 0xFFFF] = word;
+            }
+        }
+        Snapshot { memory }
+    }
+}
+
+/* one word that differs between two snapshots */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Change {
+    pub addr: u16,
+    pub before: u16,
+    pub after: u16,
+}
+
+/* words in start..=end that differ, lowest address first */
+pub fn diff(a: &Snapshot, b: &Snapshot, start: u16, end: u16) -> Vec<Change> {
+    (start as usize..=end as usize)
+        .filter(|&addr| a.memory[addr] != b.memory[addr])
+        .map(|addr| Change { addr: addr as u16, before: a.memory[addr], after: b.memory[addr] })
+        .collect()
+}
+
+/*
+one line per changed word:
+    RESULT+2     x3105  x0000 -> x002A
+*/
+pub fn report(changes: &[Change], symbols: &SymbolTable) -> String {
+    let mut out = String::new();
+    for change in changes {
+        out += &format!("{:<12} x{:04X}  x{:04X} -> x{:04X}\n",
+            symbols.describe(change.addr), change.addr, change.before, change.after);
+    }
+    out
+}
+
+impl CPU {
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot { memory: self.memory.memory.to_vec() }
+    }
+
+    /* start..=end with labels and disassembly, data lines keep their source text when there is debug info */
+    pub fn annotated_dump(&self, start: u16, end: u16) -> String {
+        let stride = if self.isa == Isa::Lc3b { 2 } else { 1 };
+        let mut out = String::new();
+
+        for addr in (start as usize..=end as usize).step_by(stride) {
+            let word = self.memory.memory[addr];
+            let label = self.symbols.name(addr as u16).unwrap_or("");
+            let text = match self.debug.line(addr as u16) {
+                Some(src) => src.text.trim().to_string(),
+                None => disassembly_for(self.isa, word),
+            };
+            let line = format!("x{:04X}  x{:04X}  {:<12} {}", addr, word, label, text);
+            out += line.trim_end();
+            out.push('\n');
+        }
+
+        out
+    }
+
+    /* what changed since `before` was taken, with symbolic addresses */
+    pub fn diff_since(&self, before: &Snapshot, start: u16, end: u16) -> String {
+        report(&diff(before, &self.snapshot(), start, end), &self.symbols)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::Segment;
+
+    fn program(words: &[u16]) -> CPU {
+        let mut cpu = CPU::new();
+        let obj: Vec<u8> = std::iter::once(0x3000).chain(words.iter().copied()).flat_map(|w: u16| w.to_be_bytes()).collect();
+        cpu.load_image(&obj);
+        cpu
+    }
+
+    #[test]
+    fn stores_show_up_against_a_snapshot_with_labels() {
+        /* ST R0, RESULT+1; HALT; RESULT .BLKW 2 */
+        let mut cpu = program(&[0x3002, 0xF025, 0x0000, 0x0000]);
+        cpu.load_symbols("// RESULT 3002\n");
+        cpu.rr0 = 0x002A;
+        let before = cpu.snapshot();
+        cpu.run();
+
+        assert_eq!(diff(&before, &cpu.snapshot(), 0x3000, 0x3003), vec![Change { addr: 0x3003, before: 0, after: 0x002A }]);
+        assert_eq!(cpu.diff_since(&before, 0x3000, 0x3003), format!("{:<12} x3003  x0000 -> x002A\n", "RESULT+1"));
+        /* outside the range nothing is reported */
+        assert_eq!(cpu.diff_since(&before, 0x3000, 0x3002), "");
+    }
+
+    #[test]
+    fn images_compare_against_memory() {
+        let image = Image { segments: vec![Segment { origin: 0x3000, words: vec![0xF025, 7] }] };
+        let cpu = program(&[0xF025, 8]);
+        let changes = diff(&Snapshot::from_image(&image, Isa::Lc3), &cpu.snapshot(), 0x3000, 0x3001);
+        assert_eq!(changes, vec![Change { addr: 0x3001, before: 7, after: 8 }]);
+
+        /* LC-3b words sit two addresses apart */
+        let lc3b = Snapshot::from_image(&image, Isa::Lc3b);
+        assert_eq!((lc3b.memory[0x3000], lc3b.memory[0x3001], lc3b.memory[0x3002]), (0xF025, 0, 7));
+    }
+
+    #[test]
+    fn annotated_dump_labels_and_disassembles() {
+        let mut cpu = program(&[0xF025]);
+        cpu.load_symbols("// MAIN 3000\n");
+        assert_eq!(cpu.annotated_dump(0x3000, 0x3000), format!("x3000  xF025  {:<12} {}\n", "MAIN", disassembly_for(Isa::Lc3, 0xF025)));
+    }
+}
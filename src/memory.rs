@@ -1,8 +1,14 @@
 use std::io::Read;
 
-use crate::io::get_key;
+use crate::io::poll_key;
+use crate::cache::{CodeWatch, DecodeCache};
+use crate::selfmod::CodeCheck;
+use crate::shadow::Shadow;
 const memory_max: usize = 1 << 16;
 
+/* devices live at or above this address */
+pub const DEVICE_START: usize = 0xFE00;
+
 pub enum MemoryMappedReg {
     // Keyboard status
     kbsr = 0xFE00,
@@ -11,14 +17,73 @@ pub enum MemoryMappedReg {
     kbdr = 0xFE02,
 }
 
+/* an interrupt a device wants taken */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct InterruptRequest {
+    /* offset into the interrupt vector table at x0100 */
+    pub vector: u8,
+    /* taken only when above the running priority, 0-7 */
+    pub priority: u8,
+}
+
+/*
+something on the memory bus; it answers reads and writes for the addresses
+it claims and gets a tick for every retired instruction
+*/
+pub trait Device {
+    /* lowest address claimed, anything below it never reaches the device */
+    fn start(&self) -> usize {
+        DEVICE_START
+    }
+    fn claims(&self, addr: usize) -> bool;
+    /* false for devices that never tick or interrupt, so the fast paths stay on */
+    fn clocked(&self) -> bool {
+        true
+    }
+    fn read(&mut self, addr: usize) -> u16;
+    fn write(&mut self, addr: usize, val: u16);
+    fn tick(&mut self) {}
+    fn interrupt(&self) -> Option<InterruptRequest> {
+        None
+    }
+    /* the CPU has taken the interrupt this device was requesting */
+    fn acknowledge(&mut self) {}
+}
+
+/* a data access made by an instruction, recorded while tracing */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Access {
+    Read(usize, u16),
+    Write(usize, u16),
+}
+
 pub struct Mem {
-    pub memory: [u16; memory_max]
+    pub memory: [u16; memory_max],
+    pub accesses: Vec<Access>,
+    pub logging: bool,
+    pub cache: DecodeCache,
+    pub watch: CodeWatch,
+    pub devices: Vec<Box<dyn Device>>,
+    /* lowest address any device claims */
+    pub device_floor: usize,
+    /* which words have been written, when checking for uninitialised reads */
+    pub shadow: Option<Shadow>,
+    /* code or data origin of each word, when checking for self-modifying code */
+    pub code: Option<CodeCheck>
 }
 
 impl Mem {
     pub fn new() -> Mem {
         Mem { 
-            memory: [0; memory_max]
+            memory: [0; memory_max],
+            accesses: Vec::new(),
+            logging: false,
+            cache: DecodeCache::new(),
+            watch: CodeWatch::new(),
+            devices: Vec::new(),
+            device_floor: memory_max,
+            shadow: None,
+            code: None
         }
     }
 
@@ -26,21 +91,116 @@ impl Mem {
         if addr == MemoryMappedReg::kbsr as usize {
             self.keyboard();
         }
-        self.memory[addr]
+        if addr >= self.device_floor {
+            if let Some(device) = self.devices.iter_mut().find(|d| d.claims(addr)) {
+                /* mirrored into memory so views of the device page stay current */
+                let val = device.read(addr);
+                self.mirror(addr, val);
+            }
+        }
+        if let Some(shadow) = self.shadow.as_mut() {
+            shadow.check(addr);
+        }
+        let val = self.memory[addr];
+        if self.logging {
+            self.accesses.push(Access::Read(addr, val));
+        }
+        val
     }
 
     pub fn write(&mut self, addr: usize, val: u16) {
+        if self.logging {
+            self.accesses.push(Access::Write(addr, val));
+        }
+        self.cache.invalidate(addr);
+        self.watch.store(addr);
+        if let Some(shadow) = self.shadow.as_mut() {
+            shadow.mark(addr);
+        }
+        if let Some(code) = self.code.as_mut() {
+            code.store(addr);
+        }
         self.memory[addr] = val;
+        if addr >= self.device_floor {
+            if let Some(device) = self.devices.iter_mut().find(|d| d.claims(addr)) {
+                device.write(addr, val);
+            }
+        }
+    }
+
+    /* a word the loader put straight into memory, passed on to whatever device claims it */
+    pub(crate) fn loaded(&mut self, addr: usize) {
+        if addr >= self.device_floor {
+            let val = self.memory[addr];
+            if let Some(device) = self.devices.iter_mut().find(|d| d.claims(addr)) {
+                device.write(addr, val);
+            }
+        }
+    }
+
+    pub fn attach(&mut self, device: Box<dyn Device>) {
+        self.device_floor = self.device_floor.min(device.start());
+        self.devices.push(device);
+    }
+
+    /* true if some device needs ticks and interrupt polling every instruction */
+    pub fn clocked(&self) -> bool {
+        self.devices.iter().any(|d| d.clocked())
+    }
+
+    /* advances every device by one instruction */
+    pub fn tick(&mut self) {
+        for device in self.devices.iter_mut() {
+            device.tick();
+        }
+    }
+
+    /* the highest priority request, with the index of the device making it */
+    pub fn interrupt(&self) -> Option<(usize, InterruptRequest)> {
+        self.devices.iter().enumerate()
+            .filter_map(|(i, d)| d.interrupt().map(|req| (i, req)))
+            .max_by_key(|(_, req)| req.priority)
+    }
+
+    /* device state copied into memory, past the checkers but not the decode cache */
+    fn mirror(&mut self, addr: usize, val: u16) {
+        self.cache.invalidate(addr);
+        self.memory[addr] = val;
+    }
+
+    /*
+    LC-3b byte access; the word at an even byte address `a` is kept at
+    memory[a], low byte first, so device registers keep their addresses
+    */
+    pub fn read_byte(&mut self, addr: usize) -> u8 {
+        let word = self.read(addr & !1);
+        if addr & 1 == 0 { word as u8 } else { (word >> 8) as u8 }
+    }
+
+    pub fn write_byte(&mut self, addr: usize, val: u8) {
+        let word = self.memory[addr & !1];
+        let word = if addr & 1 == 0 {
+            (word & 0xFF00) | val as u16
+        } else {
+            (word & 0x00FF) | ((val as u16) << 8)
+        };
+        self.write(addr & !1, word);
+    }
+
+    /* a byte without touching devices, for debuggers */
+    pub fn peek_byte(&self, addr: usize) -> u8 {
+        let word = self.memory[addr & 0xFFFE];
+        if addr & 1 == 0 { word as u8 } else { (word >> 8) as u8 }
     }
 
     fn keyboard(&mut self) {
-        let ch = get_key();
+        let ch = poll_key();
 
         if ch != 0 {
-            self.write(MemoryMappedReg::kbsr as usize, 1 << 15);
-            self.write(MemoryMappedReg::kbdr as usize, ch as u16);
+            self.mirror(MemoryMappedReg::kbsr as usize, 1 << 15);
+            self.mirror(MemoryMappedReg::kbdr as usize, ch as u16);
         } else {
-            self.write(MemoryMappedReg::kbsr as usize, 0)
+            self.mirror(MemoryMappedReg::kbsr as usize, 0);
         }
     }
 }
\ No newline at end of file
@@ -0,0 +1,489 @@
+use crate::cpu::CPU;
+use crate::disassembler::decode;
+use crate::instructions::Flags;
+
+/* clocks a memory access waits for R before it completes */
+pub const MEMORY_LATENCY: u32 = 4;
+
+/* the fetch state every instruction starts from */
+pub const FETCH: u8 = 18;
+
+/* control signals asserted during one clock, named after the LC-3 control store */
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Signals {
+    pub ld_mar: bool,
+    pub ld_mdr: bool,
+    pub ld_ir: bool,
+    pub ld_ben: bool,
+    pub ld_reg: bool,
+    pub ld_cc: bool,
+    pub ld_pc: bool,
+    pub gate_pc: bool,
+    pub gate_mdr: bool,
+    pub gate_alu: bool,
+    pub gate_marmux: bool,
+    pub mio_en: bool,
+    /* R.W, true for a write */
+    pub r_w: bool,
+    /* whatever was gated onto the bus this clock */
+    pub bus: Option<u16>,
+}
+
+impl Signals {
+    /* names of the asserted signals, in control store order */
+    pub fn asserted(&self) -> Vec<&'static str> {
+        [
+            (self.ld_mar, "LD.MAR"),
+            (self.ld_mdr, "LD.MDR"),
+            (self.ld_ir, "LD.IR"),
+            (self.ld_ben, "LD.BEN"),
+            (self.ld_reg, "LD.REG"),
+            (self.ld_cc, "LD.CC"),
+            (self.ld_pc, "LD.PC"),
+            (self.gate_pc, "GatePC"),
+            (self.gate_mdr, "GateMDR"),
+            (self.gate_alu, "GateALU"),
+            (self.gate_marmux, "GateMARMUX"),
+            (self.mio_en, "MIO.EN"),
+            (self.r_w, "R.W"),
+        ].iter().filter(|(on, _)| *on).map(|(_, name)| *name).collect()
+    }
+}
+
+/* what happened on one clock edge */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Clock {
+    pub state: u8,
+    pub next: u8,
+    /* false while a memory state is still waiting on R */
+    pub ready: bool,
+    pub signals: Signals,
+}
+
+/*
+runs instructions through the state machine from Patt & Patel appendix C,
+one state per clock; architectural registers stay on the CPU, the
+microarchitectural ones (MAR, MDR, IR, BEN) live here
+*/
+pub struct Microarch {
+    pub state: u8,
+    pub mar: u16,
+    pub mdr: u16,
+    pub ir: u16,
+    pub ben: bool,
+    pub cycles: u64,
+    pub latency: u32,
+    /* clocks left before the current memory access is ready */
+    pending: Option<u32>,
+    pub last: Option<Clock>,
+}
+
+impl Microarch {
+    pub fn new(latency: u32) -> Microarch {
+        Microarch {
+            state: FETCH,
+            mar: 0,
+            mdr: 0,
+            ir: 0,
+            ben: false,
+            cycles: 0,
+            latency,
+            pending: None,
+            last: None,
+        }
+    }
+
+    /* one clock: does the work of the current state and moves to the next */
+    pub fn clock(&mut self, cpu: &mut CPU) -> Clock {
+        let state = self.state;
+        let mut s = Signals::default();
+        let mut ready = true;
+
+        let d = decode(self.ir);
+        let dr = (self.ir >> 9) & 0x7;
+        let sr1 = (self.ir >> 6) & 0x7;
+        let pc = cpu.pc as u16;
+
+        let next = match state {
+            /* MAR <- PC, PC <- PC + 1 */
+            18 | 19 => {
+                self.mar = pc;
+                cpu.pc = pc.wrapping_add(1) as usize;
+                s.ld_mar = true;
+                s.ld_pc = true;
+                s.gate_pc = true;
+                s.bus = Some(pc);
+                33
+            },
+            /* MDR <- M */
+            33 => {
+                ready = self.read(cpu, &mut s);
+                if ready { 35 } else { 33 }
+            },
+            /* IR <- MDR */
+            35 => {
+                self.ir = self.mdr;
+                s.ld_ir = true;
+                s.gate_mdr = true;
+                s.bus = Some(self.mdr);
+                32
+            },
+            /* BEN <- IR[11]&N + IR[10]&Z + IR[9]&P, then decode on IR[15:12] */
+            32 => {
+                self.ben = (self.ir >> 9) & cpu.rcond & 0x7 != 0;
+                s.ld_ben = true;
+                (self.ir >> 12) as u8
+            },
+
+            /* ADD, AND */
+            1 | 5 => {
+                let a = *cpu.get_reg(sr1);
+                let b = if d.flag { d.imm } else { *cpu.get_reg(d.r2) };
+                let val = if state == 1 { a.wrapping_add(b) } else { a & b };
+                self.load_reg(cpu, &mut s, dr, val, true);
+                s.gate_alu = true;
+                FETCH
+            },
+            /* NOT */
+            9 => {
+                let val = !*cpu.get_reg(sr1);
+                self.load_reg(cpu, &mut s, dr, val, true);
+                s.gate_alu = true;
+                FETCH
+            },
+            /* LEA, DR <- PC + off9 */
+            14 => {
+                let val = pc.wrapping_add(d.imm);
+                self.load_reg(cpu, &mut s, dr, val, true);
+                s.gate_marmux = true;
+                FETCH
+            },
+
+            /* LD, LDI, ST, STI: MAR <- PC + off9 */
+            2 | 3 | 10 | 11 => {
+                self.load_mar(&mut s, pc.wrapping_add(d.imm));
+                s.gate_marmux = true;
+                match state {
+                    2 => 25,
+                    3 => 23,
+                    10 => 24,
+                    _ => 29,
+                }
+            },
+            /* LDR, STR: MAR <- BaseR + off6 */
+            6 | 7 => {
+                let base = *cpu.get_reg(sr1);
+                self.load_mar(&mut s, base.wrapping_add(d.imm));
+                s.gate_marmux = true;
+                if state == 7 {
+                    cpu.stack_store(sr1, self.mar);
+                }
+                if state == 6 { 25 } else { 23 }
+            },
+            /* LDI, STI: MDR <- M, the pointer */
+            24 | 29 => {
+                ready = self.read(cpu, &mut s);
+                match (ready, state) {
+                    (false, _) => state,
+                    (true, 24) => 26,
+                    (true, _) => 31,
+                }
+            },
+            /* MAR <- MDR */
+            26 | 31 => {
+                self.load_mar(&mut s, self.mdr);
+                s.gate_mdr = true;
+                if state == 26 { 25 } else { 23 }
+            },
+            /* MDR <- M */
+            25 => {
+                ready = self.read(cpu, &mut s);
+                if ready { 27 } else { 25 }
+            },
+            /* DR <- MDR, set CC */
+            27 => {
+                let val = self.mdr;
+                self.load_reg(cpu, &mut s, dr, val, true);
+                s.gate_mdr = true;
+                FETCH
+            },
+            /* MDR <- SR */
+            23 => {
+                self.mdr = *cpu.get_reg(dr);
+                s.ld_mdr = true;
+                s.gate_alu = true;
+                s.bus = Some(self.mdr);
+                16
+            },
+            /* M[MAR] <- MDR */
+            16 => {
+                s.mio_en = true;
+                s.r_w = true;
+                ready = self.wait();
+                if ready {
+                    cpu.memory.write(self.mar as usize, self.mdr);
+                    FETCH
+                } else {
+                    16
+                }
+            },
+
+            /* BR, taken when BEN */
+            0 => {
+                if let Some(coverage) = cpu.coverage.as_mut() {
+                    coverage.branch(pc.wrapping_sub(1), self.ben);
+                }
+                if self.ben { 22 } else { FETCH }
+            },
+            /* PC <- PC + off9 */
+            22 => {
+                self.load_pc(cpu, &mut s, pc.wrapping_add(d.imm));
+                FETCH
+            },
+            /* JMP, RET: PC <- BaseR */
+            12 => {
+                let target = *cpu.get_reg(sr1);
+                if sr1 == 7 {
+                    cpu.calls.ret(pc.wrapping_sub(1), target);
+                }
+                self.load_pc(cpu, &mut s, target);
+                FETCH
+            },
+            /* JSR, JSRR on IR[11] */
+            4 => {
+                if d.flag { 21 } else { 20 }
+            },
+            /* R7 <- PC, PC <- PC + off11 or BaseR */
+            20 | 21 => {
+                let target = if state == 21 { pc.wrapping_add(d.imm) } else { *cpu.get_reg(sr1) };
+                self.load_reg(cpu, &mut s, 7, pc, false);
+                s.gate_pc = true;
+                self.load_pc(cpu, &mut s, target);
+                cpu.calls.push(crate::callstack::FrameKind::Call, pc.wrapping_sub(1), target, pc);
+                FETCH
+            },
+
+            /*
+            TRAP, RTI and the reserved opcode go through the supervisor
+            stack, which has no states here; the whole instruction runs as it
+            does outside this mode, handlers, trap table and exceptions alike
+            */
+            8 | 13 | 15 => {
+                cpu.dispatch(d);
+                FETCH
+            },
+
+            /* not a state of the control store */
+            _ => {
+                eprintln!("No microcode for state {} (IR x{:04X})", state, self.ir);
+                cpu.running = false;
+                FETCH
+            },
+        };
+
+        self.state = next;
+        self.cycles += 1;
+
+        let clock = Clock { state, next, ready, signals: s };
+        self.last = Some(clock);
+        clock
+    }
+
+    /* counts down the memory latency, true once R is asserted */
+    fn wait(&mut self) -> bool {
+        let left = self.pending.get_or_insert(self.latency);
+        if *left == 0 {
+            self.pending = None;
+            true
+        } else {
+            *left -= 1;
+            false
+        }
+    }
+
+    fn read(&mut self, cpu: &mut CPU, s: &mut Signals) -> bool {
+        s.mio_en = true;
+        if !self.wait() {
+            return false;
+        }
+        self.mdr = cpu.memory.read(self.mar as usize);
+        s.ld_mdr = true;
+        true
+    }
+
+    fn load_mar(&mut self, s: &mut Signals, addr: u16) {
+        self.mar = addr;
+        s.ld_mar = true;
+        s.bus = Some(addr);
+    }
+
+    /* through PCMUX, the bus only carries the new PC for TRAP */
+    fn load_pc(&mut self, cpu: &mut CPU, s: &mut Signals, addr: u16) {
+        cpu.pc = addr as usize;
+        s.ld_pc = true;
+    }
+
+    fn load_reg(&mut self, cpu: &mut CPU, s: &mut Signals, r: u16, val: u16, set_cc: bool) {
+        cpu.set_register(r, val);
+        s.ld_reg = true;
+        s.bus = Some(val);
+
+        if set_cc {
+            cpu.rcond = if val == 0 {
+                Flags::FlZro.value()
+            } else if val >> 15 != 0 {
+                Flags::FlNeg.value()
+            } else {
+                Flags::FlPos.value()
+            };
+            s.ld_cc = true;
+        }
+    }
+}
+
+/* register transfer text for each state, for displays */
+pub fn describe(state: u8) -> &'static str {
+    match state {
+        18 | 19 => "MAR<-PC, PC<-PC+1",
+        33 => "MDR<-M",
+        35 => "IR<-MDR",
+        32 => "BEN<-IR[11]&N+IR[10]&Z+IR[9]&P, [IR[15:12]]",
+        1 => "ADD: DR<-SR1+OP2, set CC",
+        5 => "AND: DR<-SR1&OP2, set CC",
+        9 => "NOT: DR<-NOT(SR), set CC",
+        14 => "LEA: DR<-PC+off9, set CC",
+        2 => "LD: MAR<-PC+off9",
+        6 => "LDR: MAR<-B+off6",
+        10 => "LDI: MAR<-PC+off9",
+        11 => "STI: MAR<-PC+off9",
+        3 => "ST: MAR<-PC+off9",
+        7 => "STR: MAR<-B+off6",
+        24 | 29 | 25 => "MDR<-M[MAR]",
+        26 | 31 => "MAR<-MDR",
+        27 => "DR<-MDR, set CC",
+        23 => "MDR<-SR",
+        16 => "M[MAR]<-MDR",
+        0 => "BR: [BEN]",
+        22 => "PC<-PC+off9",
+        12 => "JMP: PC<-BaseR",
+        4 => "JSR: [IR[11]]",
+        21 => "R7<-PC, PC<-PC+off11",
+        20 => "R7<-PC, PC<-BaseR",
+        15 => "TRAP",
+        _ => "",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trace::{TextTrace, TraceBuffer, Tracer};
+
+    fn program(words: &[u16], latency: Option<u32>) -> CPU {
+        let mut cpu = CPU::new();
+        let obj: Vec<u8> = std::iter::once(0x3000).chain(words.iter().copied()).flat_map(|w: u16| w.to_be_bytes()).collect();
+        cpu.load_image(&obj);
+        cpu.micro = latency.map(Microarch::new);
+        cpu
+    }
+
+    /* loads, stores, ALU, a branch and a subroutine call, ending in HALT */
+    const PROGRAM: [u16; 17] = [
+        0x5020, /* AND R0, R0, #0 */
+        0x1025, /* ADD R0, R0, #5 */
+        0xE20C, /* LEA R1, DATA */
+        0x7041, /* STR R0, R1, #1 */
+        0x6640, /* LDR R3, R1, #0 */
+        0x3009, /* ST R0, DATA */
+        0x2A09, /* LD R5, DATA+1 */
+        0x903F, /* NOT R0, R0 */
+        0x1021, /* ADD R0, R0, #1 */
+        0x0801, /* BRn +1 */
+        0xF025, /* HALT, skipped */
+        0x4801, /* JSR +1 */
+        0xF025, /* HALT */
+        0x1DA1, /* ADD R6, R6, #1 */
+        0xC1C0, /* RET */
+        0x1234, /* DATA */
+        0x0000,
+    ];
+
+    #[test]
+    fn state_machine_agrees_with_the_interpreter() {
+        for latency in [1, MEMORY_LATENCY] {
+            let mut expected = program(&PROGRAM, None);
+            let mut actual = program(&PROGRAM, Some(latency));
+            while expected.running {
+                expected.step();
+                actual.step();
+                assert_eq!(actual.pc, expected.pc);
+                assert_eq!(actual.registers(), expected.registers());
+                assert_eq!(actual.rcond, expected.rcond);
+            }
+            assert!(!actual.running);
+            assert_eq!(actual.memory.memory[0x3000..0x3011], expected.memory.memory[0x3000..0x3011]);
+        }
+    }
+
+    #[test]
+    fn memory_states_wait_for_ready() {
+        let mut fast = program(&[0x6040, 0xF025], Some(1));
+        let mut slow = program(&[0x6040, 0xF025], Some(MEMORY_LATENCY));
+        fast.step();
+        slow.step();
+        let cycles = |cpu: &CPU| cpu.micro.as_ref().unwrap().cycles;
+        /* a fetch and a load, each waiting latency - 1 extra clocks */
+        assert_eq!(cycles(&slow) - cycles(&fast), 2 * (MEMORY_LATENCY as u64 - 1));
+    }
+
+    #[test]
+    fn clock_reports_the_fetch_states() {
+        let mut cpu = program(&[0x1021], Some(1));
+        let clock = cpu.clock().unwrap();
+        assert_eq!((clock.state, clock.next), (FETCH, 33));
+        assert_eq!(clock.signals.bus, Some(0x3000));
+        assert!(clock.signals.asserted().contains(&"LD.MAR"));
+        assert_eq!(cpu.pc, 0x3001);
+    }
+
+    #[test]
+    fn table_traps_and_exceptions_return_through_rti() {
+        let mut cpu = program(&[
+            0xF030, /* TRAP x30 */
+            0xD000, /* reserved */
+            0x1021, /* ADD R0, R0, #1 */
+            0xF025, /* HALT */
+            0x8000, /* RTI */
+        ], Some(1));
+        cpu.memory.memory[0x30] = 0x3004;
+        cpu.memory.memory[0x0101] = 0x3004;
+
+        cpu.step();
+        assert_eq!(cpu.pc, 0x3004);
+        cpu.step();
+        assert_eq!(cpu.pc, 0x3001);
+        cpu.step();
+        cpu.step();
+        assert_eq!(cpu.pc, 0x3002);
+        cpu.step();
+        assert!(cpu.running);
+        assert_eq!(cpu.rr0, 1);
+        assert_eq!(cpu.calls.depth(), 0);
+    }
+
+    #[test]
+    fn tracing_records_the_same_effects_as_the_interpreter() {
+        let trace = |latency| {
+            let buffer = TraceBuffer::default();
+            let mut cpu = program(&PROGRAM, latency);
+            cpu.tracer = Some(Tracer::new(Box::new(TextTrace::new(buffer.clone()))));
+            while cpu.running {
+                cpu.step();
+            }
+            buffer.take()
+        };
+        let expected = trace(None);
+        assert!(!expected.is_empty());
+        assert_eq!(trace(Some(1)), expected);
+    }
+}
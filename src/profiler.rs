@@ -0,0 +1,185 @@
+use std::collections::BTreeMap;
+
+use crate::instructions::OPCodes;
+use crate::symbols::SymbolTable;
+
+#[derive(Default, Clone)]
+pub struct Subroutine {
+    pub calls: u64,
+    /* instructions executed in the body itself */
+    pub exclusive: u64,
+    /* instructions executed in the body and everything it called */
+    pub inclusive: u64,
+}
+
+struct Frame {
+    entry: u16,
+    /* total instruction count when the frame was entered */
+    started: u64,
+}
+
+/* per-address execution counts grouped into JSR/JSRR ... RET subroutines */
+pub struct Profiler {
+    pub counts: Vec<u64>,
+    pub total: u64,
+    pub subroutines: BTreeMap<u16, Subroutine>,
+    /* (caller entry, callee entry) -> number of calls */
+    pub edges: BTreeMap<(u16, u16), u64>,
+    stack: Vec<Frame>,
+}
+
+impl Profiler {
+    pub fn new() -> Profiler {
+        Profiler {
+            counts: vec![0; 1 << 16],
+            total: 0,
+            subroutines: BTreeMap::new(),
+            edges: BTreeMap::new(),
+            stack: Vec::new(),
+        }
+    }
+
+    /* called after every instruction with its address and the PC it left behind */
+    pub fn record(&mut self, pc: u16, inst: u16, next_pc: u16) {
+        if self.stack.is_empty() {
+            /* the first instruction seen is the root of the call graph */
+            self.enter(pc, 0);
+        }
+
+        self.counts[pc as usize] += 1;
+        self.total += 1;
+
+        let current = self.stack.last().map(|f| f.entry).unwrap_or(pc);
+        self.subroutines.entry(current).or_default().exclusive += 1;
+
+        match OPCodes::from(inst >> 12) {
+            OPCodes::OpJsr => {
+                *self.edges.entry((current, next_pc)).or_insert(0) += 1;
+                self.enter(next_pc, self.total);
+            },
+            OPCodes::OpJmp if (inst >> 6) & 0x7 == 7 && self.stack.len() > 1 => {
+                let frame = self.stack.pop().unwrap();
+                /* a recursive call is already inside the outermost one's count */
+                if !self.stack.iter().any(|f| f.entry == frame.entry) {
+                    self.subroutines.entry(frame.entry).or_default().inclusive += self.total - frame.started;
+                }
+            },
+            _ => {}
+        }
+    }
+
+    fn enter(&mut self, entry: u16, started: u64) {
+        self.subroutines.entry(entry).or_default().calls += 1;
+        self.stack.push(Frame { entry, started });
+    }
+
+    /* inclusive counts including frames that have not returned yet, the outermost covers the rest */
+    pub fn inclusive(&self, entry: u16) -> u64 {
+        let open: u64 = self.stack.iter()
+            .find(|f| f.entry == entry)
+            .map(|f| self.total - f.started)
+            .unwrap_or(0);
+        self.subroutines.get(&entry).map(|s| s.inclusive).unwrap_or(0) + open
+    }
+
+    /* the `limit` most executed addresses, most frequent first */
+    pub fn hot_spots(&self, limit: usize) -> Vec<(u16, u64)> {
+        let mut hot: Vec<(u16, u64)> = self.counts.iter().enumerate()
+            .filter(|(_, &n)| n != 0)
+            .map(|(addr, &n)| (addr as u16, n))
+            .collect();
+        hot.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        hot.truncate(limit);
+        hot
+    }
+
+    pub fn report(&self, symbols: &SymbolTable) -> String {
+        let mut out = format!("{} instructions executed\n\nHot spots:\n", self.total);
+        let total = self.total.max(1) as f64;
+
+        for (addr, n) in self.hot_spots(20) {
+            out += &format!("  x{:04X} {:<20} {:>10} {:>6.2}%\n",
+                addr, symbols.describe(addr), n, n as f64 * 100.0 / total);
+        }
+
+        out += "\nSubroutines:\n";
+        out += &format!("  {:<26} {:>8} {:>10} {:>10}\n", "entry", "calls", "self", "total");
+        let mut subs: Vec<(&u16, &Subroutine)> = self.subroutines.iter().collect();
+        subs.sort_by_key(|s| std::cmp::Reverse(s.1.exclusive));
+        for (&entry, sub) in subs {
+            out += &format!("  x{:04X} {:<20} {:>8} {:>10} {:>10}\n",
+                entry, symbols.describe(entry), sub.calls, sub.exclusive, self.inclusive(entry));
+        }
+
+        out += "\nCall graph:\n";
+        for (&(caller, callee), n) in &self.edges {
+            out += &format!("  {} -> {} ({})\n", symbols.describe(caller), symbols.describe(callee), n);
+        }
+
+        out
+    }
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RET: u16 = 0xC1C0;
+    /* JSR, the target comes from the PC passed to record */
+    const JSR: u16 = 0x4800;
+    const ADD: u16 = 0x1000;
+
+    #[test]
+    fn recursion_is_counted_once() {
+        let mut profiler = Profiler::new();
+        profiler.record(0x3000, JSR, 0x3010);
+        profiler.record(0x3010, JSR, 0x3010);
+        profiler.record(0x3010, ADD, 0x3011);
+        profiler.record(0x3011, RET, 0x3011);
+        profiler.record(0x3011, RET, 0x3001);
+
+        let f = &profiler.subroutines[&0x3010];
+        assert_eq!(f.calls, 2);
+        assert_eq!(f.exclusive, 4);
+        assert_eq!(profiler.inclusive(0x3010), 4);
+        assert_eq!(profiler.edges[&(0x3000, 0x3010)], 1);
+        assert_eq!(profiler.edges[&(0x3010, 0x3010)], 1);
+    }
+
+    #[test]
+    fn open_frames_count_towards_inclusive() {
+        let mut profiler = Profiler::new();
+        profiler.record(0x3000, JSR, 0x3010);
+        profiler.record(0x3010, JSR, 0x3010);
+        profiler.record(0x3010, ADD, 0x3011);
+
+        assert_eq!(profiler.inclusive(0x3010), 2);
+        assert_eq!(profiler.inclusive(0x3000), 3);
+    }
+
+    #[test]
+    fn report_names_hot_spots_and_calls() {
+        let mut profiler = Profiler::new();
+        profiler.record(0x3000, JSR, 0x3010);
+        for _ in 0..3 {
+            profiler.record(0x3010, ADD, 0x3011);
+        }
+        profiler.record(0x3011, RET, 0x3001);
+
+        assert_eq!(profiler.hot_spots(1), vec![(0x3010, 3)]);
+
+        let mut symbols = SymbolTable::new();
+        symbols.insert("MAIN", 0x3000);
+        symbols.insert("COUNT", 0x3010);
+        let report = profiler.report(&symbols);
+        assert!(report.starts_with("5 instructions executed"));
+        assert!(report.contains("MAIN -> COUNT (1)"));
+        assert!(report.contains("x3011 COUNT+1"));
+    }
+}
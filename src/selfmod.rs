@@ -0,0 +1,247 @@
+use std::collections::BTreeSet;
+use std::fmt;
+
+use crate::cpu::CPU;
+use crate::events::Event;
+use crate::instructions::Isa;
+use crate::symbols::SymbolTable;
+
+/* where the word at an address came from */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Origin {
+    /* loaded without a listing line, or never loaded, and not yet executed */
+    Unknown,
+    /* an instruction in the listing, or executed */
+    Code,
+    /* loaded with the image from .FILL, .BLKW or .STRINGZ */
+    Data,
+    /* stored to while running */
+    Written,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum CodeProblem {
+    /* the PC reached a word loaded as data */
+    ExecutesData,
+    /* the PC reached a word the program stored */
+    ExecutesWritten,
+    /* a store hit a word that has already been executed */
+    ModifiesCode,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CodeEvent {
+    /* the instruction at fault, the one fetched or the one that stored */
+    pub pc: u16,
+    pub addr: u16,
+    pub problem: CodeProblem,
+}
+
+impl fmt::Display for CodeEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.problem {
+            CodeProblem::ExecutesData => write!(f, "x{:04X}: executing data", self.addr),
+            CodeProblem::ExecutesWritten => write!(f, "x{:04X}: executing a word stored at runtime", self.addr),
+            CodeProblem::ModifiesCode => write!(f, "x{:04X}: store to already executed x{:04X}", self.pc, self.addr),
+        }
+    }
+}
+
+/* per word origin and whether it has been executed, kept next to memory so every store is seen */
+pub struct CodeCheck {
+    pub stop: bool,
+    origins: Vec<Origin>,
+    executed: Vec<bool>,
+    /* executed words stored to during the current instruction */
+    modified: Vec<u16>,
+    pub events: Vec<CodeEvent>,
+    /* each (addr, problem) is only reported once */
+    seen: BTreeSet<(u16, CodeProblem)>,
+}
+
+impl CodeCheck {
+    pub fn new(stop: bool) -> CodeCheck {
+        CodeCheck {
+            stop,
+            origins: vec![Origin::Unknown; 1 << 16],
+            executed: vec![false; 1 << 16],
+            modified: Vec::new(),
+            events: Vec::new(),
+            seen: BTreeSet::new(),
+        }
+    }
+
+    pub fn origin(&self, addr: u16) -> Origin {
+        self.origins[addr as usize]
+    }
+
+    pub fn load(&mut self, addr: usize, origin: Origin) {
+        self.origins[addr] = origin;
+        self.executed[addr] = false;
+    }
+
+    pub fn store(&mut self, addr: usize) {
+        self.origins[addr] = Origin::Written;
+        if self.executed[addr] {
+            self.modified.push(addr as u16);
+        }
+    }
+
+    /* the event, if this is the first time the problem was seen at addr */
+    fn record(&mut self, pc: u16, addr: u16, problem: CodeProblem) -> Option<CodeEvent> {
+        if !self.seen.insert((addr, problem)) {
+            return None;
+        }
+        let event = CodeEvent { pc, addr, problem };
+        self.events.push(event);
+        Some(event)
+    }
+
+    pub fn report(&self, symbols: &SymbolTable) -> String {
+        let mut out = String::new();
+        for event in self.events.iter() {
+            out += &match event.problem {
+                CodeProblem::ExecutesData => format!("{}: executing data\n", symbols.describe(event.addr)),
+                CodeProblem::ExecutesWritten => format!("{}: executing a word stored at runtime\n", symbols.describe(event.addr)),
+                CodeProblem::ModifiesCode => format!("{}: store to already executed {}\n", symbols.describe(event.pc), symbols.describe(event.addr)),
+            };
+        }
+        out
+    }
+}
+
+impl CPU {
+    /*
+    starts telling code from data: the last loaded image is classified using
+    the debug info when there is some, and everything else is unknown until
+    it is executed or stored to; new problems go to subscribers as
+    Event::Code, and with `stop` the machine halts on each one
+    */
+    pub fn check_code(&mut self, stop: bool) {
+        self.memory.code = Some(CodeCheck::new(stop));
+        if let Some((start, end)) = self.image {
+            self.classify(start, end);
+        }
+    }
+
+    /* marks a freshly loaded range as code or data by its listing lines */
+    pub(crate) fn classify(&mut self, start: u16, end: u16) {
+        let stride = if self.isa == Isa::Lc3b { 2 } else { 1 };
+        let Some(code) = self.memory.code.as_mut() else { return };
+        for addr in (start as usize..=end as usize).step_by(stride) {
+            let origin = match self.debug.line(addr as u16) {
+                Some(line) if line.is_data() => Origin::Data,
+                Some(_) => Origin::Code,
+                None => Origin::Unknown,
+            };
+            code.load(addr, origin);
+        }
+    }
+
+    /* false, with the PC left on it, when about to execute data and stopping for it */
+    pub(crate) fn fetch_checked(&mut self, pc: u16) -> bool {
+        let Some(code) = self.memory.code.as_mut() else { return true };
+        code.executed[pc as usize] = true;
+
+        let problem = match code.origin(pc) {
+            Origin::Data => CodeProblem::ExecutesData,
+            Origin::Written => CodeProblem::ExecutesWritten,
+            Origin::Unknown => {
+                code.origins[pc as usize] = Origin::Code;
+                return true;
+            },
+            Origin::Code => return true,
+        };
+        let Some(event) = code.record(pc, pc, problem) else { return true };
+        let stop = code.stop;
+        self.observers.emit(Event::Code(event));
+        if stop {
+            self.running = false;
+            return false;
+        }
+        true
+    }
+
+    /* reports the executed words the last instruction stored to */
+    pub(crate) fn finish_code(&mut self, pc: u16) {
+        let Some(code) = self.memory.code.as_mut() else { return };
+        let events: Vec<CodeEvent> = std::mem::take(&mut code.modified).into_iter()
+            .filter_map(|addr| code.record(pc, addr, CodeProblem::ModifiesCode))
+            .collect();
+        let stop = code.stop;
+
+        for &event in events.iter() {
+            self.observers.emit(Event::Code(event));
+        }
+        if !events.is_empty() && stop {
+            self.running = false;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn program(words: &[u16]) -> CPU {
+        let mut cpu = CPU::new();
+        let obj: Vec<u8> = std::iter::once(0x3000).chain(words.iter().copied()).flat_map(|w: u16| w.to_be_bytes()).collect();
+        cpu.load_image(&obj);
+        cpu
+    }
+
+    fn subscribe(cpu: &mut CPU) -> Rc<RefCell<Vec<CodeEvent>>> {
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let seen = events.clone();
+        cpu.subscribe(false, Box::new(move |event| {
+            if let Event::Code(event) = event {
+                seen.borrow_mut().push(*event);
+            }
+        }));
+        events
+    }
+
+    #[test]
+    fn without_a_listing_words_become_code_when_executed() {
+        /* ADD R0, R0, #1; ST R0, x3000; BRnzp x3000 */
+        let mut cpu = program(&[0x1021, 0x31FE, 0x0FFD]);
+        cpu.check_code(false);
+        let events = subscribe(&mut cpu);
+        let origin = |cpu: &CPU, addr| cpu.memory.code.as_ref().unwrap().origin(addr);
+
+        assert_eq!(origin(&cpu, 0x3001), Origin::Unknown);
+        cpu.step();
+        assert_eq!(origin(&cpu, 0x3000), Origin::Code);
+        assert!(events.borrow().is_empty());
+
+        cpu.step();
+        cpu.step();
+        cpu.step();
+        assert_eq!(*events.borrow(), vec![
+            CodeEvent { pc: 0x3001, addr: 0x3000, problem: CodeProblem::ModifiesCode },
+            CodeEvent { pc: 0x3000, addr: 0x3000, problem: CodeProblem::ExecutesWritten },
+        ]);
+        assert_eq!(cpu.memory.code.as_ref().unwrap().events, *events.borrow());
+    }
+
+    #[test]
+    fn listing_data_stops_the_machine_when_executed() {
+        let listing = "\
+(0000) 3000  0011000000000000 (   1)                 .ORIG x3000
+(3000) 1021  0001000000100001 (   2)                 ADD R0, R0, #1
+(3001) 0000  0000000000000000 (   3) VALUE           .FILL x0000
+";
+        let mut cpu = program(&[0x1021, 0x0000]);
+        cpu.load_listing("p.asm", listing);
+        cpu.check_code(true);
+        let events = subscribe(&mut cpu);
+
+        cpu.step();
+        cpu.step();
+        assert!(!cpu.running);
+        assert_eq!(cpu.pc, 0x3001);
+        assert_eq!(*events.borrow(), vec![CodeEvent { pc: 0x3001, addr: 0x3001, problem: CodeProblem::ExecutesData }]);
+    }
+}
@@ -0,0 +1,239 @@
+use std::collections::BTreeSet;
+use std::fmt;
+
+use crate::cpu::CPU;
+use crate::memory::DEVICE_START;
+use crate::symbols::SymbolTable;
+
+/* what happens on a read of something never written */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UninitMode {
+    Warn,
+    Stop,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Location {
+    Memory(u16),
+    Register(u16),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct UninitRead {
+    /* the instruction that did the read */
+    pub pc: u16,
+    pub location: Location,
+}
+
+impl fmt::Display for UninitRead {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.location {
+            Location::Memory(addr) => write!(f, "x{:04X}: read of uninitialised memory x{:04X}", self.pc, addr),
+            Location::Register(r) => write!(f, "x{:04X}: read of uninitialised R{}", self.pc, r),
+        }
+    }
+}
+
+/* one bit per word of memory, set by the loader and by stores */
+pub struct Shadow {
+    written: Vec<bool>,
+    /* uninitialised addresses read during the current instruction */
+    pub pending: Vec<u16>,
+}
+
+impl Shadow {
+    pub fn new() -> Shadow {
+        Shadow {
+            written: vec![false; 1 << 16],
+            pending: Vec::new(),
+        }
+    }
+
+    pub fn mark(&mut self, addr: usize) {
+        self.written[addr] = true;
+    }
+
+    /* device registers are always considered set */
+    pub fn check(&mut self, addr: usize) {
+        if addr < DEVICE_START && !self.written[addr] {
+            self.pending.push(addr as u16);
+        }
+    }
+}
+
+impl Default for Shadow {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct UninitCheck {
+    pub mode: UninitMode,
+    registers: [bool; 8],
+    /* uninitialised registers read during the current instruction */
+    pending: Vec<u16>,
+    pub reads: Vec<UninitRead>,
+    /* each (pc, location) is only reported once */
+    seen: BTreeSet<(u16, Location)>,
+}
+
+impl UninitCheck {
+    pub fn new(mode: UninitMode) -> UninitCheck {
+        UninitCheck {
+            mode,
+            registers: [false; 8],
+            pending: Vec::new(),
+            reads: Vec::new(),
+            seen: BTreeSet::new(),
+        }
+    }
+
+    pub fn mark_register(&mut self, r: u16) {
+        if let Some(written) = self.registers.get_mut(r as usize) {
+            *written = true;
+        }
+    }
+
+    pub fn check_register(&mut self, r: u16) {
+        if self.registers.get(r as usize) == Some(&false) {
+            self.pending.push(r);
+        }
+    }
+
+    /* one line per read, with symbolic addresses where there are symbols */
+    pub fn report(&self, symbols: &SymbolTable) -> String {
+        let mut out = String::new();
+        for read in self.reads.iter() {
+            let what = match read.location {
+                Location::Memory(addr) => format!("memory {}", symbols.describe(addr)),
+                Location::Register(r) => format!("R{}", r),
+            };
+            out += &format!("{}: read of uninitialised {}\n", symbols.describe(read.pc), what);
+        }
+        out
+    }
+}
+
+/* the trap vector table then the interrupt vector table */
+const SYSTEM_TABLES: usize = 0x0200;
+
+/* xorshift, the same garbage for the same seed */
+fn garbage(seed: &mut u32) -> u16 {
+    *seed ^= *seed << 13;
+    *seed ^= *seed >> 17;
+    *seed ^= *seed << 5;
+    (*seed >> 8) as u16
+}
+
+impl CPU {
+    /*
+    starts tracking which registers and words have been written; whatever
+    the last load_image put in memory counts as written
+    */
+    pub fn check_uninitialised(&mut self, mode: UninitMode) {
+        let mut shadow = Shadow::new();
+        if let Some((start, end)) = self.image {
+            for addr in start..=end {
+                shadow.mark(addr as usize);
+            }
+        }
+        self.memory.shadow = Some(shadow);
+        self.uninit = Some(UninitCheck::new(mode));
+    }
+
+    /*
+    fills R0-R7 and memory below the devices with garbage, call before
+    load_image; the trap and interrupt vector tables stay zeroed so an
+    empty entry still means no routine
+    */
+    pub fn fill_garbage(&mut self, seed: u32) {
+        let mut seed = seed.max(1);
+        for r in 0..8 {
+            let val = garbage(&mut seed);
+            self.set_reg(r, val);
+        }
+        for word in self.memory.memory[SYSTEM_TABLES..DEVICE_START].iter_mut() {
+            *word = garbage(&mut seed);
+        }
+        self.memory.cache.clear();
+
+        /* the garbage itself does not count as initialised */
+        if let Some(check) = self.uninit.as_mut() {
+            check.registers = [false; 8];
+        }
+    }
+
+    /* turns what the last instruction read into reports */
+    pub(crate) fn finish_uninit(&mut self, pc: u16) {
+        let memory = self.memory.shadow.as_mut().map(|s| std::mem::take(&mut s.pending)).unwrap_or_default();
+        let Some(check) = self.uninit.as_mut() else { return };
+
+        let registers = std::mem::take(&mut check.pending);
+        let found = registers.into_iter().map(Location::Register)
+            .chain(memory.into_iter().map(Location::Memory));
+
+        let mut new = false;
+        for location in found {
+            if check.seen.insert((pc, location)) {
+                let read = UninitRead { pc, location };
+                eprintln!("{}", read);
+                check.reads.push(read);
+                new = true;
+            }
+        }
+
+        if new && check.mode == UninitMode::Stop {
+            self.running = false;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::redirect_console;
+
+    fn program(words: &[u16]) -> CPU {
+        let mut cpu = CPU::new();
+        let obj: Vec<u8> = std::iter::once(0x3000).chain(words.iter().copied()).flat_map(|w: u16| w.to_be_bytes()).collect();
+        cpu.load_image(&obj);
+        cpu
+    }
+
+    #[test]
+    fn reads_of_unwritten_registers_and_memory_are_reported_once() {
+        /* ADD R1, R0, #0; LD R2, x3010; BRnzp back to the start */
+        let mut cpu = program(&[0x1220, 0x240E, 0x0FFD]);
+        cpu.check_uninitialised(UninitMode::Warn);
+        for _ in 0..6 {
+            cpu.step();
+        }
+        let reads: Vec<Location> = cpu.uninit.as_ref().unwrap().reads.iter().map(|r| r.location).collect();
+        assert_eq!(reads, vec![Location::Register(0), Location::Memory(0x3010)]);
+    }
+
+    #[test]
+    fn registers_count_as_written_even_when_the_value_is_the_same() {
+        redirect_console(Box::new(|_| {}), Box::new(|| 0));
+        /* GETC with no input leaves R0 at 0; ADD R1, R0, #0 */
+        let mut cpu = program(&[0xF020, 0x1220]);
+        cpu.check_uninitialised(UninitMode::Stop);
+        cpu.step();
+        cpu.step();
+        assert!(cpu.running);
+        assert_eq!(cpu.uninit.as_ref().unwrap().reads, vec![]);
+    }
+
+    #[test]
+    fn garbage_leaves_the_vector_tables_empty() {
+        let mut cpu = CPU::new();
+        cpu.fill_garbage(7);
+        assert!(cpu.memory.memory[..SYSTEM_TABLES].iter().all(|&w| w == 0));
+        assert!(cpu.memory.memory[SYSTEM_TABLES..DEVICE_START].iter().any(|&w| w != 0));
+
+        /* HALT still finds no table entry and runs the built in routine */
+        cpu.load_image(&vec![0x30, 0x00, 0xF0, 0x25]);
+        cpu.run();
+        assert!(!cpu.running);
+    }
+}
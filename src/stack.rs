@@ -0,0 +1,206 @@
+use std::collections::BTreeSet;
+use std::fmt;
+
+use crate::cpu::CPU;
+use crate::symbols::SymbolTable;
+
+/* labels that bound the stack when it is set up from symbols */
+pub const BASE_SYMBOL: &str = "STACK_BASE";
+pub const LIMIT_SYMBOL: &str = "STACK_LIMIT";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum StackProblem {
+    /* R6 went below the limit */
+    Overflow(u16),
+    /* R6 went above the base */
+    Underflow(u16),
+    /* STR with base R6 wrote outside limit..base */
+    StoreOutside(u16),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StackEvent {
+    pub pc: u16,
+    pub problem: StackProblem,
+}
+
+impl fmt::Display for StackEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.problem {
+            StackProblem::Overflow(sp) => write!(f, "x{:04X}: stack overflow, R6 = x{:04X}", self.pc, sp),
+            StackProblem::Underflow(sp) => write!(f, "x{:04X}: stack underflow, R6 = x{:04X}", self.pc, sp),
+            StackProblem::StoreOutside(addr) => write!(f, "x{:04X}: store through R6 outside the stack at x{:04X}", self.pc, addr),
+        }
+    }
+}
+
+/*
+the stack grows down from `base`, which is R6 when it is empty, and may
+use words down to `limit`
+*/
+pub struct StackGuard {
+    pub base: u16,
+    pub limit: u16,
+    pub stop: bool,
+    /* R6 relative stores made by the current instruction */
+    stores: Vec<u16>,
+    pub events: Vec<StackEvent>,
+    /* each (pc, problem kind) is only reported once */
+    seen: BTreeSet<(u16, u8)>,
+}
+
+impl StackGuard {
+    pub fn new(base: u16, limit: u16, stop: bool) -> StackGuard {
+        StackGuard {
+            base,
+            limit,
+            stop,
+            stores: Vec::new(),
+            events: Vec::new(),
+            seen: BTreeSet::new(),
+        }
+    }
+
+    pub fn contains(&self, sp: u16) -> bool {
+        sp >= self.limit && sp <= self.base
+    }
+
+    pub(crate) fn store(&mut self, addr: u16) {
+        self.stores.push(addr);
+    }
+
+    fn record(&mut self, pc: u16, problem: StackProblem) -> bool {
+        let kind = match problem {
+            StackProblem::Overflow(_) => 0,
+            StackProblem::Underflow(_) => 1,
+            StackProblem::StoreOutside(_) => 2,
+        };
+        if !self.seen.insert((pc, kind)) {
+            return false;
+        }
+        let event = StackEvent { pc, problem };
+        eprintln!("{}", event);
+        self.events.push(event);
+        true
+    }
+
+    pub fn report(&self, symbols: &SymbolTable) -> String {
+        let mut out = String::new();
+        for event in self.events.iter() {
+            let what = match event.problem {
+                StackProblem::Overflow(sp) => format!("stack overflow, R6 = x{:04X}", sp),
+                StackProblem::Underflow(sp) => format!("stack underflow, R6 = x{:04X}", sp),
+                StackProblem::StoreOutside(addr) => format!("store through R6 outside the stack at {}", symbols.describe(addr)),
+            };
+            out += &format!("{}: {}\n", symbols.describe(event.pc), what);
+        }
+        out
+    }
+}
+
+impl CPU {
+    pub fn guard_stack(&mut self, base: u16, limit: u16, stop: bool) {
+        self.stack = Some(StackGuard::new(base, limit, stop));
+    }
+
+    /* false if the symbol table has no STACK_BASE and STACK_LIMIT labels */
+    pub fn guard_stack_from_symbols(&mut self, stop: bool) -> bool {
+        match (self.symbols.lookup(BASE_SYMBOL), self.symbols.lookup(LIMIT_SYMBOL)) {
+            (Some(base), Some(limit)) => {
+                self.guard_stack(base, limit, stop);
+                true
+            },
+            _ => false,
+        }
+    }
+
+    /* a store addressed off R6, checked once the instruction is done */
+    pub(crate) fn stack_store(&mut self, base: u16, addr: u16) {
+        if base != 6 {
+            return;
+        }
+        if let Some(guard) = self.stack.as_mut() {
+            guard.store(addr);
+        }
+    }
+
+    /*
+    R6 is only judged when an instruction moves it, so switching to the
+    supervisor stack for an interrupt or setting R6 up is not a problem
+    */
+    pub(crate) fn finish_stack(&mut self, pc: u16, before: u16) {
+        let sp = self.rr6;
+        let Some(guard) = self.stack.as_mut() else { return };
+
+        let mut new = false;
+        for addr in std::mem::take(&mut guard.stores) {
+            if addr < guard.limit || addr >= guard.base {
+                new |= guard.record(pc, StackProblem::StoreOutside(addr));
+            }
+        }
+        if sp != before && guard.contains(before) {
+            if sp < guard.limit {
+                new |= guard.record(pc, StackProblem::Overflow(sp));
+            } else if sp > guard.base {
+                new |= guard.record(pc, StackProblem::Underflow(sp));
+            }
+        }
+
+        if new && guard.stop {
+            self.running = false;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn program(words: &[u16]) -> CPU {
+        let mut cpu = CPU::new();
+        let obj: Vec<u8> = std::iter::once(0x3000).chain(words.iter().copied()).flat_map(|w: u16| w.to_be_bytes()).collect();
+        cpu.load_image(&obj);
+        cpu
+    }
+
+    fn problems(cpu: &CPU) -> Vec<StackProblem> {
+        cpu.stack.as_ref().unwrap().events.iter().map(|e| e.problem).collect()
+    }
+
+    #[test]
+    fn pushing_past_the_limit_overflows() {
+        /* three pushes and a STR through R6 */
+        let mut cpu = program(&[0x1DBF, 0x1DBF, 0x1DBF, 0x7180]);
+        cpu.rr6 = 0x4000;
+        cpu.guard_stack(0x4000, 0x3FFE, false);
+        for _ in 0..4 {
+            cpu.step();
+        }
+        assert_eq!(problems(&cpu), vec![StackProblem::Overflow(0x3FFD), StackProblem::StoreOutside(0x3FFD)]);
+        assert!(cpu.running);
+    }
+
+    #[test]
+    fn popping_past_the_base_underflows_and_stops() {
+        /* ADD R6, R6, #1 twice */
+        let mut cpu = program(&[0x1DA1, 0x1DA1]);
+        cpu.rr6 = 0x4000;
+        cpu.guard_stack(0x4000, 0x3F00, true);
+        cpu.step();
+        assert_eq!(problems(&cpu), vec![StackProblem::Underflow(0x4001)]);
+        assert!(!cpu.running);
+        assert_eq!(cpu.stack.as_ref().unwrap().report(&cpu.symbols), "x3000: stack underflow, R6 = x4001\n");
+    }
+
+    #[test]
+    fn setting_up_r6_is_not_judged() {
+        /* LD R6, STACK; HALT; STACK .FILL x4000 */
+        let mut cpu = program(&[0x2C01, 0xF025, 0x4000]);
+        cpu.load_symbols("// STACK_BASE 4000\n// STACK_LIMIT 3F00\n");
+        assert!(cpu.guard_stack_from_symbols(true));
+        cpu.step();
+        assert_eq!(cpu.rr6, 0x4000);
+        assert!(problems(&cpu).is_empty());
+        assert!(cpu.running);
+    }
+}
@@ -0,0 +1,68 @@
+use std::collections::BTreeMap;
+
+/* labels from an assembler .sym file */
+pub struct SymbolTable {
+    pub by_addr: BTreeMap<u16, String>,
+    pub by_name: BTreeMap<String, u16>,
+}
+
+impl SymbolTable {
+    pub const fn new() -> SymbolTable {
+        SymbolTable {
+            by_addr: BTreeMap::new(),
+            by_name: BTreeMap::new(),
+        }
+    }
+
+    /* lc3as format, one "//	LABEL		3000" entry per line after a header */
+    pub fn parse(text: &str) -> SymbolTable {
+        let mut table = SymbolTable::new();
+
+        for line in text.lines() {
+            let line = line.trim_start_matches('/').trim();
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() != 2 {
+                continue;
+            }
+
+            let addr = fields[1].trim_start_matches(['x', 'X']);
+            if let Ok(addr) = u16::from_str_radix(addr, 16) {
+                table.insert(fields[0], addr);
+            }
+        }
+
+        table
+    }
+
+    pub fn insert(&mut self, name: &str, addr: u16) {
+        self.by_addr.insert(addr, name.to_string());
+        self.by_name.insert(name.to_string(), addr);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_addr.is_empty()
+    }
+
+    pub fn lookup(&self, name: &str) -> Option<u16> {
+        self.by_name.get(name).copied()
+    }
+
+    pub fn name(&self, addr: u16) -> Option<&str> {
+        self.by_addr.get(&addr).map(|s| s.as_str())
+    }
+
+    /* "LABEL", "LABEL+3" or "x3005" when nothing precedes the address */
+    pub fn describe(&self, addr: u16) -> String {
+        match self.by_addr.range(..=addr).next_back() {
+            Some((&base, name)) if base == addr => name.clone(),
+            Some((&base, name)) => format!("{}+{}", name, addr - base),
+            None => format!("x{:04X}", addr),
+        }
+    }
+}
+
+impl Default for SymbolTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,186 @@
+use crate::io::now_ms;
+use crate::memory::{Device, InterruptRequest};
+
+/* control and status: bit 15 expired, 14 interrupt enable, 13 running, 12 count host milliseconds */
+pub const TMCR: usize = 0xFE08;
+/* reload value, in instructions or milliseconds */
+pub const TMIR: usize = 0xFE0A;
+/* what is left of the current interval, read only */
+pub const TMCNT: usize = 0xFE0C;
+/* interrupt vector in bits 7:0, priority in bits 10:8 */
+pub const TMVR: usize = 0xFE0E;
+
+const EXPIRED: u16 = 1 << 15;
+const INTERRUPT_ENABLE: u16 = 1 << 14;
+const RUNNING: u16 = 1 << 13;
+const HOST_TIME: u16 = 1 << 12;
+
+/* host time is only looked at this often, in instructions */
+const CLOCK_POLL: u32 = 256;
+
+/*
+programmable interval timer; reading TMCR clears the expired bit, writing it
+or TMIR (re)starts the interval, and an expiry with interrupts enabled
+raises the vector in TMVR until the CPU takes it
+*/
+pub struct Timer {
+    control: u16,
+    interval: u16,
+    count: u16,
+    vector: u16,
+    pending: bool,
+    /* host time of the next expiry when counting milliseconds */
+    deadline: f64,
+    poll: u32,
+}
+
+impl Timer {
+    pub fn new(vector: u8, priority: u8) -> Timer {
+        Timer {
+            control: 0,
+            interval: 0,
+            count: 0,
+            vector: ((priority as u16 & 0x7) << 8) | vector as u16,
+            pending: false,
+            deadline: 0.0,
+            poll: 0,
+        }
+    }
+
+    fn restart(&mut self) {
+        self.count = self.interval;
+        if self.control & HOST_TIME != 0 {
+            self.deadline = now_ms() + self.interval as f64;
+        }
+    }
+
+    fn expire(&mut self) {
+        self.control |= EXPIRED;
+        if self.control & INTERRUPT_ENABLE != 0 {
+            self.pending = true;
+        }
+        self.restart();
+    }
+}
+
+impl Device for Timer {
+    fn claims(&self, addr: usize) -> bool {
+        matches!(addr, TMCR | TMIR | TMCNT | TMVR)
+    }
+
+    fn read(&mut self, addr: usize) -> u16 {
+        match addr {
+            TMCR => {
+                let val = self.control;
+                self.control &= !EXPIRED;
+                val
+            },
+            TMIR => self.interval,
+            TMCNT if self.control & HOST_TIME != 0 => (self.deadline - now_ms()).max(0.0) as u16,
+            TMCNT => self.count,
+            _ => self.vector,
+        }
+    }
+
+    fn write(&mut self, addr: usize, val: u16) {
+        match addr {
+            TMCR => {
+                self.control = val & !EXPIRED;
+                if val & INTERRUPT_ENABLE == 0 {
+                    self.pending = false;
+                }
+                self.restart();
+            },
+            TMIR => {
+                self.interval = val;
+                self.restart();
+            },
+            TMCNT => {},
+            _ => self.vector = val & 0x07FF,
+        }
+    }
+
+    fn tick(&mut self) {
+        if self.control & RUNNING == 0 || self.interval == 0 {
+            return;
+        }
+
+        if self.control & HOST_TIME != 0 {
+            self.poll += 1;
+            if self.poll >= CLOCK_POLL {
+                self.poll = 0;
+                if now_ms() >= self.deadline {
+                    self.expire();
+                }
+            }
+            return;
+        }
+
+        self.count = self.count.saturating_sub(1);
+        if self.count == 0 {
+            self.expire();
+        }
+    }
+
+    fn interrupt(&self) -> Option<InterruptRequest> {
+        if !self.pending {
+            return None;
+        }
+        Some(InterruptRequest {
+            vector: self.vector as u8,
+            priority: ((self.vector >> 8) & 0x7) as u8,
+        })
+    }
+
+    fn acknowledge(&mut self) {
+        self.pending = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ticks_to_expiry(timer: &mut Timer) -> u32 {
+        for n in 1..=0x10000 {
+            timer.tick();
+            if timer.read(TMCR) & EXPIRED != 0 {
+                return n;
+            }
+        }
+        0
+    }
+
+    #[test]
+    fn interval_counts_whichever_register_is_written_first() {
+        let mut timer = Timer::new(0x81, 4);
+        timer.write(TMCR, RUNNING);
+        timer.write(TMIR, 10);
+        assert_eq!(timer.read(TMCNT), 10);
+        assert_eq!(ticks_to_expiry(&mut timer), 10);
+
+        let mut timer = Timer::new(0x81, 4);
+        timer.write(TMIR, 10);
+        timer.write(TMCR, RUNNING);
+        assert_eq!(ticks_to_expiry(&mut timer), 10);
+        /* and again from the reload value */
+        assert_eq!(ticks_to_expiry(&mut timer), 10);
+    }
+
+    #[test]
+    fn expiry_interrupts_only_when_enabled() {
+        let mut timer = Timer::new(0x81, 4);
+        timer.write(TMIR, 2);
+        timer.write(TMCR, RUNNING);
+        timer.tick();
+        timer.tick();
+        assert_eq!(timer.interrupt(), None);
+
+        timer.write(TMCR, RUNNING | INTERRUPT_ENABLE);
+        timer.tick();
+        timer.tick();
+        assert_eq!(timer.interrupt(), Some(InterruptRequest { vector: 0x81, priority: 4 }));
+        timer.acknowledge();
+        assert_eq!(timer.interrupt(), None);
+    }
+}
@@ -0,0 +1,257 @@
+use std::io::Write;
+
+use crate::instructions::Isa;
+use crate::memory::Access;
+
+/* everything one instruction did, as seen from outside the CPU */
+#[derive(Debug, Clone)]
+pub struct TraceRecord {
+    pub pc: u16,
+    pub inst: u16,
+    pub disassembly: String,
+    /* (register number, new value) for the destination and every other register that changed */
+    pub reg_writes: Vec<(u16, u16)>,
+    pub mem: Vec<Access>,
+    /* (old, new) condition codes when they changed */
+    pub cond: Option<(u16, u16)>,
+}
+
+pub trait TraceSink {
+    fn record(&mut self, rec: &TraceRecord);
+}
+
+/* forwards records to a sink, dropping those whose PC is outside every range */
+pub struct Tracer {
+    pub sink: Box<dyn TraceSink>,
+    pub ranges: Vec<(u16, u16)>,
+}
+
+impl Tracer {
+    pub fn new(sink: Box<dyn TraceSink>) -> Tracer {
+        Tracer {
+            sink,
+            ranges: Vec::new(),
+        }
+    }
+
+    /* only trace instructions in [start, end], may be called more than once */
+    pub fn filter(mut self, start: u16, end: u16) -> Tracer {
+        self.ranges.push((start, end));
+        self
+    }
+
+    pub fn wants(&self, pc: u16) -> bool {
+        self.ranges.is_empty() || self.ranges.iter().any(|&(s, e)| pc >= s && pc <= e)
+    }
+
+    pub fn record(&mut self, rec: &TraceRecord) {
+        if self.wants(rec.pc) {
+            self.sink.record(rec);
+        }
+    }
+}
+
+/* the register an instruction's encoding writes, TRAPs are left to what changed */
+pub fn destination(isa: Isa, inst: u16) -> Option<u16> {
+    let dr = (inst >> 9) & 0x7;
+    match (isa, inst >> 12) {
+        (_, 0x1 | 0x2 | 0x5 | 0x6 | 0x9 | 0xE) => Some(dr),
+        (Isa::Lc3, 0xA) | (Isa::Lc3b, 0xD) => Some(dr),
+        (_, 0x4) => Some(7),
+        _ => None,
+    }
+}
+
+pub fn cond_name(cond: u16) -> &'static str {
+    match cond {
+        1 => "P",
+        2 => "Z",
+        4 => "N",
+        _ => "-",
+    }
+}
+
+/* one human readable line per instruction */
+pub struct TextTrace<W: Write> {
+    out: W,
+}
+
+impl<W: Write> TextTrace<W> {
+    pub fn new(out: W) -> TextTrace<W> {
+        TextTrace { out }
+    }
+}
+
+impl<W: Write> TraceSink for TextTrace<W> {
+    fn record(&mut self, rec: &TraceRecord) {
+        let mut line = format!("x{:04X}  x{:04X}  {:<24}", rec.pc, rec.inst, rec.disassembly);
+
+        for (r, val) in &rec.reg_writes {
+            line += &format!(" R{}<-x{:04X}", r, val);
+        }
+        for access in &rec.mem {
+            match access {
+                Access::Read(addr, val) => line += &format!(" [x{:04X}]->x{:04X}", addr, val),
+                Access::Write(addr, val) => line += &format!(" [x{:04X}]<-x{:04X}", addr, val),
+            }
+        }
+        if let Some((old, new)) = rec.cond {
+            line += &format!(" CC {}->{}", cond_name(old), cond_name(new));
+        }
+
+        let _ = writeln!(self.out, "{}", line.trim_end());
+    }
+}
+
+/* one JSON object per line */
+pub struct JsonTrace<W: Write> {
+    out: W,
+}
+
+impl<W: Write> JsonTrace<W> {
+    pub fn new(out: W) -> JsonTrace<W> {
+        JsonTrace { out }
+    }
+}
+
+pub fn json_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => out += "\\\"",
+            '\\' => out += "\\\\",
+            '\n' => out += "\\n",
+            c if (c as u32) < 0x20 => out += &format!("\\u{:04x}", c as u32),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+impl<W: Write> TraceSink for JsonTrace<W> {
+    fn record(&mut self, rec: &TraceRecord) {
+        let regs: Vec<String> = rec.reg_writes.iter()
+            .map(|(r, val)| format!("{{\"reg\":{},\"value\":{}}}", r, val))
+            .collect();
+        let mem: Vec<String> = rec.mem.iter()
+            .map(|access| match access {
+                Access::Read(addr, val) => format!("{{\"op\":\"read\",\"addr\":{},\"value\":{}}}", addr, val),
+                Access::Write(addr, val) => format!("{{\"op\":\"write\",\"addr\":{},\"value\":{}}}", addr, val),
+            })
+            .collect();
+        let cond = match rec.cond {
+            Some((old, new)) => format!("{{\"old\":\"{}\",\"new\":\"{}\"}}", cond_name(old), cond_name(new)),
+            None => "null".to_string(),
+        };
+
+        let _ = writeln!(
+            self.out,
+            "{{\"pc\":{},\"inst\":{},\"asm\":\"{}\",\"regs\":[{}],\"mem\":[{}],\"cc\":{}}}",
+            rec.pc,
+            rec.inst,
+            json_escape(&rec.disassembly),
+            regs.join(","),
+            mem.join(","),
+            cond
+        );
+    }
+}
+
+/* an in-memory output that can be drained while a sink still holds it */
+#[derive(Clone, Default)]
+pub struct TraceBuffer(std::rc::Rc<std::cell::RefCell<Vec<u8>>>);
+
+impl TraceBuffer {
+    pub fn take(&self) -> String {
+        let bytes = std::mem::take(&mut *self.0.borrow_mut());
+        String::from_utf8_lossy(&bytes).into_owned()
+    }
+}
+
+impl Write for TraceBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::CPU;
+
+    /* an .obj image at x3000 */
+    fn program(words: &[u16]) -> Vec<u8> {
+        std::iter::once(0x3000).chain(words.iter().copied()).flat_map(|w: u16| w.to_be_bytes()).collect()
+    }
+
+    fn traced(json: bool, words: &[u16]) -> (CPU, TraceBuffer) {
+        let mut cpu = CPU::new();
+        cpu.load_image(&program(words));
+        let buffer = TraceBuffer::default();
+        cpu.tracer = Some(if json {
+            Tracer::new(Box::new(JsonTrace::new(buffer.clone())))
+        } else {
+            Tracer::new(Box::new(TextTrace::new(buffer.clone())))
+        });
+        (cpu, buffer)
+    }
+
+    #[test]
+    fn text_records_registers_stores_and_cc() {
+        /* ADD R0, R0, #5; ST R0, #1 */
+        let (mut cpu, buffer) = traced(false, &[0x1025, 0x3001]);
+        cpu.step();
+        cpu.step();
+
+        let text = buffer.take();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("x3000  x1025"));
+        assert!(lines[0].ends_with("R0<-x0005 CC -->P"));
+        assert!(lines[1].ends_with("[x3003]<-x0005"));
+    }
+
+    #[test]
+    fn unchanged_destination_is_recorded() {
+        /* ADD R1, R1, #0 */
+        let (mut cpu, buffer) = traced(false, &[0x1260]);
+        cpu.step();
+        assert!(buffer.take().contains("R1<-x0000"));
+    }
+
+    #[test]
+    fn json_has_one_object_per_instruction() {
+        /* LD R2, #1; HALT; .FILL x1234 */
+        let (mut cpu, buffer) = traced(true, &[0x2401, 0xF025, 0x1234]);
+        cpu.step();
+
+        let text = buffer.take();
+        assert!(text.starts_with("{\"pc\":12288,\"inst\":9217,"));
+        assert!(text.contains("\"regs\":[{\"reg\":2,\"value\":4660}]"));
+        assert!(text.contains("\"mem\":[{\"op\":\"read\",\"addr\":12290,\"value\":4660}]"));
+    }
+
+    #[test]
+    fn filter_drops_records_outside_the_ranges() {
+        let (mut cpu, buffer) = traced(false, &[0x1260, 0x1260]);
+        cpu.tracer = cpu.tracer.take().map(|t| t.filter(0x3001, 0x3001));
+        cpu.step();
+        cpu.step();
+        assert!(buffer.take().starts_with("x3001"));
+    }
+
+    #[test]
+    fn outer_logging_is_kept() {
+        /* ST R0, #0 */
+        let (mut cpu, _buffer) = traced(false, &[0x3000]);
+        cpu.memory.logging = true;
+        cpu.step();
+        assert!(cpu.memory.logging);
+        assert!(cpu.memory.accesses.contains(&Access::Write(0x3001, 0)));
+    }
+}
@@ -0,0 +1,488 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::cpu::CPU;
+use crate::instructions::{Isa, TrapCodes};
+use crate::io::{get_key, now_ms, print};
+use crate::memory::Mem;
+
+/* R0 when an extended trap fails */
+const FAILED: u16 = 0xFFFF;
+
+/* which extended traps may run; everything off is the textbook machine */
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Capabilities {
+    /* IN_DEC x26, OUT_DEC x27 */
+    pub decimal: bool,
+    /* RANDOM x28 */
+    pub random: bool,
+    /* CLOCK x29, TICKS x2A */
+    pub clock: bool,
+    /* OPEN x30, READ x31, WRITE x32, CLOSE x33 */
+    pub files: bool,
+}
+
+impl Capabilities {
+    pub const fn none() -> Capabilities {
+        Capabilities { decimal: false, random: false, clock: false, files: false }
+    }
+
+    pub const fn all() -> Capabilities {
+        Capabilities { decimal: true, random: true, clock: true, files: true }
+    }
+
+    pub(crate) fn allows(&self, code: TrapCodes) -> bool {
+        match code {
+            TrapCodes::TrapInDec | TrapCodes::TrapOutDec => self.decimal,
+            TrapCodes::TrapRandom => self.random,
+            TrapCodes::TrapClock | TrapCodes::TrapTicks => self.clock,
+            TrapCodes::TrapOpen | TrapCodes::TrapRead | TrapCodes::TrapWrite | TrapCodes::TrapClose => self.files,
+            _ => true,
+        }
+    }
+}
+
+/* OPEN modes, passed in R1 */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OpenMode {
+    Read,
+    /* truncates */
+    Write,
+    Append,
+}
+
+impl OpenMode {
+    fn from(mode: u16) -> Option<OpenMode> {
+        match mode {
+            0 => Some(OpenMode::Read),
+            1 => Some(OpenMode::Write),
+            2 => Some(OpenMode::Append),
+            _ => None,
+        }
+    }
+}
+
+/*
+files the program can reach, supplied by the host; names are whatever the
+program passes, so an implementation decides what they map to
+*/
+pub trait VirtualFs {
+    fn open(&mut self, name: &str, mode: OpenMode) -> Option<u16>;
+    /* None at end of file or for a bad handle */
+    fn read(&mut self, handle: u16) -> Option<u8>;
+    fn write(&mut self, handle: u16, byte: u8) -> bool;
+    fn close(&mut self, handle: u16);
+}
+
+struct OpenFile {
+    name: String,
+    pos: usize,
+    mode: OpenMode,
+}
+
+/* files held in memory, filled and read back by the host */
+#[derive(Default)]
+pub struct MemoryFs {
+    pub files: BTreeMap<String, Vec<u8>>,
+    open: BTreeMap<u16, OpenFile>,
+    next: u16,
+}
+
+impl MemoryFs {
+    pub fn new() -> MemoryFs {
+        MemoryFs::default()
+    }
+}
+
+impl VirtualFs for MemoryFs {
+    fn open(&mut self, name: &str, mode: OpenMode) -> Option<u16> {
+        match mode {
+            OpenMode::Read if !self.files.contains_key(name) => return None,
+            OpenMode::Write => {
+                self.files.insert(name.to_string(), Vec::new());
+            },
+            OpenMode::Append => {
+                self.files.entry(name.to_string()).or_default();
+            },
+            _ => {},
+        }
+
+        let pos = if mode == OpenMode::Append { self.files[name].len() } else { 0 };
+        let handle = self.next;
+        self.next = self.next.wrapping_add(1) % FAILED;
+        self.open.insert(handle, OpenFile { name: name.to_string(), pos, mode });
+        Some(handle)
+    }
+
+    fn read(&mut self, handle: u16) -> Option<u8> {
+        let file = self.open.get_mut(&handle)?;
+        if file.mode != OpenMode::Read {
+            return None;
+        }
+        let byte = *self.files.get(&file.name)?.get(file.pos)?;
+        file.pos += 1;
+        Some(byte)
+    }
+
+    fn write(&mut self, handle: u16, byte: u8) -> bool {
+        let Some(file) = self.open.get_mut(&handle) else { return false };
+        if file.mode == OpenMode::Read {
+            return false;
+        }
+        let Some(data) = self.files.get_mut(&file.name) else { return false };
+        data.push(byte);
+        file.pos = data.len();
+        true
+    }
+
+    fn close(&mut self, handle: u16) {
+        self.open.remove(&handle);
+    }
+}
+
+/* a host routine for one trap vector, with the whole machine to work on */
+pub type TrapHandler = Box<dyn FnMut(&mut CPU)>;
+
+pub struct TrapExtensions {
+    pub caps: Capabilities,
+    handlers: BTreeMap<u8, TrapHandler>,
+    /* vectors unregistered while their handler was running */
+    removed: BTreeSet<u8>,
+    pub fs: Option<Box<dyn VirtualFs>>,
+    /* xorshift state, never zero */
+    pub seed: u32,
+}
+
+impl TrapExtensions {
+    pub const fn new() -> TrapExtensions {
+        TrapExtensions {
+            caps: Capabilities::none(),
+            handlers: BTreeMap::new(),
+            removed: BTreeSet::new(),
+            fs: None,
+            seed: 0x2545_F491,
+        }
+    }
+
+    pub fn handles(&self, vector: u8) -> bool {
+        self.handlers.contains_key(&vector)
+    }
+
+    fn random(&mut self) -> u16 {
+        let mut x = self.seed;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.seed = x;
+        (x >> 16) as u16
+    }
+}
+
+impl Default for TrapExtensions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CPU {
+    /* runs `handler` for TRAP `vector` in place of whatever would run otherwise */
+    pub fn register_trap(&mut self, vector: u8, handler: TrapHandler) {
+        self.traps.removed.remove(&vector);
+        self.traps.handlers.insert(vector, handler);
+    }
+
+    pub fn unregister_trap(&mut self, vector: u8) {
+        self.traps.removed.insert(vector);
+        self.traps.handlers.remove(&vector);
+    }
+
+    /* false if nothing is registered for `vector` */
+    pub(crate) fn run_trap_handler(&mut self, vector: u8) -> bool {
+        let Some(mut handler) = self.traps.handlers.remove(&vector) else { return false };
+        self.traps.removed.remove(&vector);
+        handler(self);
+        /* the handler may have unregistered itself or registered a replacement */
+        if !self.traps.removed.remove(&vector) {
+            self.traps.handlers.entry(vector).or_insert(handler);
+        }
+        true
+    }
+
+    /* the traps past HALT, only reached when their capability is on */
+    pub(crate) fn trap_extended(&mut self, code: TrapCodes) {
+        match code {
+            TrapCodes::TrapInDec => {
+                let val = self.read_decimal();
+                self.set_reg(0, val);
+                self.update_flags(0);
+            },
+            TrapCodes::TrapOutDec => {
+                for c in (self.rr0 as i16).to_string().bytes() {
+                    print(c);
+                }
+            },
+            TrapCodes::TrapRandom => {
+                let val = self.traps.random();
+                self.set_reg(0, val);
+            },
+            TrapCodes::TrapClock => {
+                let secs = (now_ms() / 1000.0) as u32;
+                self.set_reg(0, (secs >> 16) as u16);
+                self.set_reg(1, secs as u16);
+            },
+            TrapCodes::TrapTicks => {
+                let ticks = self.ticks;
+                self.set_reg(0, (ticks >> 16) as u16);
+                self.set_reg(1, ticks as u16);
+            },
+            TrapCodes::TrapOpen => {
+                let name = self.read_string(self.rr0);
+                let handle = match (OpenMode::from(self.rr1), self.traps.fs.as_mut()) {
+                    (Some(mode), Some(fs)) => fs.open(&name, mode),
+                    _ => None,
+                };
+                self.set_reg(0, handle.unwrap_or(FAILED));
+            },
+            TrapCodes::TrapRead => {
+                let (handle, buf, len) = (self.rr0, self.rr1, self.rr2);
+                let Some(fs) = self.traps.fs.as_mut() else {
+                    return self.set_reg(0, FAILED);
+                };
+                let mut count = 0;
+                while count < len {
+                    let Some(byte) = fs.read(handle) else { break };
+                    write_char(&mut self.memory, self.isa, buf.wrapping_add(count), byte);
+                    count += 1;
+                }
+                self.set_reg(0, count);
+            },
+            TrapCodes::TrapWrite => {
+                let (handle, buf, len) = (self.rr0, self.rr1, self.rr2);
+                let Some(fs) = self.traps.fs.as_mut() else {
+                    return self.set_reg(0, FAILED);
+                };
+                let mut count = 0;
+                while count < len {
+                    let byte = read_char(&mut self.memory, self.isa, buf.wrapping_add(count));
+                    if !fs.write(handle, byte) {
+                        break;
+                    }
+                    count += 1;
+                }
+                self.set_reg(0, if count == 0 && len > 0 { FAILED } else { count });
+            },
+            TrapCodes::TrapClose => {
+                if let Some(fs) = self.traps.fs.as_mut() {
+                    fs.close(self.rr0);
+                }
+            },
+            _ => {},
+        }
+    }
+
+    /*
+    an optional '-' then digits, echoed, ended by anything else or no more
+    input; saturates at -32768 and 65535
+    */
+    fn read_decimal(&mut self) -> u16 {
+        let mut val: i32 = 0;
+        let mut negative = false;
+        let mut first = true;
+
+        loop {
+            let c = get_key();
+            if c == 0 {
+                break;
+            }
+            if c == b'-' && first {
+                negative = true;
+            } else if c.is_ascii_digit() {
+                val = (val * 10 + (c - b'0') as i32).min(0xFFFF);
+            } else {
+                break;
+            }
+            print(c);
+            first = false;
+        }
+
+        if negative { (-val.min(0x8000)) as u16 } else { val as u16 }
+    }
+
+    /* zero terminated */
+    fn read_string(&mut self, addr: u16) -> String {
+        let mut name = String::new();
+        let mut addr = addr;
+        loop {
+            let c = read_char(&mut self.memory, self.isa, addr);
+            if c == 0 || name.len() >= 256 {
+                break;
+            }
+            name.push(c as char);
+            addr = addr.wrapping_add(1);
+        }
+        name
+    }
+}
+
+/* strings and file buffers are one char per word, or per byte on an LC-3b */
+fn read_char(memory: &mut Mem, isa: Isa, addr: u16) -> u8 {
+    match isa {
+        Isa::Lc3 => memory.read(addr as usize) as u8,
+        Isa::Lc3b => memory.read_byte(addr as usize),
+    }
+}
+
+fn write_char(memory: &mut Mem, isa: Isa, addr: u16, c: u8) {
+    match isa {
+        Isa::Lc3 => memory.write(addr as usize, c as u16),
+        Isa::Lc3b => memory.write_byte(addr as usize, c),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::redirect_console;
+    use crate::shadow::UninitMode;
+
+    fn program(words: &[u16]) -> CPU {
+        let mut cpu = CPU::new();
+        let obj: Vec<u8> = std::iter::once(0x3000).chain(words.iter().copied()).flat_map(|w: u16| w.to_be_bytes()).collect();
+        cpu.load_image(&obj);
+        cpu
+    }
+
+    fn typing(text: &'static str) {
+        let mut keys = text.bytes();
+        redirect_console(Box::new(|_| {}), Box::new(move || keys.next().unwrap_or(0)));
+    }
+
+    #[test]
+    fn disabled_traps_go_through_the_table() {
+        /* TRAP x28 */
+        let mut cpu = program(&[0xF028]);
+        cpu.memory.memory[0x28] = 0x4000;
+        cpu.step();
+        assert_eq!(cpu.pc, 0x4000);
+        assert_eq!(cpu.calls.depth(), 1);
+        assert!(cpu.running);
+
+        /* with nothing in the table the machine stops, as for any unknown vector */
+        let mut cpu = program(&[0xF028]);
+        cpu.step();
+        assert!(!cpu.running);
+    }
+
+    #[test]
+    fn in_dec_saturates() {
+        for (typed, expected) in [("-12\n", 0xFFF4), ("65535\n", 0xFFFF), ("70000\n", 0xFFFF), ("-65536\n", 0x8000), ("-32768\n", 0x8000)] {
+            typing(typed);
+            /* TRAP x26 */
+            let mut cpu = program(&[0xF026]);
+            cpu.traps.caps = Capabilities::all();
+            cpu.step();
+            assert_eq!(cpu.rr0, expected, "{:?}", typed);
+        }
+    }
+
+    #[test]
+    fn extended_results_count_as_written() {
+        /* RANDOM, AND R0, R0, #0, CLOCK, ADD R2, R0, R1, TICKS, ADD R2, R0, R1 */
+        let mut cpu = program(&[0xF028, 0x5020, 0xF029, 0x1401, 0xF02A, 0x1401]);
+        cpu.traps.caps = Capabilities::all();
+        cpu.check_uninitialised(UninitMode::Warn);
+        for _ in 0..6 {
+            cpu.step();
+        }
+        assert_eq!(cpu.uninit.as_ref().unwrap().reads, vec![]);
+    }
+
+    #[test]
+    fn files_round_trip() {
+        let mut cpu = CPU::new();
+        cpu.traps.caps = Capabilities::all();
+        cpu.traps.fs = Some(Box::new(MemoryFs::new()));
+        for (i, c) in "f\0hi".bytes().enumerate() {
+            cpu.memory.memory[0x4000 + i] = c as u16;
+        }
+
+        cpu.rr0 = 0x4000;
+        cpu.rr1 = 1;
+        cpu.trap_extended(TrapCodes::TrapOpen);
+        let handle = cpu.rr0;
+        assert_ne!(handle, FAILED);
+        (cpu.rr1, cpu.rr2) = (0x4002, 2);
+        cpu.trap_extended(TrapCodes::TrapWrite);
+        assert_eq!(cpu.rr0, 2);
+        cpu.rr0 = handle;
+        cpu.trap_extended(TrapCodes::TrapClose);
+
+        (cpu.rr0, cpu.rr1) = (0x4000, 0);
+        cpu.trap_extended(TrapCodes::TrapOpen);
+        (cpu.rr1, cpu.rr2) = (0x5000, 8);
+        cpu.trap_extended(TrapCodes::TrapRead);
+        assert_eq!(cpu.rr0, 2);
+        assert_eq!(cpu.memory.memory[0x5000..0x5002], [b'h' as u16, b'i' as u16]);
+    }
+
+    #[test]
+    fn handlers_replace_builtins_and_run_in_a_trap_frame() {
+        /* TRAP x25 twice */
+        let mut cpu = program(&[0xF025, 0xF025]);
+        cpu.register_trap(0x25, Box::new(|cpu| {
+            assert_eq!(cpu.calls.depth(), 1);
+            cpu.memory.write(0x4000, 0x1234);
+            cpu.set_register(0, 7);
+        }));
+
+        cpu.step();
+        assert!(cpu.running);
+        assert_eq!(cpu.memory.memory[0x4000], 0x1234);
+        assert_eq!(cpu.rr0, 7);
+        assert_eq!(cpu.calls.depth(), 0);
+
+        cpu.unregister_trap(0x25);
+        cpu.step();
+        assert!(!cpu.running);
+    }
+
+    #[test]
+    fn lc3b_files_use_one_char_per_byte() {
+        let mut cpu = CPU::new();
+        cpu.isa = Isa::Lc3b;
+        cpu.traps.caps = Capabilities::all();
+        cpu.traps.fs = Some(Box::new(MemoryFs::new()));
+        /* "f" then "hi", two chars a word, low byte first */
+        cpu.memory.memory[0x4000] = b'f' as u16;
+        cpu.memory.memory[0x4002] = u16::from_le_bytes(*b"hi");
+
+        (cpu.rr0, cpu.rr1) = (0x4000, 1);
+        cpu.trap_extended(TrapCodes::TrapOpen);
+        let handle = cpu.rr0;
+        (cpu.rr1, cpu.rr2) = (0x4002, 2);
+        cpu.trap_extended(TrapCodes::TrapWrite);
+        assert_eq!(cpu.rr0, 2);
+        cpu.rr0 = handle;
+        cpu.trap_extended(TrapCodes::TrapClose);
+
+        cpu.memory.memory[0x5000] = 0xAAAA;
+        (cpu.rr0, cpu.rr1) = (0x4000, 0);
+        cpu.trap_extended(TrapCodes::TrapOpen);
+        (cpu.rr1, cpu.rr2) = (0x5001, 8);
+        cpu.trap_extended(TrapCodes::TrapRead);
+        assert_eq!(cpu.rr0, 2);
+        assert_eq!(cpu.memory.memory[0x5000..0x5003], [u16::from_le_bytes([0xAA, b'h']), 0, b'i' as u16]);
+    }
+
+    #[test]
+    fn a_handler_can_unregister_itself() {
+        /* TRAP x25 twice */
+        let mut cpu = program(&[0xF025, 0xF025]);
+        cpu.register_trap(0x25, Box::new(|cpu| cpu.unregister_trap(0x25)));
+
+        cpu.step();
+        assert!(cpu.running);
+        assert!(!cpu.traps.handles(0x25));
+        cpu.step();
+        assert!(!cpu.running);
+    }
+}
@@ -1,5 +1,27 @@
 use crate::*;
 use crate::{cpu, memory::Mem, io::*};
+use crate::trace::{JsonTrace, TextTrace, TraceBuffer, Tracer};
+use crate::profiler::Profiler;
+use crate::symbols::SymbolTable;
+use crate::coverage::Coverage;
+use crate::debuginfo::DebugInfo;
+use crate::callstack::CallStack;
+use crate::cache::{CodeWatch, DecodeCache};
+use crate::block::BlockEngine;
+use crate::events::{Event, Observers};
+use crate::microarch::{describe, Microarch, MEMORY_LATENCY};
+use crate::disassembler::{disassemble, disassembly_for};
+use crate::instructions::Isa;
+use crate::interrupts::SUPERVISOR_STACK;
+use crate::timer::Timer;
+use crate::shadow::UninitMode;
+use crate::image::{Image, ImageFormat};
+use crate::memdiff::{self, Snapshot};
+use crate::framebuffer::{Framebuffer, Screen};
+use crate::traps::{Capabilities, MemoryFs, OpenMode, TrapExtensions, VirtualFs};
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+use js_sys::{Array, Function, Object, Reflect, Uint16Array, Uint8ClampedArray};
 
 static mut cpu: cpu::CPU = cpu::CPU {
     rr0 : 0,
@@ -13,22 +35,626 @@ static mut cpu: cpu::CPU = cpu::CPU {
     pc : 0x3000,
     rcond : 0,
     rcount : 0,
+    user: false,
+    priority: 0,
+    saved_ssp: SUPERVISOR_STACK,
+    saved_usp: 0,
+    relaxed: false,
+    isa: Isa::Lc3,
     memory : Mem {
         memory: [0; 1 << 16],
+        accesses: Vec::new(),
+        logging: false,
+        cache: DecodeCache::new(),
+        watch: CodeWatch::new(),
+        devices: Vec::new(),
+        device_floor: 1 << 16,
+        shadow: None,
+        code: None
     },
-    running: true
+    running: true,
+    tracer: None,
+    profiler: None,
+    symbols: SymbolTable::new(),
+    debug: DebugInfo::new(),
+    coverage: None,
+    image: None,
+    calls: CallStack::new(),
+    blocks: None,
+    observers: Observers::new(),
+    micro: None,
+    traps: TrapExtensions::new(),
+    ticks: 0,
+    uninit: None,
+    stack: None,
+    convention: None
 };
 
+thread_local! {
+    static TRACE_BUFFER: RefCell<Option<TraceBuffer>> = const { RefCell::new(None) };
+    /* the files behind the extended file traps, shared with fsput/fsget */
+    static FILES: RefCell<Option<Rc<RefCell<MemoryFs>>>> = const { RefCell::new(None) };
+    static SCREEN: RefCell<Option<Rc<RefCell<Screen>>>> = const { RefCell::new(None) };
+    static SNAPSHOT: RefCell<Option<Snapshot>> = const { RefCell::new(None) };
+}
+
+/* the page drives one VM, and only ever from one export at a time */
+fn with_cpu<R>(f: impl FnOnce(&mut cpu::CPU) -> R) -> R {
+    let vm = &raw mut cpu;
+    unsafe { f(&mut *vm) }
+}
+
+/* picks the LC-3b instead of the LC-3, load the image after switching */
+#[wasm_bindgen]
+pub fn setisa(lc3b: bool) {
+    with_cpu(|vm| vm.isa = if lc3b { Isa::Lc3b } else { Isa::Lc3 });
+}
+
+/* runs up to `count` instructions through the block engine, returns how many ran */
+#[wasm_bindgen]
+pub fn runfor(count: u32) -> u32 {
+    with_cpu(|vm| {
+        if vm.blocks.is_none() {
+            vm.blocks = Some(BlockEngine::new());
+        }
+        vm.run_for(count as usize) as u32
+    })
+}
+
+#[wasm_bindgen]
+pub fn loadimage(path: Vec<u8>) {
+    with_cpu(|vm| vm.load_image(&path));
+}
+
+#[wasm_bindgen]
+pub fn loadsymbols(sym: Vec<u8>) {
+    with_cpu(|vm| vm.load_symbols(&String::from_utf8_lossy(&sym)));
+}
+
+#[wasm_bindgen]
+pub fn loadlisting(source: String, lst: Vec<u8>) {
+    with_cpu(|vm| vm.load_listing(&source, &String::from_utf8_lossy(&lst)));
+}
+
+/* pushes PC and registers to the page, called by the frontend when it redraws */
+#[wasm_bindgen]
+pub fn refresh() {
+    with_cpu(|vm| {
+        pushpc(vm.pc);
+        pushreg(vm);
+    });
+}
+
+#[wasm_bindgen]
+pub fn running() -> bool {
+    with_cpu(|vm| vm.running)
+}
+
+thread_local! {
+    static DISASSEMBLY_SUBSCRIPTION: Cell<Option<usize>> = const { Cell::new(None) };
+}
+
+/* opt-in per-instruction disassembly log */
+#[wasm_bindgen]
+pub fn setdisassembly(on: bool) {
+    with_cpu(|vm| {
+        if let Some(id) = DISASSEMBLY_SUBSCRIPTION.take() {
+            vm.unsubscribe(id);
+        }
+        if on {
+            let id = vm.subscribe(true, Box::new(|event| {
+                if let Event::Step { inst, .. } = event {
+                    disassemble(*inst);
+                }
+            }));
+            DISASSEMBLY_SUBSCRIPTION.set(Some(id));
+        }
+    });
+}
+
+#[wasm_bindgen]
+pub fn step() {
+    with_cpu(|vm| vm.step());
+}
+
+#[wasm_bindgen]
+pub fn tracestart(json: bool, start: u16, end: u16) {
+    let buffer = TraceBuffer::default();
+    let tracer = if json {
+        Tracer::new(Box::new(JsonTrace::new(buffer.clone())))
+    } else {
+        Tracer::new(Box::new(TextTrace::new(buffer.clone())))
+    };
+
+    with_cpu(|vm| vm.tracer = Some(tracer.filter(start, end)));
+    TRACE_BUFFER.with(|b| *b.borrow_mut() = Some(buffer));
+}
+
+#[wasm_bindgen]
+pub fn tracestop() {
+    with_cpu(|vm| vm.tracer = None);
+}
+
+#[wasm_bindgen]
+pub fn tracetake() -> String {
+    TRACE_BUFFER.with(|b| b.borrow().as_ref().map(|buffer| buffer.take()).unwrap_or_default())
+}
+
+#[wasm_bindgen]
+pub fn profilestart() {
+    with_cpu(|vm| vm.profiler = Some(Profiler::new()));
+}
+
+#[wasm_bindgen]
+pub fn profilestop() {
+    with_cpu(|vm| vm.profiler = None);
+}
+
+#[wasm_bindgen]
+pub fn profilereport() -> String {
+    with_cpu(|vm| match &vm.profiler {
+        Some(profiler) => profiler.report(&vm.symbols),
+        None => String::new(),
+    })
+}
+
+#[wasm_bindgen]
+pub fn coveragestart() {
+    with_cpu(|vm| vm.coverage = Some(Coverage::new()));
+}
+
+#[wasm_bindgen]
+pub fn coveragereport() -> String {
+    with_cpu(|vm| match (&vm.coverage, vm.image) {
+        (Some(coverage), Some((start, end))) => coverage.listing(&vm.memory, start, end, &vm.symbols, &vm.debug),
+        _ => String::new(),
+    })
+}
+
+#[wasm_bindgen]
+pub fn coveragelcov() -> String {
+    with_cpu(|vm| match &vm.coverage {
+        Some(coverage) => coverage.lcov(&vm.debug),
+        None => String::new(),
+    })
+}
+
+#[wasm_bindgen]
+pub fn backtrace() -> String {
+    with_cpu(|vm| vm.backtrace())
+}
+
+#[wasm_bindgen]
+pub fn lint() -> String {
+    with_cpu(|vm| crate::lint::report(&vm.lint()))
+}
+
+/* R0-R7, PC and PSR in one copy */
+#[wasm_bindgen]
+pub fn registers() -> Uint16Array {
+    with_cpu(|vm| {
+        let mut regs = vm.registers().to_vec();
+        regs.push(vm.pc as u16);
+        regs.push(vm.psr());
+        Uint16Array::from(&regs[..])
+    })
+}
+
+/*
+a view straight onto VM memory, no copy; it is invalidated if wasm memory
+grows, so take a fresh one each frame rather than keeping it around
+*/
+#[wasm_bindgen]
+pub fn memoryview() -> Uint16Array {
+    with_cpu(|vm| unsafe { Uint16Array::view(&vm.memory.memory) })
+}
+
+/* { n, z, p } booleans */
+#[wasm_bindgen]
+pub fn conditioncodes() -> Object {
+    let cond = with_cpu(|vm| vm.rcond);
+    let cc = Object::new();
+    let _ = Reflect::set(&cc, &"n".into(), &(cond & 4 != 0).into());
+    let _ = Reflect::set(&cc, &"z".into(), &(cond & 2 != 0).into());
+    let _ = Reflect::set(&cc, &"p".into(), &(cond & 1 != 0).into());
+    cc
+}
+
+/* [{ addr, word, label, text }] for `before` words before `addr` to `after` words after it */
+#[wasm_bindgen]
+pub fn disassemblywindow(addr: u16, before: u16, after: u16) -> Array {
+    with_cpu(|vm| {
+        let lines = Array::new();
+        let isa = vm.isa;
+        /* LC-3b words sit on even byte addresses */
+        let stride = if isa == Isa::Lc3b { 2 } else { 1 };
+        let start = (addr & !(stride - 1)).saturating_sub(before * stride);
+        let end = (addr & !(stride - 1)).saturating_add(after * stride);
+
+        for a in (start..=end).step_by(stride as usize) {
+            let word = vm.memory.memory[a as usize];
+            let label = vm.symbols.name(a).unwrap_or("").to_string();
+
+            let line = Object::new();
+            let _ = Reflect::set(&line, &"addr".into(), &a.into());
+            let _ = Reflect::set(&line, &"word".into(), &word.into());
+            let _ = Reflect::set(&line, &"label".into(), &label.into());
+            let _ = Reflect::set(&line, &"text".into(), &disassembly_for(isa, word).into());
+            lines.push(&line);
+        }
+
+        lines
+    })
+}
+
+/* switches to clocking instructions through the control state machine */
+#[wasm_bindgen]
+pub fn microstart(latency: u32) {
+    with_cpu(|vm| vm.micro = Some(Microarch::new(latency)));
+}
+
+#[wasm_bindgen]
+pub fn microstop() {
+    with_cpu(|vm| vm.micro = None);
+}
+
+/* one clock, { state, next, text, ready, signals, bus, mar, mdr, ir, ben, cycles } */
+#[wasm_bindgen]
+pub fn microclock() -> Object {
+    with_cpu(|vm| {
+        let info = Object::new();
+        let Some(clock) = vm.clock() else { return info };
+        let Some(micro) = vm.micro.as_ref() else { return info };
+
+        let signals = Array::new();
+        for name in clock.signals.asserted() {
+            signals.push(&name.into());
+        }
+
+        let _ = Reflect::set(&info, &"state".into(), &clock.state.into());
+        let _ = Reflect::set(&info, &"next".into(), &clock.next.into());
+        let _ = Reflect::set(&info, &"text".into(), &describe(clock.state).into());
+        let _ = Reflect::set(&info, &"ready".into(), &clock.ready.into());
+        let _ = Reflect::set(&info, &"signals".into(), &signals);
+        let _ = Reflect::set(&info, &"bus".into(), &clock.signals.bus.map_or(JsValue::NULL, |b| b.into()));
+        let _ = Reflect::set(&info, &"mar".into(), &micro.mar.into());
+        let _ = Reflect::set(&info, &"mdr".into(), &micro.mdr.into());
+        let _ = Reflect::set(&info, &"ir".into(), &micro.ir.into());
+        let _ = Reflect::set(&info, &"ben".into(), &micro.ben.into());
+        let _ = Reflect::set(&info, &"cycles".into(), &(micro.cycles as f64).into());
+        info
+    })
+}
+
+#[wasm_bindgen]
+pub fn microcycles() -> f64 {
+    with_cpu(|vm| vm.micro.as_ref().map_or(0.0, |m| m.cycles as f64))
+}
+
+/* turns extended trap groups on or off, all off is the textbook trap set */
+#[wasm_bindgen]
+pub fn settraps(decimal: bool, random: bool, clock: bool, files: bool) {
+    with_cpu(|vm| {
+        vm.traps.caps = Capabilities { decimal, random, clock, files };
+        if files && vm.traps.fs.is_none() {
+            let fs = Rc::new(RefCell::new(MemoryFs::new()));
+            vm.traps.fs = Some(Box::new(SharedFs(fs.clone())));
+            FILES.set(Some(fs));
+        }
+    });
+}
+
+#[wasm_bindgen]
+pub fn seedrandom(seed: u32) {
+    with_cpu(|vm| vm.traps.seed = seed.max(1));
+}
+
+/* puts a file where the program's OPEN trap can find it */
+#[wasm_bindgen]
+pub fn fsput(name: String, data: Vec<u8>) {
+    FILES.with_borrow(|files| {
+        if let Some(fs) = files.as_ref() {
+            fs.borrow_mut().files.insert(name, data);
+        }
+    });
+}
+
+#[wasm_bindgen]
+pub fn fsget(name: String) -> Vec<u8> {
+    FILES.with_borrow(|files| {
+        files.as_ref()
+            .and_then(|fs| fs.borrow().files.get(&name).cloned())
+            .unwrap_or_default()
+    })
+}
+
+/* lets the page keep a handle on the files the CPU owns */
+struct SharedFs(Rc<RefCell<MemoryFs>>);
+
+impl VirtualFs for SharedFs {
+    fn open(&mut self, name: &str, mode: OpenMode) -> Option<u16> {
+        self.0.borrow_mut().open(name, mode)
+    }
+
+    fn read(&mut self, handle: u16) -> Option<u8> {
+        self.0.borrow_mut().read(handle)
+    }
+
+    fn write(&mut self, handle: u16, byte: u8) -> bool {
+        self.0.borrow_mut().write(handle, byte)
+    }
+
+    fn close(&mut self, handle: u16) {
+        self.0.borrow_mut().close(handle)
+    }
+}
+
+thread_local! {
+    /* the CPU a running TRAP handler was called with, null outside one */
+    static HANDLER_CPU: Cell<*mut cpu::CPU> = const { Cell::new(std::ptr::null_mut()) };
+}
+
+/*
+the memory a handler works on: inside a handler it is the CPU the handler
+was called with, since the page's VM is already borrowed by the step that
+got there
+*/
+fn with_memory<R>(f: impl FnOnce(&mut Mem) -> R) -> R {
+    let vm = HANDLER_CPU.get();
+    if vm.is_null() {
+        with_cpu(|vm| f(&mut vm.memory))
+    } else {
+        unsafe { f(&mut (*vm).memory) }
+    }
+}
+
+/* the `memory` argument of a TRAP handler */
+#[wasm_bindgen]
+pub struct TrapMemory;
+
 #[wasm_bindgen]
-pub extern fn loadimage(path: Vec<u8>) {   
-    unsafe {
-        cpu.load_image(&path)
+impl TrapMemory {
+    pub fn read(&self, addr: u16) -> u16 {
+        readmemory(addr)
+    }
+
+    pub fn write(&self, addr: u16, val: u16) {
+        writememory(addr, val)
+    }
+}
+
+/*
+calls `handler(regs, vector, memory)` for TRAP `vector`; `regs` holds R0-R7
+and is copied back afterwards, `memory` has read(addr) and write(addr, val)
+*/
+#[wasm_bindgen]
+pub fn registertrap(vector: u8, handler: Function) {
+    let run = move |vm: &mut cpu::CPU| {
+        let regs = Uint16Array::from(&vm.registers()[..]);
+        let memory = JsValue::from(TrapMemory);
+
+        let outer = HANDLER_CPU.replace(vm);
+        let result = handler.call3(&JsValue::NULL, &regs, &vector.into(), &memory);
+        HANDLER_CPU.set(outer);
+
+        if let Err(e) = result {
+            eprintln!("TRAP x{:02X} handler threw {:?}", vector, e);
+            vm.running = false;
+            return;
+        }
+        for r in 0..8 {
+            vm.set_register(r, regs.get_index(r as u32));
+        }
     };
+    with_cpu(|vm| vm.register_trap(vector, Box::new(run)));
 }
 
 #[wasm_bindgen]
-pub extern fn step() {
-    unsafe {
-        cpu.step()
+pub fn unregistertrap(vector: u8) {
+    with_cpu(|vm| vm.unregister_trap(vector));
+}
+
+/* memory through the CPU, so devices and the decode caches see it; safe to call from a TRAP handler */
+#[wasm_bindgen]
+pub fn readmemory(addr: u16) -> u16 {
+    with_memory(|memory| memory.read(addr as usize))
+}
+
+#[wasm_bindgen]
+pub fn writememory(addr: u16, val: u16) {
+    with_memory(|memory| memory.write(addr as usize, val));
+}
+
+/* puts the interval timer on the bus at xFE08-xFE0E, interrupting through `vector` */
+#[wasm_bindgen]
+pub fn attachtimer(vector: u8, priority: u8) {
+    with_cpu(|vm| vm.memory.attach(Box::new(Timer::new(vector, priority))));
+}
+
+/* maps the 128x124 display over xC000-xFDFF */
+#[wasm_bindgen]
+pub fn attachdisplay() {
+    SCREEN.with_borrow_mut(|screen| {
+        if screen.is_none() {
+            let display = Framebuffer::new();
+            *screen = Some(display.screen());
+            with_cpu(|vm| vm.memory.attach(Box::new(display)));
+        }
+    });
+}
+
+/* RGBA for the whole display, for `new ImageData(pixels, 128, 124)` */
+#[wasm_bindgen]
+pub fn displaypixels() -> Uint8ClampedArray {
+    SCREEN.with_borrow(|screen| match screen.as_ref() {
+        Some(screen) => Uint8ClampedArray::from(screen.borrow_mut().rgba()),
+        None => Uint8ClampedArray::new_with_length(0),
+    })
+}
+
+/* [x, y, width, height] changed since the last call, or null */
+#[wasm_bindgen]
+pub fn displaydirty() -> JsValue {
+    let Some(dirty) = SCREEN.with_borrow(|screen| screen.as_ref().map(|s| s.borrow_mut().take_dirty())) else { return JsValue::NULL };
+    match dirty {
+        Some(r) => [r.x0, r.y0, r.width(), r.height()].iter().map(|&n| JsValue::from(n as u32)).collect::<Array>().into(),
+        None => JsValue::NULL,
+    }
+}
+
+/* teaching mode: user programs may touch system space and devices without ACV */
+#[wasm_bindgen]
+pub fn setrelaxed(on: bool) {
+    with_cpu(|vm| vm.relaxed = on);
+}
+
+/* starts the loaded program in user mode, as an OS would hand it over */
+#[wasm_bindgen]
+pub fn setusermode(on: bool) {
+    with_cpu(|vm| vm.user = on);
+}
+
+/* reports reads of never written registers and memory, stopping at the first when `stop` */
+#[wasm_bindgen]
+pub fn uninitstart(stop: bool) {
+    with_cpu(|vm| vm.check_uninitialised(if stop { UninitMode::Stop } else { UninitMode::Warn }));
+}
+
+#[wasm_bindgen]
+pub fn uninitreport() -> String {
+    with_cpu(|vm| match &vm.uninit {
+        Some(check) => check.report(&vm.symbols),
+        None => String::new(),
+    })
+}
+
+/* garbage in registers and memory to shake out uninitialised reads, call before loadimage */
+#[wasm_bindgen]
+pub fn fillgarbage(seed: u32) {
+    with_cpu(|vm| vm.fill_garbage(seed));
+}
+
+/* reports executing data and stores to executed code, stopping at each new one when `stop` */
+#[wasm_bindgen]
+pub fn codecheckstart(stop: bool) {
+    with_cpu(|vm| vm.check_code(stop));
+}
+
+#[wasm_bindgen]
+pub fn codecheckreport() -> String {
+    with_cpu(|vm| match &vm.memory.code {
+        Some(code) => code.report(&vm.symbols),
+        None => String::new(),
+    })
+}
+
+/* R6 stack checking between base (empty) and limit, or from the STACK_BASE and STACK_LIMIT labels when both are 0 */
+#[wasm_bindgen]
+pub fn stackguard(base: u16, limit: u16, stop: bool) -> bool {
+    with_cpu(|vm| {
+        if base == 0 && limit == 0 {
+            return vm.guard_stack_from_symbols(stop);
+        }
+        vm.guard_stack(base, limit, stop);
+        true
+    })
+}
+
+#[wasm_bindgen]
+pub fn stackreport() -> String {
+    with_cpu(|vm| match &vm.stack {
+        Some(guard) => guard.report(&vm.symbols),
+        None => String::new(),
+    })
+}
+
+/* checks R5, R6 and the registers in `callee_saved` (bit n for Rn) at every return */
+#[wasm_bindgen]
+pub fn conventionstart(callee_saved: u8, return_slot: bool, stop: bool) {
+    with_cpu(|vm| vm.check_convention(callee_saved, return_slot, stop));
+}
+
+#[wasm_bindgen]
+pub fn conventionreport() -> String {
+    with_cpu(|vm| match &vm.convention {
+        Some(check) => check.report(&vm.symbols),
+        None => String::new(),
+    })
+}
+
+/* .obj, .hex, .bin, Intel HEX or little endian, whichever the data looks like */
+#[wasm_bindgen]
+pub fn loadanyimage(data: Vec<u8>) -> bool {
+    with_cpu(|vm| vm.load_any(&data))
+}
+
+/* start..=end of memory as 0 .obj, 1 little endian, 2 .hex, 3 .bin or 4 Intel HEX */
+#[wasm_bindgen]
+pub fn dumpmemory(start: u16, end: u16, format: u8) -> Vec<u8> {
+    let format = match format {
+        1 => ImageFormat::RawLe,
+        2 => ImageFormat::Hex,
+        3 => ImageFormat::Bin,
+        4 => ImageFormat::IntelHex,
+        _ => ImageFormat::Obj,
     };
+    let mut out = Vec::new();
+    with_cpu(|vm| vm.dump(start, end).write(format, vm.isa, &mut out).unwrap());
+    out
+}
+
+/* labels, words and disassembly for start..=end */
+#[wasm_bindgen]
+pub fn annotateddump(start: u16, end: u16) -> String {
+    with_cpu(|vm| vm.annotated_dump(start, end))
+}
+
+/* remembers memory as it is now, for snapshotdiff */
+#[wasm_bindgen]
+pub fn takesnapshot() {
+    SNAPSHOT.set(Some(with_cpu(|vm| vm.snapshot())));
+}
+
+#[wasm_bindgen]
+pub fn snapshotdiff(start: u16, end: u16) -> String {
+    SNAPSHOT.with_borrow(|snapshot| match snapshot {
+        Some(before) => with_cpu(|vm| vm.diff_since(before, start, end)),
+        None => String::new(),
+    })
+}
+
+/* memory now against a reference image in any loadable format, for grading */
+#[wasm_bindgen]
+pub fn imagediff(reference: Vec<u8>, start: u16, end: u16) -> String {
+    with_cpu(|vm| {
+        let Some(image) = Image::read(&reference, vm.isa) else { return String::new() };
+        let expected = Snapshot::from_image(&image, vm.isa);
+        memdiff::report(&memdiff::diff(&expected, &vm.snapshot(), start, end), &vm.symbols)
+    })
+}
+
+/*
+runs the loaded program for up to `limit` instructions while a copy of it
+runs on the control state machine, and describes the first disagreement
+*/
+#[wasm_bindgen]
+pub fn lockstepmicro(limit: u32) -> String {
+    with_cpu(|vm| {
+        let mut reference = vm.fork();
+        reference.micro = Some(Microarch::new(MEMORY_LATENCY));
+        match vm.lockstep(&mut reference, limit as u64) {
+            Some(divergence) => divergence.to_string(),
+            None => String::new(),
+        }
+    })
+}
+
+/* runs the loaded program against a JSON trace recorded from a reference */
+#[wasm_bindgen]
+pub fn replaytrace(trace: String) -> String {
+    with_cpu(|vm| match vm.replay(&trace) {
+        Ok(Some(divergence)) => divergence.to_string(),
+        Ok(None) => String::new(),
+        Err(e) => e,
+    })
 }